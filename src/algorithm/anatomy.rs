@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::atlas::Hemisphere;
+use crate::model::ontology::Ontology;
+use crate::model::ImageMetadata;
+use crate::utility::types::{CellRecord, Matrix, Settings};
+
+/// Analysis results for one atlas structure, including all of its descendants.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StructureResults {
+    pub id: u64,
+    pub acronym: String,
+    pub name: String,
+    pub parent: Option<u64>,
+    /// The side these results cover, `None` for both together.
+    #[serde(default)]
+    pub hemisphere: Option<Hemisphere>,
+    pub cell_count: usize,
+    /// Analysed area in full-resolution pixels.
+    pub area_px: f64,
+    /// Analysed area and cells per mm², when the image's pixel size is known.
+    pub area_mm2: Option<f64>,
+    pub density_per_mm2: Option<f64>,
+    /// Averages and the co-marker positive share of cells, `None` without cells.
+    pub average_rotundity: Option<f64>,
+    pub average_branch_length: Option<f64>,
+    pub average_scholl: Option<f64>,
+    pub percentage_cd68_num: Option<f64>,
+}
+
+/// Points sampled when measuring structure areas; the grid is coarsened to stay under it.
+const AREA_SAMPLES: usize = 1_000_000;
+
+/// Tags every cell with the atlas structure and hemisphere at its centroid
+/// and aggregates the cells and the analysed area per structure, each parent
+/// summing its children. Every structure gets a row for both hemispheres
+/// together and one per hemisphere it reaches.
+///
+/// `annotation` and `hemispheres` are the annotation and hemisphere slices
+/// the image is registered to. Returns `None` when the image has no usable
+/// registration. Cells outside the annotation or in structures missing from
+/// `ontology` count towards none.
+pub fn structure_results(
+    cells: &mut [CellRecord],
+    img: &ImageMetadata,
+    annotation: &Matrix<u32>,
+    hemispheres: &Matrix<u16>,
+    ontology: &Ontology,
+    settings: &Settings,
+) -> Option<Vec<StructureResults>> {
+    let inverse = img.registration.as_ref()?.inverse()?;
+    let (ah, aw) = annotation.dim();
+    let label_at = |row: f32, col: f32| {
+        let (x, y) = inverse.apply((col, row));
+        let (x, y) = (x.round(), y.round());
+        if x < 0.0 || y < 0.0 || x >= aw as f32 || y >= ah as f32 {
+            (0, None)
+        } else {
+            let at = (y as usize, x as usize);
+            let side = hemispheres.get(at).and_then(|&h| Hemisphere::from_label(h));
+            (annotation[at] as u64, side)
+        }
+    };
+
+    let mut totals: HashMap<(u64, Option<Hemisphere>), Totals> = HashMap::new();
+    let mut add_up = |id: u64, side: Option<Hemisphere>, f: &dyn Fn(&mut Totals)| {
+        if ontology.get(id).is_none() {
+            return;
+        }
+        std::iter::once(id)
+            .chain(ontology.ancestors(id))
+            .for_each(|k| {
+                f(totals.entry((k, None)).or_default());
+                if side.is_some() {
+                    f(totals.entry((k, side)).or_default());
+                }
+            });
+    };
+
+    cells.iter_mut().for_each(|cell| {
+        let (id, side) = label_at(cell.centroid_row as f32, cell.centroid_col as f32);
+        cell.structure_id = id;
+        cell.structure = ontology
+            .get(id)
+            .map(|s| s.acronym.clone())
+            .unwrap_or_default();
+        cell.hemisphere = side.map(|h| h.to_str().to_owned()).unwrap_or_default();
+
+        let overlaps = cell.co_marker_overlap > settings.overlap_percentage_threshold;
+        add_up(id, side, &|t: &mut Totals| t.add_cell(cell, overlaps));
+    });
+
+    let (w, h) = img.size;
+    let step = ((w * h) as f64 / AREA_SAMPLES as f64)
+        .sqrt()
+        .ceil()
+        .max(1.0) as usize;
+    let mut areas: HashMap<(u64, Option<Hemisphere>), usize> = HashMap::new();
+    for row in (step / 2..h).step_by(step) {
+        for col in (step / 2..w).step_by(step) {
+            let pt = (row as f64, col as f64);
+            if !img.regions.is_empty() && !img.regions.iter().any(|r| r.contains(pt)) {
+                continue;
+            }
+            *areas.entry(label_at(row as f32, col as f32)).or_default() += step * step;
+        }
+    }
+    areas.into_iter().for_each(|((id, side), px)| {
+        add_up(id, side, &|t: &mut Totals| t.area += px as f64);
+    });
+
+    let mm2_per_px = img.pixel_size.map(|um| (um * 1e-3).powi(2));
+
+    let mut out = totals
+        .into_iter()
+        .filter_map(|((id, hemisphere), t)| {
+            let s = ontology.get(id)?;
+            let area_mm2 = mm2_per_px.map(|a| a * t.area);
+            Some(StructureResults {
+                id,
+                acronym: s.acronym.clone(),
+                name: s.name.clone(),
+                parent: s.parent,
+                hemisphere,
+                cell_count: t.cells,
+                area_px: t.area,
+                area_mm2,
+                density_per_mm2: area_mm2.filter(|&a| a > 0.0).map(|a| t.cells as f64 / a),
+                average_rotundity: t.rotundity.mean(),
+                average_branch_length: t.branch_length.mean(),
+                average_scholl: t.scholl.mean(),
+                percentage_cd68_num: (t.cells > 0)
+                    .then(|| 100.0 * t.overlapping as f64 / t.cells as f64),
+            })
+        })
+        .collect::<Vec<StructureResults>>();
+
+    out.sort_by_cached_key(|r| (ontology.depth(r.id), r.acronym.clone(), r.hemisphere));
+    Some(out)
+}
+
+#[derive(Default)]
+struct Totals {
+    cells: usize,
+    overlapping: usize,
+    area: f64,
+    rotundity: Mean,
+    branch_length: Mean,
+    scholl: Mean,
+}
+
+impl Totals {
+    fn add_cell(&mut self, cell: &CellRecord, overlaps: bool) {
+        self.cells += 1;
+        self.overlapping += overlaps as usize;
+        self.rotundity.add(cell.rotundity);
+        self.branch_length.add(cell.mean_branch_length);
+        self.scholl.add(cell.scholl_slope);
+    }
+}
+
+/// Running mean over the finite values added, as in `CellTable::results`.
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    n: usize,
+}
+
+impl Mean {
+    fn add(&mut self, a: f64) {
+        if a.is_finite() {
+            self.sum += a;
+            self.n += 1;
+        }
+    }
+
+    fn mean(&self) -> Option<f64> {
+        (self.n > 0).then(|| self.sum / self.n as f64)
+    }
+}
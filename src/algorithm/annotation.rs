@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use ndarray::Zip;
+
+use crate::model::atlas::Hemisphere;
+use crate::model::ImageMetadata;
+use crate::utility::io::{save_as_binary, save_as_luma32};
+use crate::utility::types::Matrix;
+
+/// The atlas annotation slice carried into section space by `img`'s
+/// registration, one label per section pixel at `downsample`.
+pub fn warp_annotation<T: Copy + Default>(
+    img: &ImageMetadata,
+    annotation: &Matrix<T>,
+    downsample: usize,
+) -> Option<Matrix<T>> {
+    let reg = img.registration.as_ref()?;
+    let downsample = downsample.max(1);
+    let (w, h) = img.size;
+    let canvas = (w.div_ceil(downsample), h.div_ceil(downsample));
+    reg.resample_labels(annotation, img.size, canvas)
+}
+
+/// Pixels labelled with any of `ids`.
+pub fn structure_mask(labels: &Matrix<u32>, ids: &HashSet<u64>) -> Matrix<bool> {
+    labels.map(|&l| ids.contains(&(l as u64)))
+}
+
+/// Warps `annotation` onto `img` and writes both the label image and the mask
+/// of `ids` under `acronym` to the image's atlas mask folder. With the slice's
+/// `hemispheres`, also writes the mask split at the midline under
+/// `<acronym>_Left` and `<acronym>_Right`. Returns the path of the whole mask.
+pub fn write_structure_mask(
+    img: &ImageMetadata,
+    annotation: &Matrix<u32>,
+    hemispheres: Option<&Matrix<u16>>,
+    acronym: &str,
+    ids: &HashSet<u64>,
+    downsample: usize,
+) -> Result<PathBuf, Error> {
+    let unregistered = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} has no usable registration", img.id()),
+        )
+    };
+    let labels = warp_annotation(img, annotation, downsample).ok_or_else(unregistered)?;
+
+    std::fs::create_dir_all(img.atlas_mask_dir())?;
+    save_as_luma32(&labels, &img.annotation_fn())
+        .map_err(|err| Error::other(format!("{}: {}", img.annotation_fn(), err)))?;
+
+    let save = |mask: &Matrix<bool>, path: &str| {
+        save_as_binary(mask, path).map_err(|err| Error::other(format!("{}: {}", path, err)))
+    };
+    let mask = structure_mask(&labels, ids);
+    let path = img.structure_mask_fn(acronym);
+    save(&mask, &path)?;
+
+    if let Some(hemispheres) = hemispheres {
+        let sides = warp_annotation(img, hemispheres, downsample).ok_or_else(unregistered)?;
+        for side in Hemisphere::ALL {
+            let half = Zip::from(&mask)
+                .and(&sides)
+                .map_collect(|&m, &h| m && h == side.label());
+            let name = format!("{}_{}", acronym, side.to_str());
+            save(&half, &img.structure_mask_fn(&name))?;
+        }
+    }
+    Ok(path.into())
+}
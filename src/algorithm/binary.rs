@@ -151,7 +151,10 @@ const PS: [Pnt; 8] = [
     (7, 0),
 ];
 
-pub fn branch_length(img: &Matrix<u32>, markers: &Vec<Pnt>) -> (Matrix<usize>, Vec<Vec<usize>>) {
+pub fn branch_length(
+    img: &Matrix<u32>,
+    markers: &Vec<Option<Pnt>>,
+) -> (Matrix<usize>, Vec<Vec<usize>>) {
     let shape = img.dim();
     let n_cells = markers.len();
 
@@ -160,12 +163,20 @@ pub fn branch_length(img: &Matrix<u32>, markers: &Vec<Pnt>) -> (Matrix<usize>, V
     let mut frontiers = Vec::with_capacity(n_cells);
     let mut histograms = Vec::with_capacity(n_cells);
 
-    for i in 0..n_cells {
-        let marker_pt = markers[i];
-        visited[marker_pt] = true;
-        out[marker_pt] = 1;
-        frontiers.push(VecDeque::from([marker_pt]));
-        histograms.push(vec![1]);
+    // cells without a skeleton get an empty frontier and histogram
+    for marker in markers {
+        match *marker {
+            Some(marker_pt) => {
+                visited[marker_pt] = true;
+                out[marker_pt] = 1;
+                frontiers.push(VecDeque::from([marker_pt]));
+                histograms.push(vec![0, 1]);
+            }
+            None => {
+                frontiers.push(VecDeque::new());
+                histograms.push(vec![]);
+            }
+        }
     }
 
     while !frontiers.iter().all(|a| a.is_empty()) {
@@ -179,13 +190,12 @@ pub fn branch_length(img: &Matrix<u32>, markers: &Vec<Pnt>) -> (Matrix<usize>, V
                     }
 
                     let dist = out[pt] + 1;
-                    let curr_max_man = histograms[label_idx].len();
+                    let hist = &mut histograms[label_idx];
 
-                    if dist >= curr_max_man {
-                        histograms[label_idx].push(1);
-                    } else {
-                        histograms[label_idx][dist] += 1;
+                    if dist >= hist.len() {
+                        hist.resize(dist + 1, 0);
                     }
+                    hist[dist] += 1;
 
                     frontier.push_back(new_pt);
                     visited[new_pt] = true;
@@ -0,0 +1,401 @@
+use std::time::{Duration, Instant};
+
+use image::imageops::{resize, FilterType};
+use image::{ImageBuffer, Luma};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::algorithm::landmarks::Point;
+use crate::algorithm::metrics::Metric;
+use crate::algorithm::proc::imadjust_buff;
+use crate::model::registration::{mat3_inv, project};
+
+type Buffer = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Cubic B-spline free-form deformation: a displacement of section pixels
+/// `(x, y)`, interpolated from a regular grid of control points.
+///
+/// Control point `(i, j)` sits at `((i - 1) * spacing.0, (j - 1) * spacing.1)`,
+/// so the grid reaches one point before the image and two past it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BSplineField {
+    /// Control points across and down.
+    pub grid: (usize, usize),
+    /// Pixels between neighbouring control points in x and y.
+    pub spacing: (f32, f32),
+    /// Displacement at each control point in pixels, row-major.
+    pub coefficients: Vec<Point>,
+}
+
+impl BSplineField {
+    /// A field of zero displacement covering a `(width, height)` image.
+    pub fn zeros((w, h): (usize, usize), spacing: (f32, f32)) -> Self {
+        let grid = (
+            (w as f32 / spacing.0).ceil() as usize + 3,
+            (h as f32 / spacing.1).ceil() as usize + 3,
+        );
+        Self {
+            grid,
+            spacing,
+            coefficients: vec![(0.0, 0.0); grid.0 * grid.1],
+        }
+    }
+
+    pub fn displacement(&self, pt: Point) -> Point {
+        self.displacement_over(&self.support(pt))
+    }
+
+    fn displacement_over(&self, support: &[(usize, f32); 16]) -> Point {
+        support.iter().fold((0.0, 0.0), |(dx, dy), &(k, w)| {
+            let c = self.coefficients[k];
+            (dx + w * c.0, dy + w * c.1)
+        })
+    }
+
+    pub fn displace(&self, (x, y): Point) -> Point {
+        let (dx, dy) = self.displacement((x, y));
+        (x + dx, y + dy)
+    }
+
+    /// The same deformation for the image resized by `(sx, sy)`.
+    pub fn scaled(&self, sx: f32, sy: f32) -> Self {
+        Self {
+            grid: self.grid,
+            spacing: (self.spacing.0 * sx, self.spacing.1 * sy),
+            coefficients: self
+                .coefficients
+                .iter()
+                .map(|&(dx, dy)| (dx * sx, dy * sy))
+                .collect(),
+        }
+    }
+
+    /// The 16 control points influencing `pt` with their weights. Points off the
+    /// grid take the weights of its nearest edge.
+    fn support(&self, (x, y): Point) -> [(usize, f32); 16] {
+        let axis = |v: f32, spacing: f32, n: usize| {
+            let t = (v / spacing).clamp(0.0, (n - 3) as f32 - 1e-3);
+            let i = t.floor();
+            (i as usize, cubic_weights(t - i))
+        };
+        let (i, wx) = axis(x, self.spacing.0, self.grid.0);
+        let (j, wy) = axis(y, self.spacing.1, self.grid.1);
+
+        std::array::from_fn(|k| {
+            let (m, l) = (k / 4, k % 4);
+            ((j + m) * self.grid.0 + i + l, wy[m] * wx[l])
+        })
+    }
+
+    /// Mean squared second difference of the displacements, in units of the
+    /// grid spacing: a discrete bending energy, zero for any affine field.
+    pub fn bending_energy(&self) -> f64 {
+        let mut total = 0.0;
+        self.for_each_stencil(|weight, terms| {
+            let (a, b) = self.combine(terms);
+            total += weight * (a * a + b * b);
+        });
+        total / (self.grid.0 * self.grid.1).max(1) as f64
+    }
+
+    /// Derivative of `bending_energy` with respect to each coefficient.
+    fn bending_gradient(&self) -> Vec<(f64, f64)> {
+        let n = (self.grid.0 * self.grid.1).max(1) as f64;
+        let (sx, sy) = (self.spacing.0 as f64, self.spacing.1 as f64);
+        let mut gradient = vec![(0.0, 0.0); self.coefficients.len()];
+        self.for_each_stencil(|weight, terms| {
+            let (a, b) = self.combine(terms);
+            terms.iter().for_each(|&(w, k)| {
+                let g = &mut gradient[k];
+                g.0 += 2.0 * weight * w * a / (sx * n);
+                g.1 += 2.0 * weight * w * b / (sy * n);
+            });
+        });
+        gradient
+    }
+
+    /// Visits the second differences of the grid as a weight and the
+    /// `(factor, control point)` terms it combines.
+    fn for_each_stencil(&self, mut visit: impl FnMut(f64, &[(f64, usize)])) {
+        let (nx, ny) = self.grid;
+        let k = |i: usize, j: usize| j * nx + i;
+        for j in 0..ny {
+            for i in 0..nx {
+                if i > 0 && i + 1 < nx {
+                    visit(
+                        1.0,
+                        &[(1.0, k(i - 1, j)), (-2.0, k(i, j)), (1.0, k(i + 1, j))],
+                    );
+                }
+                if j > 0 && j + 1 < ny {
+                    visit(
+                        1.0,
+                        &[(1.0, k(i, j - 1)), (-2.0, k(i, j)), (1.0, k(i, j + 1))],
+                    );
+                }
+                if i + 1 < nx && j + 1 < ny {
+                    visit(
+                        2.0,
+                        &[
+                            (1.0, k(i + 1, j + 1)),
+                            (-1.0, k(i + 1, j)),
+                            (-1.0, k(i, j + 1)),
+                            (1.0, k(i, j)),
+                        ],
+                    );
+                }
+            }
+        }
+    }
+
+    /// Sum of `terms` over the coefficients in units of the grid spacing.
+    fn combine(&self, terms: &[(f64, usize)]) -> (f64, f64) {
+        terms.iter().fold((0.0, 0.0), |(x, y), &(w, k)| {
+            let c = self.coefficients[k];
+            (
+                x + w * (c.0 / self.spacing.0) as f64,
+                y + w * (c.1 / self.spacing.1) as f64,
+            )
+        })
+    }
+}
+
+fn cubic_weights(u: f32) -> [f32; 4] {
+    let (u2, u3) = (u * u, u * u * u);
+    [
+        (1.0 - u).powi(3) / 6.0,
+        (3.0 * u3 - 6.0 * u2 + 4.0) / 6.0,
+        (-3.0 * u3 + 3.0 * u2 + 3.0 * u + 1.0) / 6.0,
+        u3 / 6.0,
+    ]
+}
+
+/// Settings for `refine`.
+#[derive(Clone, Copy, Debug)]
+pub struct DeformOptions {
+    /// Control points spanning the longer side of the image; fewer gives a stiffer field.
+    pub control_points: usize,
+    pub max_iterations: usize,
+    /// Weight of the bending energy against the similarity metric.
+    pub regularization: f64,
+    /// Pixels sampled for each evaluation of the metric.
+    pub samples: usize,
+    pub metric: Metric,
+    /// Seeds the choice of sampled pixels.
+    pub seed: u64,
+}
+
+impl Default for DeformOptions {
+    fn default() -> Self {
+        Self {
+            control_points: 6,
+            max_iterations: 40,
+            regularization: 0.5,
+            samples: 4000,
+            metric: Metric::default(),
+            seed: 0,
+        }
+    }
+}
+
+/// Outcome of `refine`.
+#[derive(Clone, Debug)]
+pub struct DeformReport {
+    /// Metric under the affine transform alone.
+    pub initial_metric: f32,
+    pub metric: f32,
+    pub bending_energy: f64,
+    pub iterations: usize,
+    pub converged: bool,
+    pub elapsed: Duration,
+}
+
+/// A section pixel with the control points it depends on.
+struct Sample {
+    at: Point,
+    fixed: f32,
+    support: [(usize, f32); 16],
+}
+
+/// Refines an affine alignment of `moving`, resized to the size of `fixed`,
+/// with a B-spline deformation maximising `options.metric` less a bending
+/// penalty.
+///
+/// `affine` takes moving pixels to fixed pixels as in `iter_align`; the field
+/// returned displaces fixed pixels before they are mapped back through it.
+/// Optimises by gradient descent over a random sample of pixels, with the
+/// gradient taken analytically through the B-spline weights, shrinking the
+/// step whenever it fails to improve the cost.
+pub fn refine(
+    moving: &Buffer,
+    fixed: &Buffer,
+    affine: [f32; 9],
+    options: &DeformOptions,
+) -> (BSplineField, DeformReport) {
+    let start = Instant::now();
+
+    let (moving, fixed) = (imadjust_buff(moving), imadjust_buff(fixed));
+    let (w, h) = fixed.dimensions();
+    let moving = resize(&moving, w, h, FilterType::Gaussian);
+
+    let spacing = w.max(h) as f32 / (options.control_points.max(2) - 1) as f32;
+    let mut field = BSplineField::zeros((w as usize, h as usize), (spacing, spacing));
+
+    let Some(inverse) = mat3_inv(&affine) else {
+        let report = DeformReport {
+            initial_metric: f32::NAN,
+            metric: f32::NAN,
+            bending_energy: 0.0,
+            iterations: 0,
+            converged: false,
+            elapsed: start.elapsed(),
+        };
+        return (field, report);
+    };
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let samples = (0..options.samples.min((w * h) as usize))
+        .map(|_| {
+            let (x, y) = (rng.random_range(0..w), rng.random_range(0..h));
+            let at = (x as f32, y as f32);
+            Sample {
+                at,
+                fixed: fixed.get_pixel(x, y).0[0],
+                support: field.support(at),
+            }
+        })
+        .collect::<Vec<Sample>>();
+
+    let similarity = |field: &BSplineField| {
+        let pairs = samples.iter().map(|s| {
+            let (dx, dy) = field.displacement_over(&s.support);
+            let src = project(&inverse, (s.at.0 + dx, s.at.1 + dy));
+            (s.fixed, bilinear(&moving, src).0)
+        });
+        options.metric.similarity_of(pairs) as f64
+    };
+    let cost =
+        |field: &BSplineField| -similarity(field) + options.regularization * field.bending_energy();
+
+    // chain rule: metric through the moving intensities, the image slope and
+    // the projection, onto the control points each sample depends on
+    let cost_gradient = |field: &BSplineField| {
+        let warped = samples
+            .iter()
+            .map(|s| {
+                let (dx, dy) = field.displacement_over(&s.support);
+                let at = (s.at.0 + dx, s.at.1 + dy);
+                let (value, slope) = bilinear(&moving, project(&inverse, at));
+                (value, pull_back(&inverse, at, slope))
+            })
+            .collect::<Vec<(f32, Point)>>();
+        let pairs = samples
+            .iter()
+            .zip(&warped)
+            .map(|(s, &(m, _))| (s.fixed, m))
+            .collect::<Vec<(f32, f32)>>();
+        let d_metric = options.metric.gradient_of(&pairs);
+
+        let mut gradient = field.bending_gradient();
+        gradient
+            .iter_mut()
+            .for_each(|g| *g = (g.0 * options.regularization, g.1 * options.regularization));
+        for ((s, &(_, (gx, gy))), d) in samples.iter().zip(&warped).zip(d_metric) {
+            for &(k, w) in &s.support {
+                let g = &mut gradient[k];
+                g.0 -= (d * w * gx) as f64;
+                g.1 -= (d * w * gy) as f64;
+            }
+        }
+        gradient
+    };
+
+    let initial_metric = similarity(&field) as f32;
+    let mut current = cost(&field);
+    let mut step = spacing * 0.1;
+    let mut iterations = 0;
+    let mut converged = false;
+
+    'descent: while iterations < options.max_iterations {
+        iterations += 1;
+
+        let gradient = cost_gradient(&field);
+        let norm = gradient
+            .iter()
+            .fold(0.0, |m: f64, g| m.max(g.0.abs()).max(g.1.abs()));
+        if norm == 0.0 {
+            converged = true;
+            break;
+        }
+
+        // the largest control point moves by `step` pixels
+        loop {
+            let mut candidate = field.clone();
+            candidate
+                .coefficients
+                .iter_mut()
+                .enumerate()
+                .for_each(|(k, c)| {
+                    c.0 -= step * (gradient[k].0 / norm) as f32;
+                    c.1 -= step * (gradient[k].1 / norm) as f32;
+                });
+
+            let c = cost(&candidate);
+            if c < current {
+                (field, current) = (candidate, c);
+                step *= 1.2;
+                break;
+            }
+
+            step *= 0.5;
+            if step < 0.01 {
+                converged = true;
+                break 'descent;
+            }
+        }
+    }
+
+    let report = DeformReport {
+        initial_metric,
+        metric: similarity(&field) as f32,
+        bending_energy: field.bending_energy(),
+        iterations,
+        converged,
+        elapsed: start.elapsed(),
+    };
+    (field, report)
+}
+
+/// Bilinear sample of `im` at `(x, y)`, zero outside it, with its slope in x
+/// and y.
+fn bilinear(im: &Buffer, (x, y): Point) -> (f32, Point) {
+    let (w, h) = im.dimensions();
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let at = |i: f32, j: f32| {
+        if i < 0.0 || j < 0.0 || i >= w as f32 || j >= h as f32 {
+            0.0
+        } else {
+            im.get_pixel(i as u32, j as u32).0[0]
+        }
+    };
+
+    let (a, b) = (at(x0, y0), at(x0 + 1.0, y0));
+    let (c, d) = (at(x0, y0 + 1.0), at(x0 + 1.0, y0 + 1.0));
+    let top = a * (1.0 - fx) + b * fx;
+    let bottom = c * (1.0 - fx) + d * fx;
+    let slope = ((b - a) * (1.0 - fy) + (d - c) * fy, bottom - top);
+    (top * (1.0 - fy) + bottom * fy, slope)
+}
+
+/// Carries `slope`, taken at `project(t, at)`, back to a slope at `at`.
+fn pull_back(t: &[f32; 9], at: Point, (gx, gy): Point) -> Point {
+    let w = t[6] * at.0 + t[7] * at.1 + t[8];
+    let (u, v) = project(t, at);
+    // Jacobian of the projection, row by row
+    let (ux, uy) = ((t[0] - u * t[6]) / w, (t[1] - u * t[7]) / w);
+    let (vx, vy) = ((t[3] - v * t[6]) / w, (t[4] - v * t[7]) / w);
+    (gx * ux + gy * vx, gx * uy + gy * vy)
+}
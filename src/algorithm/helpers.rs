@@ -73,3 +73,37 @@ pub fn nearest(pt: Pnt, pts: &Vec<Pnt>) -> Option<&Pnt> {
         }
     })
 }
+
+/// Solves the square system `a x = b` by Gaussian elimination with partial
+/// pivoting. `None` if `a` is singular.
+pub fn solve_linear(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let (upper, lower) = a.split_at_mut(col + 1);
+        let (b_upper, b_lower) = b.split_at_mut(col + 1);
+        let pivot_row = &upper[col][col..];
+        for (row, b_row) in lower.iter_mut().zip(b_lower) {
+            let f = row[col] / pivot_row[0];
+            row[col..]
+                .iter_mut()
+                .zip(pivot_row)
+                .for_each(|(a, p)| *a -= f * p);
+            *b_row -= f * b_upper[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let acc = (row + 1..n).map(|k| a[row][k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - acc) / a[row][row];
+    }
+    Some(x)
+}
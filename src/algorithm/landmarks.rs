@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::algorithm::helpers::solve_linear;
+
+pub type Point = (f32, f32);
+
+/// Paired control points, atlas slice pixels to full-resolution section pixels.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Landmarks {
+    pub atlas: Vec<Point>,
+    pub section: Vec<Point>,
+    /// Whether the section is mapped with a thin-plate spline through the
+    /// points rather than their least-squares affine fit.
+    pub thin_plate: bool,
+}
+
+/// Least-squares affine taking `src` onto `dst`, as a row-major 3x3 matrix.
+/// Needs at least three points that are not collinear.
+pub fn fit_affine(src: &[Point], dst: &[Point]) -> Option<[f32; 9]> {
+    if src.len() < 3 || src.len() != dst.len() {
+        return None;
+    }
+
+    // normal equations of [x y 1] p = u, shared by both output coordinates
+    let mut ata = vec![vec![0.0; 3]; 3];
+    let mut atu = vec![0.0; 3];
+    let mut atv = vec![0.0; 3];
+
+    src.iter().zip(dst).for_each(|(&(x, y), &(u, v))| {
+        let row = [x as f64, y as f64, 1.0];
+        for i in 0..3 {
+            for j in 0..3 {
+                ata[i][j] += row[i] * row[j];
+            }
+            atu[i] += row[i] * u as f64;
+            atv[i] += row[i] * v as f64;
+        }
+    });
+
+    let p = solve_linear(ata.clone(), atu)?;
+    let q = solve_linear(ata, atv)?;
+
+    Some([
+        p[0] as f32,
+        p[1] as f32,
+        p[2] as f32,
+        q[0] as f32,
+        q[1] as f32,
+        q[2] as f32,
+        0.0,
+        0.0,
+        1.0,
+    ])
+}
+
+/// Thin-plate spline interpolating `src` onto `dst` exactly.
+#[derive(Clone, Debug)]
+pub struct ThinPlate {
+    centres: Vec<Point>,
+    // per output coordinate: one weight per centre, then the affine part [1, x, y]
+    wx: Vec<f64>,
+    wy: Vec<f64>,
+}
+
+impl ThinPlate {
+    pub fn fit(src: &[Point], dst: &[Point]) -> Option<Self> {
+        let n = src.len();
+        if n < 3 || n != dst.len() {
+            return None;
+        }
+
+        let mut a = vec![vec![0.0; n + 3]; n + 3];
+        for i in 0..n {
+            for j in 0..n {
+                a[i][j] = kernel(src[i], src[j]);
+            }
+            let p = [1.0, src[i].0 as f64, src[i].1 as f64];
+            for k in 0..3 {
+                a[i][n + k] = p[k];
+                a[n + k][i] = p[k];
+            }
+        }
+
+        let rhs = |f: fn(&Point) -> f32| {
+            let mut b = dst.iter().map(|p| f(p) as f64).collect::<Vec<f64>>();
+            b.extend([0.0; 3]);
+            b
+        };
+
+        Some(Self {
+            centres: src.to_vec(),
+            wx: solve_linear(a.clone(), rhs(|p| p.0))?,
+            wy: solve_linear(a, rhs(|p| p.1))?,
+        })
+    }
+
+    pub fn apply(&self, pt: Point) -> Point {
+        let n = self.centres.len();
+        let eval = |w: &[f64]| {
+            let bend = self
+                .centres
+                .iter()
+                .zip(w)
+                .map(|(&c, &wi)| wi * kernel(c, pt))
+                .sum::<f64>();
+            bend + w[n] + w[n + 1] * pt.0 as f64 + w[n + 2] * pt.1 as f64
+        };
+        (eval(&self.wx) as f32, eval(&self.wy) as f32)
+    }
+}
+
+/// The thin-plate radial basis r² log r².
+fn kernel(a: Point, b: Point) -> f64 {
+    let r2 = ((a.0 - b.0) as f64).powi(2) + ((a.1 - b.1) as f64).powi(2);
+    if r2 == 0.0 {
+        0.0
+    } else {
+        r2 * r2.ln()
+    }
+}
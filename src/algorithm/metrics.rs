@@ -0,0 +1,272 @@
+use image::{ImageBuffer, Luma};
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+type Buffer = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Histogram bins used by the information-theoretic metrics.
+const N_BINS: usize = 50;
+
+/// How closely a warped atlas slice matches a section. Images are expected to
+/// be intensity-normalised to [0, 1]; larger values always mean a better match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Metric {
+    /// Mutual information with a cubic B-spline Parzen window on the moving
+    /// image, after Mattes et al. Robust when the two contrasts differ, e.g.
+    /// Nissl or autofluorescence against the reference template.
+    #[default]
+    MattesMutualInformation,
+    /// (H(fixed) + H(moving)) / H(fixed, moving); less sensitive to how much
+    /// of the slice overlaps the section.
+    NormalizedMutualInformation,
+    /// Pearson correlation of intensities, for contrasts that differ only by
+    /// gain and offset.
+    NormalizedCrossCorrelation,
+    /// Negated mean squared difference, for near-identical contrasts.
+    MeanSquaredDifference,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 4] = [
+        Self::MattesMutualInformation,
+        Self::NormalizedMutualInformation,
+        Self::NormalizedCrossCorrelation,
+        Self::MeanSquaredDifference,
+    ];
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            Self::MattesMutualInformation => "Mattes MI",
+            Self::NormalizedMutualInformation => "Normalized MI",
+            Self::NormalizedCrossCorrelation => "NCC",
+            Self::MeanSquaredDifference => "MSD",
+        }
+    }
+
+    pub fn similarity(&self, fixed: &Buffer, moving: &Buffer) -> f32 {
+        self.similarity_of(fixed.iter().copied().zip(moving.iter().copied()))
+    }
+
+    /// The metric over `(fixed, moving)` intensity pairs, e.g. a sample of pixels.
+    pub fn similarity_of(&self, pairs: impl Iterator<Item = (f32, f32)>) -> f32 {
+        match self {
+            Self::MattesMutualInformation => mutual_information(&parzen_histogram(pairs)),
+            Self::NormalizedMutualInformation => {
+                normalized_mutual_information(&joint_histogram(pairs))
+            }
+            Self::NormalizedCrossCorrelation => cross_correlation(pairs),
+            Self::MeanSquaredDifference => -mean_squared_difference(pairs),
+        }
+    }
+
+    /// Derivative of `similarity_of` with respect to each moving intensity.
+    /// Normalized MI is differentiated through the Parzen-windowed histogram,
+    /// its own being flat between bin edges.
+    pub fn gradient_of(&self, pairs: &[(f32, f32)]) -> Vec<f32> {
+        match self {
+            Self::MattesMutualInformation => {
+                let joint = parzen_histogram(pairs.iter().copied());
+                let (_, pm) = marginals(&joint);
+                histogram_gradient(pairs, |i, j| {
+                    let (a, m) = (joint[(i, j)], pm[i]);
+                    if a > 0.0 && m > 0.0 {
+                        (a / m).ln()
+                    } else {
+                        0.0
+                    }
+                })
+            }
+            Self::NormalizedMutualInformation => {
+                let joint = parzen_histogram(pairs.iter().copied());
+                let (pf, pm) = marginals(&joint);
+                let h_marginals = entropy(pf.iter()) + entropy(pm.iter());
+                let h_joint = entropy(joint.iter());
+                if h_joint <= 0.0 {
+                    return vec![0.0; pairs.len()];
+                }
+                let ln = |a: f64| if a > 0.0 { a.ln() } else { 0.0 };
+                histogram_gradient(pairs, |i, j| {
+                    (h_marginals * ln(joint[(i, j)]) - h_joint * ln(pm[i])) / (h_joint * h_joint)
+                })
+            }
+            Self::NormalizedCrossCorrelation => cross_correlation_gradient(pairs),
+            Self::MeanSquaredDifference => {
+                let n = pairs.len() as f32;
+                pairs.iter().map(|&(f, m)| 2.0 * (f - m) / n).collect()
+            }
+        }
+    }
+}
+
+fn bin(a: f32) -> f32 {
+    (a * N_BINS as f32).clamp(0.0, N_BINS as f32 - 1.0)
+}
+
+/// Joint histogram indexed `(moving, fixed)`, normalised to sum to one.
+fn joint_histogram(pairs: impl Iterator<Item = (f32, f32)>) -> Array2<f64> {
+    let mut joint = Array2::zeros((N_BINS, N_BINS));
+    pairs.for_each(|(f, m)| joint[(bin(m) as usize, bin(f) as usize)] += 1.0);
+
+    let total = joint.sum();
+    if total > 0.0 {
+        joint /= total;
+    }
+    joint
+}
+
+/// Joint histogram with each moving sample spread over the four nearest bins
+/// by a cubic B-spline, making the metric smooth in the transform; the fixed
+/// image keeps a zero-order window as in Mattes et al.
+fn parzen_histogram(pairs: impl Iterator<Item = (f32, f32)>) -> Array2<f64> {
+    let mut joint = Array2::zeros((N_BINS, N_BINS));
+    pairs.for_each(|(f, m)| {
+        let j = bin(f) as usize;
+        let centre = bin(m) - 0.5;
+        let first = centre.floor() as isize - 1;
+        (first..first + 4).for_each(|i| {
+            let w = cubic_bspline((i as f32 - centre) as f64);
+            // weight falling off either end stays in the end bins
+            joint[(i.clamp(0, N_BINS as isize - 1) as usize, j)] += w;
+        });
+    });
+
+    let total = joint.sum();
+    if total > 0.0 {
+        joint /= total;
+    }
+    joint
+}
+
+fn cubic_bspline(x: f64) -> f64 {
+    let x = x.abs();
+    if x < 1.0 {
+        (4.0 - 6.0 * x * x + 3.0 * x * x * x) / 6.0
+    } else if x < 2.0 {
+        (2.0 - x).powi(3) / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn cubic_bspline_slope(x: f64) -> f64 {
+    let (sign, x) = (x.signum(), x.abs());
+    if x < 1.0 {
+        sign * (-2.0 * x + 1.5 * x * x)
+    } else if x < 2.0 {
+        sign * -0.5 * (2.0 - x).powi(2)
+    } else {
+        0.0
+    }
+}
+
+/// Chains `d_dp`, the derivative of a metric with respect to the Parzen
+/// histogram entry `(moving bin, fixed bin)`, through each moving sample's
+/// window. Constant terms of `d_dp` cancel, each window summing to one.
+fn histogram_gradient(pairs: &[(f32, f32)], d_dp: impl Fn(usize, usize) -> f64) -> Vec<f32> {
+    let n = pairs.len() as f64;
+    pairs
+        .iter()
+        .map(|&(f, m)| {
+            let j = bin(f) as usize;
+            let scaled = m * N_BINS as f32;
+            // clamped intensities no longer move their window
+            if scaled <= 0.0 || scaled >= N_BINS as f32 - 1.0 {
+                return 0.0;
+            }
+            let centre = bin(m) - 0.5;
+            let first = centre.floor() as isize - 1;
+            let d = (first..first + 4).fold(0.0, |acc, i| {
+                let dw = -cubic_bspline_slope((i as f32 - centre) as f64) * N_BINS as f64;
+                acc + dw * d_dp(i.clamp(0, N_BINS as isize - 1) as usize, j)
+            });
+            (d / n) as f32
+        })
+        .collect()
+}
+
+fn entropy<'a>(p: impl Iterator<Item = &'a f64>) -> f64 {
+    p.filter(|&&a| a > 0.0).map(|&a| -a * a.ln()).sum()
+}
+
+fn marginals(joint: &Array2<f64>) -> (Array1<f64>, Array1<f64>) {
+    (joint.sum_axis(Axis(0)), joint.sum_axis(Axis(1)))
+}
+
+fn mutual_information(joint: &Array2<f64>) -> f32 {
+    let (pf, pm) = marginals(joint);
+    let mi = joint.indexed_iter().fold(0.0, |acc, ((i, j), &a)| {
+        let pp = pm[i] * pf[j];
+        if a > 0.0 && pp > 0.0 {
+            acc + a * (a / pp).ln()
+        } else {
+            acc
+        }
+    });
+    mi as f32
+}
+
+fn normalized_mutual_information(joint: &Array2<f64>) -> f32 {
+    let (pf, pm) = marginals(joint);
+    let h_joint = entropy(joint.iter());
+    if h_joint > 0.0 {
+        ((entropy(pf.iter()) + entropy(pm.iter())) / h_joint) as f32
+    } else {
+        1.0
+    }
+}
+
+fn cross_correlation(pairs: impl Iterator<Item = (f32, f32)>) -> f32 {
+    let (mut n, mut sf, mut sm, mut sff, mut smm, mut sfm) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    pairs.for_each(|(f, m)| {
+        let (f, m) = (f as f64, m as f64);
+        n += 1.0;
+        sf += f;
+        sm += m;
+        sff += f * f;
+        smm += m * m;
+        sfm += f * m;
+    });
+
+    let cov = sfm - sf * sm / n;
+    let var = (sff - sf * sf / n) * (smm - sm * sm / n);
+    if var > 0.0 {
+        (cov / var.sqrt()) as f32
+    } else {
+        0.0
+    }
+}
+
+fn cross_correlation_gradient(pairs: &[(f32, f32)]) -> Vec<f32> {
+    let n = pairs.len() as f64;
+    let (sf, sm) = pairs.iter().fold((0.0, 0.0), |(sf, sm), &(f, m)| {
+        (sf + f as f64, sm + m as f64)
+    });
+    let (mf, mm) = (sf / n, sm / n);
+    let (vf, vm, cov) = pairs
+        .iter()
+        .fold((0.0, 0.0, 0.0), |(vf, vm, cov), &(f, m)| {
+            let (df, dm) = (f as f64 - mf, m as f64 - mm);
+            (vf + df * df, vm + dm * dm, cov + df * dm)
+        });
+    if vf * vm <= 0.0 {
+        return vec![0.0; pairs.len()];
+    }
+
+    let norm = (vf * vm).sqrt();
+    let r = cov / norm;
+    pairs
+        .iter()
+        .map(|&(f, m)| ((f as f64 - mf) / norm - r * (m as f64 - mm) / vm) as f32)
+        .collect()
+}
+
+fn mean_squared_difference(pairs: impl Iterator<Item = (f32, f32)>) -> f32 {
+    let (n, total) = pairs.fold((0usize, 0.0f64), |(n, total), (f, m)| {
+        (n + 1, total + ((f - m) as f64).powi(2))
+    });
+    if n > 0 {
+        (total / n as f64) as f32
+    } else {
+        0.0
+    }
+}
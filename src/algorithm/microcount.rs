@@ -1,6 +1,7 @@
+use crate::model::ImageMetadata;
 use crate::utility::{
-    io::{read_tiff_region, save_as_binary, save_as_luma8, save_as_rgb_bool},
-    types::{Pnt, Results, Settings, ROI},
+    io::{read_tiff_region, save_as_binary, save_as_luma32, save_as_rgb_bool},
+    types::{CellRecord, CellTable, Intermediate, Matrix, Pnt, Region, RegionArea, Settings, ROI},
 };
 
 use crate::algorithm::{
@@ -8,19 +9,520 @@ use crate::algorithm::{
     helpers::{nearest, scholl},
     proc::pacefilt,
     regions::{centroids, floodfill, label2regions, regions2label, rotunditiy},
+    tiling::{tile_grid, Tile},
 };
 
 use ndarray::prelude::*;
 use scirs2_ndimage::morphology::binary_opening;
+use std::path::PathBuf;
+use tiff::TiffError;
 
-pub fn from_fn(file_name: &str, roi: ROI, settings: Settings) -> Results {
-    let channels = read_tiff_region(file_name, roi, 1).expect("ReadFailure");
-    let iba1 = &(channels[2].map(|&a| a as f64));
-    let cd68 = &(channels[1].map(|&a| a as f64));
+#[derive(Debug)]
+pub enum AnalysisError {
+    Tiff(TiffError),
+    Cancelled,
+}
+
+impl std::fmt::Display for AnalysisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Tiff(err) => write!(f, "{}", err),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl From<TiffError> for AnalysisError {
+    fn from(err: TiffError) -> Self {
+        Self::Tiff(err)
+    }
+}
+
+impl From<std::io::Error> for AnalysisError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Tiff(err.into())
+    }
+}
+
+/// Called with a stage name and percent complete as the pipeline advances.
+/// Returning `false` cancels the run at the next stage boundary.
+pub type Progress<'a> = &'a (dyn Fn(&str, f32) -> bool + Sync);
+
+const STAGES: [&str; 7] = [
+    "Reading",
+    "Segmenting co-marker",
+    "Segmenting somas",
+    "Detecting branches",
+    "Separating cells",
+    "Measuring morphology",
+    "Saving",
+];
+
+/// Maps the stages of one segmentation pass onto a slice of the overall percentage.
+struct Reporter<'a> {
+    progress: Progress<'a>,
+    start: f32,
+    span: f32,
+}
+
+impl Reporter<'_> {
+    fn report(&self, stage: &str, frac: f32) -> Result<(), AnalysisError> {
+        if (self.progress)(stage, self.start + self.span * frac) {
+            Ok(())
+        } else {
+            Err(AnalysisError::Cancelled)
+        }
+    }
+
+    fn stage(&self, idx: usize) -> Result<(), AnalysisError> {
+        self.report(STAGES[idx], idx as f32 / STAGES.len() as f32)
+    }
+}
+
+/// Where the intermediates selected in `Settings::intermediates` are written.
+#[derive(Clone, Debug)]
+pub struct ArtifactDirs {
+    pub proc_dir: PathBuf,
+    pub mask_dir: PathBuf,
+}
+
+impl ArtifactDirs {
+    pub fn for_image(img: &ImageMetadata) -> Self {
+        Self {
+            proc_dir: img.proc_dir().into(),
+            mask_dir: img.mask_dir().into(),
+        }
+    }
+
+    /// Keeps each region's intermediates in its own sub-folder.
+    pub fn for_region(&self, region: &Region) -> Self {
+        let dir = region.dir_name();
+        Self {
+            proc_dir: self.proc_dir.join(&dir),
+            mask_dir: self.mask_dir.join(&dir),
+        }
+    }
+
+    pub fn path(&self, a: Intermediate) -> PathBuf {
+        if a.is_mask() {
+            self.mask_dir.join(a.file_name())
+        } else {
+            self.proc_dir.join(a.file_name())
+        }
+    }
+}
+
+/// Analyses the whole image, or only its regions when it has any.
+///
+/// Each region is analysed over its bounding box with pixels outside the
+/// polygon ignored, and its cells are reported in image coordinates.
+pub fn from_image(
+    img: &ImageMetadata,
+    settings: &Settings,
+    progress: Progress,
+) -> Result<CellTable, AnalysisError> {
+    let out = ArtifactDirs::for_image(img);
+    let channels = (img.cell_channel, img.comarker_channel);
+
+    if img.regions.is_empty() {
+        return from_fn(
+            &img.analysis_fn(),
+            img.full_roi(),
+            channels.0,
+            channels.1,
+            settings,
+            &out,
+            None,
+            progress,
+        );
+    }
+
+    let n = img.regions.len() as f32;
+    let mut table = CellTable {
+        cells: vec![],
+        percentage_cd68_area: 0.0,
+        regions: vec![],
+    };
+
+    for (i, region) in img.regions.iter().enumerate() {
+        let roi = region.bounds(img.size);
+        let sub = |stage: &str, percent: f32| {
+            let stage = format!("{}: {}", region.name, stage);
+            progress(&stage, 100.0 * (i as f32 + percent / 100.0) / n)
+        };
+
+        let t = from_fn(
+            &img.analysis_fn(),
+            roi,
+            channels.0,
+            channels.1,
+            settings,
+            &out.for_region(region),
+            Some(region),
+            &sub,
+        )?;
+
+        table.cells.extend(t.cells.into_iter().map(|mut c| {
+            c.centroid_row += roi.0;
+            c.centroid_col += roi.1;
+            c
+        }));
+        table.regions.extend(t.regions);
+    }
+
+    let area = table.regions.iter().map(|r| r.area).sum::<usize>();
+    let cd68 = table
+        .regions
+        .iter()
+        .map(|r| r.percentage_cd68_area * r.area as f64)
+        .sum::<f64>();
+    table.percentage_cd68_area = cd68 / area as f64;
+
+    progress("Done", 100.0);
+    Ok(table)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn from_fn(
+    file_name: &str,
+    roi: ROI,
+    cell_channel: usize,
+    comarker_channel: usize,
+    settings: &Settings,
+    out: &ArtifactDirs,
+    within: Option<&Region>,
+    progress: Progress,
+) -> Result<CellTable, AnalysisError> {
+    let channels = (cell_channel, comarker_channel);
+
+    let (cells, cd68_count, area) = match settings.tile_size {
+        Some(size) if roi.2 > size || roi.3 > size => from_fn_tiled(
+            file_name, roi, channels, settings, out, size, within, progress,
+        )?,
+        _ => {
+            let rep = Reporter {
+                progress,
+                start: 0.0,
+                span: 100.0,
+            };
+
+            rep.stage(0)?;
+            let (iba1, cd68) = read_channels(file_name, roi, channels)?;
+            let inside = Footprint::new(within, roi);
+            let mut stats = ChannelStats::default();
+            stats.accumulate(&iba1, &cd68, &inside);
+
+            let seg = segment(&iba1, &cd68, &stats, settings, &rep)?;
+            save_intermediates(&seg, settings, out)?;
+
+            let cd68_count = seg
+                .cd68_mask
+                .indexed_iter()
+                .filter(|&(pt, &a)| a && inside.contains(pt))
+                .count();
+            let cells = seg
+                .cells
+                .into_iter()
+                .filter(|c| inside.contains((c.centroid_row, c.centroid_col)))
+                .collect();
+
+            (cells, cd68_count, inside.area())
+        }
+    };
+
+    let percentage_cd68_area = 100.0 * cd68_count as f64 / area as f64;
+    let mut table = CellTable {
+        cells,
+        percentage_cd68_area,
+        regions: vec![],
+    };
+
+    if let Some(region) = within {
+        table
+            .cells
+            .iter_mut()
+            .for_each(|c| c.region = region.name.clone());
+        table.regions.push(RegionArea {
+            name: region.name.clone(),
+            area,
+            percentage_cd68_area,
+        });
+    }
+
+    progress("Done", 100.0);
+    Ok(table)
+}
+
+/// Which pixels of a read window count towards the analysis: all of them, or
+/// only those inside a region.
+struct Footprint {
+    mask: Option<Matrix<bool>>,
+    len: usize,
+}
+
+impl Footprint {
+    fn new(region: Option<&Region>, roi: ROI) -> Self {
+        Self {
+            mask: region.map(|r| r.mask(roi)),
+            len: roi.2 * roi.3,
+        }
+    }
 
+    fn contains(&self, pt: Pnt) -> bool {
+        self.mask.as_ref().is_none_or(|m| m[pt])
+    }
+
+    fn area(&self) -> usize {
+        match &self.mask {
+            Some(m) => m.iter().filter(|&&a| a).count(),
+            None => self.len,
+        }
+    }
+}
+
+/// Runs the pipeline over `roi` in overlapping tiles so only one tile is held
+/// in memory at a time.
+///
+/// Normalisation statistics are gathered over the whole ROI in a first pass so
+/// every tile is thresholded exactly as a single pass would. Each cell is kept
+/// only by the tile whose core contains its soma centroid; with an overlap wider
+/// than a cell's territory this reproduces the single-pass cell table.
+/// Intermediates are stitched from the tile cores into images of the whole ROI.
+///
+/// Returns the cells, the co-marker pixel count and the analysed area.
+#[allow(clippy::too_many_arguments)]
+fn from_fn_tiled(
+    file_name: &str,
+    roi: ROI,
+    channels: (usize, usize),
+    settings: &Settings,
+    out: &ArtifactDirs,
+    size: usize,
+    within: Option<&Region>,
+    progress: Progress,
+) -> Result<(Vec<CellRecord>, usize, usize), AnalysisError> {
+    // the statistics pass is cheap next to segmentation, give it a tenth of the bar
+    let stats_tiles = tile_grid(roi, size, 0);
+    let n_tiles = stats_tiles.len() as f32;
+
+    let mut stats = ChannelStats::default();
+    let mut area = 0;
+    for (i, tile) in stats_tiles.into_iter().enumerate() {
+        let rep = Reporter {
+            progress,
+            start: 10.0 * i as f32 / n_tiles,
+            span: 0.0,
+        };
+        rep.report("Gathering statistics", 0.0)?;
+        let (iba1, cd68) = read_channels(file_name, tile.core, channels)?;
+        let inside = Footprint::new(within, tile.core);
+        stats.accumulate(&iba1, &cd68, &inside);
+        area += inside.area();
+    }
+
+    let mut cells = vec![];
+    let mut cd68_count = 0;
+    let mut stitched = Stitched::new(settings, (roi.2, roi.3));
+
+    for (i, tile) in tile_grid(roi, size, settings.tile_overlap)
+        .into_iter()
+        .enumerate()
+    {
+        let rep = Reporter {
+            progress,
+            start: 10.0 + 90.0 * i as f32 / n_tiles,
+            span: 90.0 / n_tiles,
+        };
+
+        rep.stage(0)?;
+        let (iba1, cd68) = read_channels(file_name, tile.read, channels)?;
+        let mut seg = segment(&iba1, &cd68, &stats, settings, &rep)?;
+        let inside = Footprint::new(within, tile.read);
+
+        cd68_count += seg
+            .cd68_mask
+            .indexed_iter()
+            .filter(|&(pt, &a)| a && tile.owns(pt) && inside.contains(pt))
+            .count();
+
+        // ROI-wide label of each of the tile's cells, 0 for those it does not own
+        let mut labels = vec![0; seg.cells.len()];
+        for (i, mut c) in std::mem::take(&mut seg.cells).into_iter().enumerate() {
+            let pt = (c.centroid_row, c.centroid_col);
+            if tile.owns(pt) && inside.contains(pt) {
+                c.label_id = cells.len() as u32 + 1;
+                labels[i] = c.label_id;
+                cells.push(to_roi_coords(c, &tile, roi));
+            }
+        }
+        stitched.add(&seg, &tile, roi, &labels);
+    }
+
+    save_intermediates(&stitched.0, settings, out)?;
+
+    Ok((cells, cd68_count, area))
+}
+
+/// Intermediates of a whole ROI pieced together from its tiles. Images
+/// `Settings::intermediates` does not need are left empty.
+struct Stitched(Segmentation);
+
+impl Stitched {
+    fn new(settings: &Settings, dim: Pnt) -> Self {
+        use Intermediate as I;
+        let dim = |needed_for: &[Intermediate]| {
+            let kept = needed_for
+                .iter()
+                .any(|a| settings.intermediates.contains(a));
+            if kept {
+                dim
+            } else {
+                (0, 0)
+            }
+        };
+        Self(Segmentation {
+            cells: vec![],
+            cd68_mask: Matrix::default(dim(&[I::CoMarkerMask])),
+            soma_mask: Matrix::default(dim(&[I::Somas])),
+            branches: Matrix::default(dim(&[I::Branches])),
+            segmented: Matrix::default(dim(&[I::Segmented, I::Perimeter])),
+            skelly: Matrix::default(dim(&[I::Skeleton, I::Overlay])),
+            detected: Matrix::default(dim(&[I::BranchPoints, I::Overlay])),
+            length_img: Matrix::default(dim(&[I::BranchLength])),
+        })
+    }
+
+    /// Copies the core of each of the tile's masks, and every pixel of the
+    /// cells it owns from its label images, relabelled through `labels`.
+    fn add(&mut self, seg: &Segmentation, tile: &Tile, roi: ROI, labels: &[u32]) {
+        let (r, c) = tile.to_roi((0, 0), roi);
+        let (cr, cc, ch, cw) = tile.core_local();
+        let masks = [
+            (&mut self.0.cd68_mask, &seg.cd68_mask),
+            (&mut self.0.soma_mask, &seg.soma_mask),
+            (&mut self.0.branches, &seg.branches),
+            (&mut self.0.skelly, &seg.skelly),
+            (&mut self.0.detected, &seg.detected),
+        ];
+        for (whole, part) in masks.into_iter().filter(|(w, _)| !w.is_empty()) {
+            whole
+                .slice_mut(s![r + cr..r + cr + ch, c + cc..c + cc + cw])
+                .assign(&part.slice(s![cr..cr + ch, cc..cc + cw]));
+        }
+
+        for ((i, j), &l) in seg.segmented.indexed_iter() {
+            let id = match l.checked_sub(1).and_then(|k| labels.get(k as usize)) {
+                Some(&id) if id > 0 => id,
+                _ => continue,
+            };
+            if !self.0.segmented.is_empty() {
+                self.0.segmented[(r + i, c + j)] = id;
+            }
+            if !self.0.length_img.is_empty() && seg.length_img[(i, j)] > 0 {
+                self.0.length_img[(r + i, c + j)] = seg.length_img[(i, j)];
+            }
+        }
+    }
+}
+
+fn to_roi_coords(mut cell: CellRecord, tile: &Tile, roi: ROI) -> CellRecord {
+    let (r, c) = tile.to_roi((cell.centroid_row, cell.centroid_col), roi);
+    cell.centroid_row = r;
+    cell.centroid_col = c;
+    cell
+}
+
+fn read_channels(
+    file_name: &str,
+    roi: ROI,
+    (cell_channel, comarker_channel): (usize, usize),
+) -> Result<(Matrix<f64>, Matrix<f64>), TiffError> {
+    let channels = read_tiff_region(file_name, roi, 1)?;
+    let channel = |idx: usize| {
+        channels.get(idx).ok_or_else(|| {
+            let msg = format!(
+                "channel {} not in {} ({} channels)",
+                idx,
+                file_name,
+                channels.len()
+            );
+            TiffError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))
+        })
+    };
+    let iba1 = channel(cell_channel)?.to_f64();
+    let cd68 = channel(comarker_channel)?.to_f64();
+    Ok((iba1, cd68))
+}
+
+/// Running sums for the log-intensity z-scores and the cell-marker maximum
+/// used to normalise both channels.
+#[derive(Clone, Copy, Debug, Default)]
+struct ChannelStats {
+    n: usize,
+    iba1_sum: f64,
+    iba1_sum_sq: f64,
+    iba1_max: f64,
+    cd68_sum: f64,
+    cd68_sum_sq: f64,
+}
+
+impl ChannelStats {
+    fn accumulate(&mut self, iba1: &Matrix<f64>, cd68: &Matrix<f64>, inside: &Footprint) {
+        iba1.indexed_iter()
+            .zip(cd68.iter())
+            .filter(|&((pt, _), _)| inside.contains(pt))
+            .for_each(|((_, &a), &b)| {
+                let l = (a + 0.000001).log10();
+                self.iba1_sum += l;
+                self.iba1_sum_sq += l * l;
+                self.iba1_max = self.iba1_max.max(a);
+
+                let l = (b + 0.000001).log10();
+                self.cd68_sum += l;
+                self.cd68_sum_sq += l * l;
+                self.n += 1;
+            });
+    }
+
+    fn mean_std(&self, sum: f64, sum_sq: f64) -> (f64, f64) {
+        let n = self.n as f64;
+        let mean = sum / n;
+        (mean, (sum_sq / n - mean * mean).max(0.0).sqrt())
+    }
+
+    fn iba1(&self) -> (f64, f64) {
+        self.mean_std(self.iba1_sum, self.iba1_sum_sq)
+    }
+
+    fn cd68(&self) -> (f64, f64) {
+        self.mean_std(self.cd68_sum, self.cd68_sum_sq)
+    }
+}
+
+/// Cell table and intermediate images for one segmented region.
+struct Segmentation {
+    cells: Vec<CellRecord>,
+    cd68_mask: Matrix<bool>,
+    soma_mask: Matrix<bool>,
+    branches: Matrix<bool>,
+    segmented: Matrix<u32>,
+    skelly: Matrix<bool>,
+    detected: Matrix<bool>,
+    length_img: Matrix<u32>,
+}
+
+fn segment(
+    iba1: &Matrix<f64>,
+    cd68: &Matrix<f64>,
+    stats: &ChannelStats,
+    settings: &Settings,
+    rep: &Reporter,
+) -> Result<Segmentation, AnalysisError> {
     // Segment activation
+    rep.stage(1)?;
+    let (cd68_mean, cd68_std) = stats.cd68();
     let cd68_log = (cd68 + 0.000001).log10();
-    let cd68_norm = (&cd68_log - cd68_log.mean().unwrap()) / cd68_log.std(0.0);
+    let cd68_norm = (&cd68_log - cd68_mean) / cd68_std;
     let cd68_th = cd68_norm.map(|a| *a > settings.co_marker_threshold);
     let cd68_blobs = conncomps(&cd68_th);
     let cd68_regions = cd68_blobs
@@ -30,18 +532,22 @@ pub fn from_fn(file_name: &str, roi: ROI, settings: Settings) -> Results {
         .collect();
 
     // Segment Somas
+    rep.stage(2)?;
+    let (iba1_mean, iba1_std) = stats.iba1();
     let iba1_log = (iba1 + 0.000001).log10();
-    let iba1_norm = (&iba1_log - iba1_log.mean().unwrap()) / iba1_log.std(0.0);
+    let iba1_norm = (&iba1_log - iba1_mean) / iba1_std;
     let soma = iba1_norm.map(|a| *a > settings.soma_threshold);
     let soma_mask =
         binary_opening(&soma, None, Some(5), None, None, None, None).expect("Open Error");
 
     // Segment microglia
-    let iba1_scale = iba1 / iba1.fold(0.0, |acc, a| if acc > *a { acc } else { *a });
+    rep.stage(3)?;
+    let iba1_scale = iba1 / stats.iba1_max;
     let eig1 = pacefilt(&iba1_scale, 17, 5.0);
     let branches = eig1.map(|a| *a > settings.cell_marker_threshold);
 
     // Separate microglia
+    rep.stage(4)?;
     let regions = conncomps(&soma_mask);
     let markers = regions.iter().map(centroids).collect::<Vec<Pnt>>();
 
@@ -52,78 +558,115 @@ pub fn from_fn(file_name: &str, roi: ROI, settings: Settings) -> Results {
     let cell_count = segmented_regions.len();
     let segmented = regions2label(&segmented_regions, soma_mask.dim());
     let skelly = skel(&branches);
-    let poly = perimeter(&segmented);
 
     // Analyse morphology
-    let rotundities = regions.iter().map(rotunditiy);
-    let average_rotundity = Array1::from_iter(rotundities).mean().unwrap();
+    rep.stage(5)?;
     let detected = count_branches(&skelly);
 
     let labelled_skelly = skelly.map(|&a| if a { 1 } else { 0 }) * &segmented;
-    let skelly_regions = label2regions(&labelled_skelly);
+    let mut skelly_regions = label2regions(&labelled_skelly);
+    skelly_regions.resize(cell_count, vec![]);
 
     let skelly_markers = skelly_regions
         .iter()
-        .zip(markers)
-        .map(|(a, b)| nearest(b, a))
-        .filter_map(|a| a.cloned())
-        .collect::<Vec<Pnt>>();
+        .zip(&markers)
+        .map(|(a, &b)| nearest(b, a).cloned())
+        .collect::<Vec<Option<Pnt>>>();
 
     let (length_img, man_hists) = branch_length(&labelled_skelly, &skelly_markers);
     let length_img = length_img.map(|&a| a as u32);
-    let av_lengths_total = man_hists.iter().fold(0, |acc, a| {
-        let weighted_sum = a.iter().enumerate().fold(0, |acc, (a, &b)| acc + a * b);
-        let sum = a.iter().fold(0, |a, &b| a + b);
-        acc + (weighted_sum / sum)
-    });
-    let average_branch_length = av_lengths_total as f64 / cell_count as f64;
-
-    let scholl_idxs = skelly_regions
-        .iter()
-        .zip(skelly_markers)
-        .map(|(a, b)| scholl(b, a.to_vec()));
-
-    let average_scholl = scholl_idxs.fold(0.0, |acc, a| acc + a) / cell_count as f64;
 
     // Analyse activation
-    let mut cd68_mask_label = regions2label(&cd68_regions, cd68.dim());
+    let cd68_mask_label = regions2label(&cd68_regions, cd68.dim());
     let cd68_mask = cd68_mask_label.map(|&a| a > 0);
-    let cd68_count = cd68_mask_label
-        .map(|&a| if a > 0 { 1.0 } else { 0.0 })
-        .sum();
-    let percentage_cd68_area = 100.0 * cd68_count / (cd68_mask_label.iter().count() as f64);
 
-    branches
-        .indexed_iter()
-        .for_each(|(pt, &a)| cd68_mask_label[pt] = if a { cd68_mask_label[pt] } else { 0 });
+    let cells = (0..cell_count)
+        .map(|i| {
+            let territory = &segmented_regions[i];
+            let hist = &man_hists[i];
+            let n_branch_px = hist.iter().sum::<usize>();
+            let weighted_sum = hist.iter().enumerate().map(|(d, &n)| d * n).sum::<usize>();
+            let overlap = territory.iter().filter(|&&pt| cd68_mask[pt]).count();
 
-    let overlap_regions = label2regions(&cd68_mask_label);
-    let n_overlap = overlap_regions
-        .iter()
-        .zip(segmented_regions)
-        .map(|(a, b)| 100.0 * a.len() as f64 / b.len() as f64)
-        .filter(|&a| a > settings.overlap_percentage_threshold)
-        .count() as f64;
-
-    let percentage_cd68_num = 100.0 * n_overlap / cell_count as f64;
-
-    // save images
-    save_as_binary(&cd68_mask, "./assets/cd68_mask.tif");
-    save_as_binary(&soma_mask, "./assets/somas.tif");
-    save_as_binary(&branches, "./assets/branches.tif");
-    save_as_luma8(&segmented, "./assets/segmented.tif");
-    save_as_binary(&skelly, "./assets/skelly.tif");
-    save_as_luma8(&poly, "./assets/poly.tif");
-    save_as_rgb_bool(&skelly, &detected, &detected, "./assets/overlay.tif");
-    save_as_binary(&detected, "./assets/detected.tif");
-    save_as_luma8(&length_img, "./assets/branch_length.tif");
-
-    Results {
-        cell_count,
-        average_rotundity,
-        average_branch_length,
-        average_scholl,
-        percentage_cd68_area,
-        percentage_cd68_num,
+            CellRecord {
+                label_id: (i + 1) as u32,
+                centroid_row: markers[i].0,
+                centroid_col: markers[i].1,
+                soma_area: regions[i].len(),
+                territory_area: territory.len(),
+                rotundity: rotunditiy(&regions[i]),
+                mean_branch_length: if n_branch_px > 0 {
+                    weighted_sum as f64 / n_branch_px as f64
+                } else {
+                    0.0
+                },
+                max_branch_length: hist.len().saturating_sub(1),
+                scholl_slope: skelly_markers[i]
+                    .map(|m| scholl(m, skelly_regions[i].clone()))
+                    .unwrap_or(f64::NAN),
+                branch_points: skelly_regions[i].iter().filter(|&&pt| detected[pt]).count(),
+                // A cell without territory has no co-marker positive pixels.
+                co_marker_overlap: if territory.is_empty() {
+                    0.0
+                } else {
+                    100.0 * overlap as f64 / territory.len() as f64
+                },
+                region: String::new(),
+                structure_id: 0,
+                structure: String::new(),
+                hemisphere: String::new(),
+            }
+        })
+        .collect();
+
+    rep.stage(6)?;
+
+    Ok(Segmentation {
+        cells,
+        cd68_mask,
+        soma_mask,
+        branches,
+        segmented,
+        skelly,
+        detected,
+        length_img,
+    })
+}
+
+fn save_intermediates(
+    seg: &Segmentation,
+    settings: &Settings,
+    out: &ArtifactDirs,
+) -> Result<(), TiffError> {
+    let keep = |a: Intermediate| settings.intermediates.contains(&a);
+    if Intermediate::ALL.iter().any(|&a| keep(a) && a.is_mask()) {
+        std::fs::create_dir_all(&out.mask_dir)?;
+    }
+    if Intermediate::ALL.iter().any(|&a| keep(a) && !a.is_mask()) {
+        std::fs::create_dir_all(&out.proc_dir)?;
     }
+
+    for &a in &settings.intermediates {
+        let path = out.path(a);
+        let path = path.to_string_lossy();
+        let binary = |mask: &Matrix<bool>| {
+            save_as_binary(mask, &path)
+                .map_err(|err| TiffError::IoError(std::io::Error::other(err)))
+        };
+        match a {
+            Intermediate::CoMarkerMask => binary(&seg.cd68_mask)?,
+            Intermediate::Somas => binary(&seg.soma_mask)?,
+            Intermediate::Branches => binary(&seg.branches)?,
+            Intermediate::Segmented => save_as_luma32(&seg.segmented, &path)?,
+            Intermediate::Skeleton => binary(&seg.skelly)?,
+            Intermediate::BranchPoints => binary(&seg.detected)?,
+            Intermediate::Perimeter => save_as_luma32(&perimeter(&seg.segmented), &path)?,
+            Intermediate::Overlay => {
+                save_as_rgb_bool(&seg.skelly, &seg.detected, &seg.detected, &path)
+            }
+            Intermediate::BranchLength => save_as_luma32(&seg.length_img, &path)?,
+        }
+    }
+
+    Ok(())
 }
@@ -1,5 +1,12 @@
+pub mod anatomy;
+pub mod annotation;
 mod binary;
+pub mod deformable;
 mod helpers;
+pub mod landmarks;
+pub mod metrics;
 pub mod microcount;
+mod optimise;
 pub mod proc;
 mod regions;
+pub mod tiling;
@@ -0,0 +1,132 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// When a Nelder–Mead run stops.
+#[derive(Clone, Copy, Debug)]
+pub struct Stopping {
+    pub max_iterations: usize,
+    /// Converged once the costs across the simplex differ by less than this.
+    pub cost_tolerance: f64,
+    /// ... and every vertex is within this distance of the best one.
+    pub step_tolerance: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Minimum {
+    pub x: Vec<f64>,
+    pub cost: f64,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+/// Minimises `cost` with the Nelder–Mead simplex method, starting from `x0`
+/// with an initial simplex spanning `steps` along each axis.
+///
+/// The sign of each initial step is drawn from `seed`, so runs are
+/// reproducible while different seeds explore different starting simplices.
+pub fn nelder_mead<F>(cost: F, x0: &[f64], steps: &[f64], stopping: Stopping, seed: u64) -> Minimum
+where
+    F: Fn(&[f64]) -> f64,
+{
+    const REFLECT: f64 = 1.0;
+    const EXPAND: f64 = 2.0;
+    const CONTRACT: f64 = 0.5;
+    const SHRINK: f64 = 0.5;
+
+    let n = x0.len();
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut simplex = vec![x0.to_vec()];
+    for i in 0..n {
+        let mut x = x0.to_vec();
+        let sign = if rng.random::<bool>() { 1.0 } else { -1.0 };
+        x[i] += sign * steps[i];
+        simplex.push(x);
+    }
+    let mut costs = simplex.iter().map(|x| cost(x)).collect::<Vec<f64>>();
+
+    let along = |from: &[f64], to: &[f64], t: f64| {
+        from.iter()
+            .zip(to)
+            .map(|(&a, &b)| a + t * (b - a))
+            .collect::<Vec<f64>>()
+    };
+
+    let mut iterations = 0;
+    let mut converged = false;
+
+    while iterations < stopping.max_iterations {
+        let mut order = (0..=n).collect::<Vec<usize>>();
+        order.sort_by(|&a, &b| costs[a].total_cmp(&costs[b]));
+        simplex = order.iter().map(|&i| simplex[i].clone()).collect();
+        costs = order.iter().map(|&i| costs[i]).collect();
+
+        let spread = (costs[n] - costs[0]).abs();
+        let size = simplex[1..]
+            .iter()
+            .map(|x| distance(x, &simplex[0]))
+            .fold(0.0, f64::max);
+        if spread < stopping.cost_tolerance && size < stopping.step_tolerance {
+            converged = true;
+            break;
+        }
+
+        iterations += 1;
+
+        let centroid = (0..n)
+            .map(|j| simplex[..n].iter().map(|x| x[j]).sum::<f64>() / n as f64)
+            .collect::<Vec<f64>>();
+
+        let reflected = along(&centroid, &simplex[n], -REFLECT);
+        let reflected_cost = cost(&reflected);
+
+        if reflected_cost < costs[0] {
+            let expanded = along(&centroid, &simplex[n], -EXPAND);
+            let expanded_cost = cost(&expanded);
+            (simplex[n], costs[n]) = if expanded_cost < reflected_cost {
+                (expanded, expanded_cost)
+            } else {
+                (reflected, reflected_cost)
+            };
+        } else if reflected_cost < costs[n - 1] {
+            (simplex[n], costs[n]) = (reflected, reflected_cost);
+        } else {
+            // contract towards the better of the worst vertex and its reflection
+            let (outer, outer_cost) = if reflected_cost < costs[n] {
+                (&reflected, reflected_cost)
+            } else {
+                (&simplex[n], costs[n])
+            };
+            let contracted = along(&centroid, outer, CONTRACT);
+            let contracted_cost = cost(&contracted);
+
+            if contracted_cost < outer_cost {
+                (simplex[n], costs[n]) = (contracted, contracted_cost);
+            } else {
+                for i in 1..=n {
+                    simplex[i] = along(&simplex[0], &simplex[i], SHRINK);
+                    costs[i] = cost(&simplex[i]);
+                }
+            }
+        }
+    }
+
+    let best = (0..=n)
+        .min_by(|&a, &b| costs[a].total_cmp(&costs[b]))
+        .unwrap_or(0);
+
+    Minimum {
+        x: simplex[best].clone(),
+        cost: costs[best],
+        iterations,
+        converged,
+    }
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
@@ -2,14 +2,20 @@ use core::f32;
 use std::f32::consts::PI;
 use std::ops::Div;
 
+use std::time::{Duration, Instant};
+
+use crate::algorithm::deformable::DeformReport;
 use crate::algorithm::helpers::conv;
+use crate::algorithm::metrics::Metric;
+use crate::algorithm::optimise::{nelder_mead, Stopping};
+use crate::model::registration::{mat3_mul, scale};
 use crate::utility::imops::{array2buff, vec2buff};
-use crate::utility::io::{save_as_luma16, save_as_luma8};
 use crate::utility::types::Matrix;
 
 use image::imageops::FilterType;
 use image::{ImageBuffer, Luma};
-use imageproc::geometric_transformations::{rotate, warp, warp_with, Interpolation, Projection};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::geometric_transformations::{warp, warp_into_with, Interpolation, Projection};
 use imageproc::image::imageops::resize;
 use ndarray::prelude::*;
 
@@ -45,7 +51,9 @@ pub fn pacefilt(img: &Matrix<f64>, l: usize, sigma: f64) -> Matrix<f64> {
     eig1
 }
 
-fn imadjust_buff(buff: &ImageBuffer<Luma<f32>, Vec<f32>>) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+pub(crate) fn imadjust_buff(
+    buff: &ImageBuffer<Luma<f32>, Vec<f32>>,
+) -> ImageBuffer<Luma<f32>, Vec<f32>> {
     let mut arr = buff.clone().into_vec();
     arr.sort_by(|a, b| f32::total_cmp(a, b));
 
@@ -63,98 +71,199 @@ fn imadjust_buff(buff: &ImageBuffer<Luma<f32>, Vec<f32>>) -> ImageBuffer<Luma<f3
     return vec2buff(barr, h as usize, w as usize);
 }
 
-pub fn iter_align(
-    moving: &ImageBuffer<Luma<f32>, Vec<f32>>,
-    fixed: &ImageBuffer<Luma<f32>, Vec<f32>>,
-) -> [f32; 9] {
-    println!("Aligning ... ");
-
-    // let moving = array2buff(moving.t().to_owned());
-    // let fixed = array2buff(fixed.to_owned());
-
-    let (moving, fixed) = (imadjust_buff(&moving), imadjust_buff(&fixed));
-
-    let (w, h) = fixed.dimensions();
-    let r_moving = resize(&moving, w, h, FilterType::Gaussian);
+/// Settings for `iter_align`.
+#[derive(Clone, Copy, Debug)]
+pub struct AlignOptions {
+    /// Pyramid levels, each half the size of the one above; 1 aligns at full size only.
+    pub levels: usize,
+    /// Optimiser iterations allowed per level.
+    pub max_iterations: usize,
+    /// A level has converged once the metric varies less than this across the simplex.
+    pub tolerance: f64,
+    /// Seeds the optimiser's initial simplex.
+    pub seed: u64,
+    /// Similarity maximised between the warped slice and the section.
+    pub metric: Metric,
+}
 
-    let mut t = std::array::from_fn(|i| if [0, 4, 8].contains(&i) { 1.0 } else { 0.0 });
-    let mut curr_fitness = mutual_information(&r_moving, &fixed, false);
-
-    for i in 0..2000 {
-        let new_t = mutate(&t); // <--- mutate should be function of interation
-        let proj = Projection::from_matrix(new_t).unwrap();
-        let new_moving = warp(&r_moving, &proj, Interpolation::Nearest, Luma([0.0]));
-        let new_fitness = mutual_information(&new_moving, &fixed, true);
-        if new_fitness < 0.000001 {
-            continue;
-        } else if new_fitness > curr_fitness {
-            t = new_t;
-            curr_fitness = new_fitness;
-            println!("{:?}", curr_fitness);
+impl Default for AlignOptions {
+    fn default() -> Self {
+        Self {
+            levels: 3,
+            max_iterations: 300,
+            tolerance: 1e-5,
+            seed: 0,
+            metric: Metric::default(),
         }
     }
+}
 
-    println!("tform == {:?}", t);
-
-    let proj = Projection::from_matrix(t).unwrap();
-    let new_moving = warp(&r_moving, &proj, Interpolation::Nearest, Luma([0.0]));
-
-    let (w, h) = new_moving.dimensions();
-
-    let out_img = Array2::from_shape_vec((h as usize, w as usize), new_moving.into_vec()).unwrap();
-    let out_his = Array2::from_shape_vec((h as usize, w as usize), fixed.into_vec()).unwrap();
-
-    save_as_luma8(&out_img.map(|a| (255.0 * a) as u32), "./out.tiff");
-    save_as_luma8(&out_his.map(|a| (255.0 * a) as u32), "./out1.tiff");
-
-    return t;
+#[derive(Clone, Debug)]
+pub struct LevelReport {
+    /// (width, height) of the images at this level.
+    pub size: (usize, usize),
+    pub iterations: usize,
+    pub metric: f32,
+    pub converged: bool,
+    pub elapsed: Duration,
 }
 
-fn mutate(chr: &[f32; 9]) -> [f32; 9] {
-    std::array::from_fn(|j| {
-        if j < 6 {
-            chr[j] + (rand::random::<f32>() * 2.0 - 1.0) * 0.001
-        } else {
-            chr[j]
-        }
-    })
+/// Outcome of `iter_align`, levels listed coarsest first.
+#[derive(Clone, Debug)]
+pub struct AlignReport {
+    pub options: AlignOptions,
+    pub transform: [f32; 9],
+    pub metric: f32,
+    pub iterations: usize,
+    pub levels: Vec<LevelReport>,
+    /// The B-spline stage, when one followed the affine alignment.
+    pub deformation: Option<DeformReport>,
 }
 
-fn mutual_information(
-    fixed: &ImageBuffer<Luma<f32>, Vec<f32>>,
+/// Affinely aligns `moving`, resized to the size of `fixed`, onto `fixed` by
+/// maximising `options.metric` between them.
+///
+/// Runs Nelder–Mead from the coarsest level of a Gaussian pyramid down to full
+/// size, each level starting from the previous result with half its step size.
+/// `init` is the starting transform between the full-size images.
+pub fn iter_align(
     moving: &ImageBuffer<Luma<f32>, Vec<f32>>,
-    show: bool,
-) -> f32 {
-    let n_bins = 100.0;
-
-    let mut joint = Array2::from_elem((n_bins as usize, n_bins as usize), 0f32);
+    fixed: &ImageBuffer<Luma<f32>, Vec<f32>>,
+    init: [f32; 9],
+    options: &AlignOptions,
+) -> AlignReport {
+    let (moving, fixed) = (imadjust_buff(moving), imadjust_buff(fixed));
 
-    moving
-        .clone()
-        .into_vec()
-        .iter()
-        .map(|a| a.to_owned())
-        .zip(fixed.clone().into_vec())
-        .for_each(|(a, b)| {
-            let i = (n_bins * a).clamp(0.0, n_bins - 1.0) as usize;
-            let j = (n_bins * b).clamp(0.0, n_bins - 1.0) as usize;
-            joint[(i, j)] += 1.0;
+    let (w, h) = fixed.dimensions();
+    let r_moving = resize(&moving, w, h, FilterType::Gaussian);
+    let pyramid = gaussian_pyramid(r_moving, fixed, options.levels);
+
+    let mut t = init;
+    let mut levels = vec![];
+
+    for (depth, (moving, fixed)) in pyramid.iter().rev().enumerate() {
+        let start = Instant::now();
+
+        let (lw, lh) = fixed.dimensions();
+        let (sx, sy) = (lw as f32 / w as f32, lh as f32 / h as f32);
+        let t_level = mat3_mul(&scale(sx, sy), &mat3_mul(&t, &scale(1.0 / sx, 1.0 / sy)));
+
+        // offsets to the affine entries, translations in units of the level size
+        let units = [1.0, 1.0, lw as f64, 1.0, 1.0, lh as f64];
+        let to_matrix = |p: &[f64]| -> [f32; 9] {
+            std::array::from_fn(|i| {
+                if i < 6 {
+                    t_level[i] + (p[i] * units[i]) as f32
+                } else {
+                    t_level[i]
+                }
+            })
+        };
+        let cost = |p: &[f64]| match Projection::from_matrix(to_matrix(p)) {
+            Some(proj) => {
+                let warped = warp(moving, &proj, Interpolation::Bilinear, Luma([0.0]));
+                -options.metric.similarity(fixed, &warped) as f64
+            }
+            None => f64::INFINITY,
+        };
+
+        let step = 0.1 * 0.5f64.powi(depth as i32);
+        let stopping = Stopping {
+            max_iterations: options.max_iterations,
+            cost_tolerance: options.tolerance,
+            step_tolerance: step * 0.01,
+        };
+        let min = nelder_mead(cost, &[0.0; 6], &[step; 6], stopping, options.seed);
+
+        t = mat3_mul(
+            &scale(1.0 / sx, 1.0 / sy),
+            &mat3_mul(&to_matrix(&min.x), &scale(sx, sy)),
+        );
+        levels.push(LevelReport {
+            size: (lw as usize, lh as usize),
+            iterations: min.iterations,
+            metric: -min.cost as f32,
+            converged: min.converged,
+            elapsed: start.elapsed(),
         });
+    }
 
-    let pxy = &joint / joint.sum();
-    let px = pxy.sum_axis(Axis(0));
-    let py = pxy.sum_axis(Axis(1));
-    let px_py = Array2::from_shape_fn(joint.dim(), |(i, j)| px[j] * py[i]);
+    AlignReport {
+        options: *options,
+        transform: t,
+        metric: levels.last().map(|l| l.metric).unwrap_or(f32::NAN),
+        iterations: levels.iter().map(|l| l.iterations).sum(),
+        levels,
+        deformation: None,
+    }
+}
 
-    if show {
-        save_as_luma16(&joint.map(|a| (a * 1.0) as u16), "./out2.tiff");
+/// `(moving, fixed)` at one pyramid level.
+type PyramidLevel = (
+    ImageBuffer<Luma<f32>, Vec<f32>>,
+    ImageBuffer<Luma<f32>, Vec<f32>>,
+);
+
+/// `levels` copies of both images, finest first, each blurred and halved from
+/// the one before. Stops early once an image would drop below 16 pixels a side.
+fn gaussian_pyramid(
+    moving: ImageBuffer<Luma<f32>, Vec<f32>>,
+    fixed: ImageBuffer<Luma<f32>, Vec<f32>>,
+    levels: usize,
+) -> Vec<PyramidLevel> {
+    let mut out = vec![(moving, fixed)];
+
+    while out.len() < levels {
+        let (moving, fixed) = &out[out.len() - 1];
+        let (w, h) = (fixed.width() / 2, fixed.height() / 2);
+        if w.min(h) < 16 {
+            break;
+        }
+        let down = |im: &ImageBuffer<Luma<f32>, Vec<f32>>| {
+            resize(&gaussian_blur_f32(im, 1.0), w, h, FilterType::Triangle)
+        };
+        out.push((down(moving), down(fixed)));
     }
 
-    pxy.indexed_iter().fold(0.0, |acc, (idx, &a)| {
-        if a == 0.0 || px_py[idx] == 0.0 {
-            acc
-        } else {
-            acc + a * (a / px_py[idx]).ln()
+    out
+}
+
+/// Resamples a label slice onto a `(width, height)` canvas like `warp_slice`,
+/// taking the nearest label so labels of any type are kept as they are.
+/// Pixels mapped outside `labels` are `T::default()`.
+pub fn warp_labels<T, F>(labels: &Matrix<T>, mapping: F, (w, h): (usize, usize)) -> Matrix<T>
+where
+    T: Copy + Default,
+    F: Fn(f32, f32) -> (f32, f32),
+{
+    Array2::from_shape_fn((h, w), |(y, x)| {
+        let (sx, sy) = mapping(x as f32, y as f32);
+        if sx < -0.5 || sy < -0.5 {
+            return T::default();
         }
+        let at = ((sy + 0.5) as usize, (sx + 0.5) as usize);
+        labels.get(at).copied().unwrap_or_default()
     })
 }
+
+/// Resamples an atlas slice onto a `(width, height)` canvas. `mapping` takes a
+/// canvas pixel to the slice pixel it samples.
+pub fn warp_slice<F>(
+    slice: &Matrix<u16>,
+    mapping: F,
+    (w, h): (usize, usize),
+    interpolation: Interpolation,
+) -> Matrix<u16>
+where
+    F: Fn(f32, f32) -> (f32, f32) + Send + Sync,
+{
+    let mut out = ImageBuffer::new(w as u32, h as u32);
+    warp_into_with(
+        &array2buff(slice.to_owned()),
+        mapping,
+        interpolation,
+        Luma([0]),
+        &mut out,
+    );
+    Array2::from_shape_vec((h, w), out.into_vec()).expect("buffer matches its dimensions")
+}
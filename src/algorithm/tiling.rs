@@ -0,0 +1,59 @@
+use crate::utility::types::{Pnt, ROI};
+
+/// One step of a tiled walk over a region of interest.
+///
+/// `core` is the part of the ROI this tile is responsible for; `read` is `core`
+/// grown by the overlap on every side (clipped to the ROI) and is what gets
+/// segmented, so cells straddling the core border are seen whole.
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    pub core: ROI,
+    pub read: ROI,
+}
+
+impl Tile {
+    /// Whether a point in `read`-local coordinates falls inside the core.
+    pub fn owns(&self, (r, c): Pnt) -> bool {
+        let (core_r, core_c, core_h, core_w) = self.core_local();
+        r >= core_r && r < core_r + core_h && c >= core_c && c < core_c + core_w
+    }
+
+    /// The core expressed in `read`-local coordinates.
+    pub fn core_local(&self) -> ROI {
+        (
+            self.core.0 - self.read.0,
+            self.core.1 - self.read.1,
+            self.core.2,
+            self.core.3,
+        )
+    }
+
+    /// Converts a `read`-local point to coordinates relative to the ROI origin.
+    pub fn to_roi(&self, (r, c): Pnt, roi: ROI) -> Pnt {
+        (r + self.read.0 - roi.0, c + self.read.1 - roi.1)
+    }
+}
+
+/// Splits `roi` into row-major tiles with cores of at most `size` pixels a side.
+pub fn tile_grid(roi: ROI, size: usize, overlap: usize) -> Vec<Tile> {
+    let (r0, c0, h, w) = roi;
+
+    let starts =
+        |len: usize| (0..len.div_ceil(size)).map(move |i| (i * size, size.min(len - i * size)));
+
+    starts(h)
+        .flat_map(|(dr, th)| {
+            starts(w).map(move |(dc, tw)| {
+                let read_r = dr.saturating_sub(overlap);
+                let read_c = dc.saturating_sub(overlap);
+                let read_h = (dr + th + overlap).min(h) - read_r;
+                let read_w = (dc + tw + overlap).min(w) - read_c;
+
+                Tile {
+                    core: (r0 + dr, c0 + dc, th, tw),
+                    read: (r0 + read_r, c0 + read_c, read_h, read_w),
+                }
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,221 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use microcount_rs::algorithm::microcount;
+use microcount_rs::model::{constants, ConvertStatus, ImageMetadata, Workspace};
+use microcount_rs::utility::io::{save_cells_csv, save_results_csv};
+use microcount_rs::utility::tile_cache;
+use microcount_rs::utility::types::{CellTable, Settings};
+
+const EXIT_OK: u8 = 0;
+const EXIT_PARTIAL: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_WORKSPACE: u8 = 3;
+
+const RESULTS_FILE: &str = "results.csv";
+
+const USAGE: &str = "\
+Usage: microcount-cli <WORKSPACE_DIR> [OPTIONS]
+
+Runs microglia analysis over the images of a workspace and writes
+<WORKSPACE_DIR>/ws_processed/results.csv, plus a per-cell table
+<WORKSPACE_DIR>/ws_processed/<ID>/cells.csv for each image. Images
+with regions are analysed only inside them and get an extra results
+row per region. The intermediates chosen in the settings are kept under
+<WORKSPACE_DIR>/ws_processed/<ID>/ and <WORKSPACE_DIR>/ws_masks/<ID>/.
+
+Options:
+  -i, --image <ID>     Only analyse this image (id or source path), repeatable
+  -p, --preset <NAME>  Use this workspace preset instead of the active one;
+                       per-image settings overrides still take precedence
+      --converted-only Skip images that have not been converted
+  -h, --help           Print this message
+
+Exit codes:
+  0  every selected image was analysed
+  1  at least one image failed
+  2  invalid arguments
+  3  the workspace could not be read, the preset does not exist
+     or no images matched";
+
+struct Args {
+    ws_dir: PathBuf,
+    images: Vec<String>,
+    preset: Option<String>,
+    converted_only: bool,
+}
+
+fn parse_args(mut argv: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut ws_dir = None;
+    let mut images = vec![];
+    let mut preset = None;
+    let mut converted_only = false;
+
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-i" | "--image" => match argv.next() {
+                Some(id) => images.push(id),
+                None => return Err(format!("{} expects an image id", arg)),
+            },
+            "-p" | "--preset" => match argv.next() {
+                Some(name) => preset = Some(name),
+                None => return Err(format!("{} expects a preset name", arg)),
+            },
+            "--converted-only" => converted_only = true,
+            a if a.starts_with('-') => return Err(format!("unknown option {}", a)),
+            _ if ws_dir.is_none() => ws_dir = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let ws_dir = ws_dir.ok_or("missing workspace directory")?;
+
+    Ok(Some(Args {
+        ws_dir,
+        images,
+        preset,
+        converted_only,
+    }))
+}
+
+fn select_images(ws: &Workspace, args: &Args) -> Vec<ImageMetadata> {
+    let mut images = ws
+        .images
+        .values()
+        .filter(|img| {
+            args.images.is_empty()
+                || args
+                    .images
+                    .iter()
+                    .any(|id| id == img.id() || id == img.src_fn())
+        })
+        .filter(|img| {
+            !args.converted_only || matches!(img.conversion_status, ConvertStatus::Converted)
+        })
+        .cloned()
+        .collect::<Vec<ImageMetadata>>();
+
+    images.sort_by(|a, b| a.id().cmp(b.id()));
+    images
+}
+
+fn analyse(img: &ImageMetadata, settings: &Settings) -> Result<CellTable, String> {
+    let run = || microcount::from_image(img, settings, &|_, _| true);
+    match panic::catch_unwind(AssertUnwindSafe(run)) {
+        Ok(Ok(res)) => Ok(res),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(cause) => Err(cause
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| cause.downcast_ref::<String>().cloned())
+            .unwrap_or("analysis panicked".into())),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::from(EXIT_OK);
+        }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let mut ws = match Workspace::from_dir(&args.ws_dir) {
+        Ok(ws) => ws,
+        Err(err) => {
+            eprintln!("error: could not open {}: {}", args.ws_dir.display(), err);
+            return ExitCode::from(EXIT_WORKSPACE);
+        }
+    };
+
+    if let Some(name) = &args.preset {
+        if let Err(err) = ws.settings.set_active(name) {
+            let names = ws.settings.names().collect::<Vec<&str>>().join(", ");
+            eprintln!("error: {} (available: {})", err, names);
+            return ExitCode::from(EXIT_WORKSPACE);
+        }
+    }
+
+    let proc_dir = Path::new(&args.ws_dir).join(constants::DIR_PROC);
+    let images = select_images(&ws, &args);
+    if images.is_empty() {
+        eprintln!("error: no images in {} matched", args.ws_dir.display());
+        return ExitCode::from(EXIT_WORKSPACE);
+    }
+
+    // Silence the default hook so a failing image prints one line, not a backtrace.
+    panic::set_hook(Box::new(|_| {}));
+
+    let n_images = images.len();
+    let start = Instant::now();
+    let mut results = vec![];
+    let mut failures = vec![];
+
+    for (i, img) in images.iter().enumerate() {
+        eprint!("[{}/{}] {} ... ", i + 1, n_images, img.id());
+        let t = Instant::now();
+        let settings = ws.settings_for(img);
+
+        let cells_fn = img.cells_fn();
+        let res = analyse(img, &settings).and_then(|table| {
+            std::fs::create_dir_all(img.proc_dir())
+                .map_err(csv::Error::from)
+                .and_then(|_| save_cells_csv(&table.cells, &cells_fn))
+                .map(|_| table)
+                .map_err(|err| format!("could not write {}: {}", cells_fn, err))
+        });
+
+        match res {
+            Ok(table) => {
+                eprintln!(
+                    "ok ({} cells, {:.1}s)",
+                    table.cells.len(),
+                    t.elapsed().as_secs_f64()
+                );
+                let id = img.id().to_owned();
+                results.push((id.clone(), String::new(), table.results(&settings)));
+                results.extend(
+                    table
+                        .region_results(&settings)
+                        .into_iter()
+                        .map(|(region, r)| (id.clone(), region, r)),
+                );
+            }
+            Err(err) => {
+                eprintln!("FAILED ({})", err);
+                failures.push(img.id().to_owned());
+            }
+        }
+    }
+
+    let out_fn = proc_dir.join(RESULTS_FILE);
+
+    if let Err(err) = save_results_csv(&results, &out_fn.to_string_lossy()) {
+        eprintln!("error: could not write {}: {}", out_fn.display(), err);
+        return ExitCode::from(EXIT_WORKSPACE);
+    }
+
+    eprintln!(
+        "Analysed {}/{} images in {:.1}s, results written to {}",
+        n_images - failures.len(),
+        n_images,
+        start.elapsed().as_secs_f64(),
+        out_fn.display()
+    );
+    eprintln!("Tile cache: {}", tile_cache::stats());
+
+    if failures.is_empty() {
+        ExitCode::from(EXIT_OK)
+    } else {
+        eprintln!("Failed: {}", failures.join(", "));
+        ExitCode::from(EXIT_PARTIAL)
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{mpsc, Arc};
+
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::algorithm::anatomy::{structure_results, StructureResults};
+use crate::algorithm::microcount::{self, AnalysisError};
+use crate::model::{AnalysisStatus, Atlas, Workspace};
+use crate::utility::io::{save_cells_csv, save_midline_csv, save_structures_csv};
+use crate::utility::types::Results;
+
+#[derive(Debug, Clone)]
+pub enum BatchEvent {
+    Progress {
+        id: String,
+        stage: String,
+        percent: f32,
+    },
+    Finished {
+        id: String,
+        status: AnalysisStatus,
+    },
+    Done,
+}
+
+/// The GUI's end of a running batch: drain `events` each frame and cancel
+/// images or the whole batch through it.
+pub struct BatchHandle {
+    pub events: mpsc::Receiver<BatchEvent>,
+    batch: CancellationToken,
+    images: HashMap<String, CancellationToken>,
+}
+
+impl BatchHandle {
+    pub fn cancel(&self, id: &str) {
+        if let Some(token) = self.images.get(id) {
+            token.cancel();
+        }
+    }
+
+    pub fn cancel_all(&self) {
+        self.batch.cancel();
+    }
+
+    pub fn is_cancelled(&self, id: &str) -> bool {
+        self.images.get(id).is_some_and(|t| t.is_cancelled())
+    }
+}
+
+/// Builds a job that analyses the images `ids` of `ws`, at most `worker_limit`
+/// at a time, recording each image's status and results in the workspace.
+///
+/// Registered images also get results per structure of `atlas`.
+///
+/// `notify` is called after every event so the GUI can repaint. Dropping the
+/// returned future (e.g. when the thread pool cancels it) cancels the batch.
+pub fn analyse_batch<N>(
+    ws: Arc<Mutex<Workspace>>,
+    atlas: Arc<Atlas>,
+    ids: Vec<String>,
+    worker_limit: usize,
+    notify: N,
+) -> (BatchHandle, impl Future<Output = ()> + Send + 'static)
+where
+    N: Fn() + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let batch = CancellationToken::new();
+    let images = ids
+        .iter()
+        .map(|id| (id.to_owned(), batch.child_token()))
+        .collect::<HashMap<String, CancellationToken>>();
+
+    let handle = BatchHandle {
+        events: rx,
+        batch: batch.clone(),
+        images: images.clone(),
+    };
+
+    let notify = Arc::new(notify);
+
+    let job = async move {
+        let _guard = batch.drop_guard();
+
+        set_status(&ws, &ids, AnalysisStatus::Queued, None).await;
+
+        let semaphore = Arc::new(Semaphore::new(worker_limit.max(1)));
+        let mut set = JoinSet::new();
+
+        for id in ids {
+            let token = images[&id].clone();
+            let semaphore = Arc::clone(&semaphore);
+            let ws = Arc::clone(&ws);
+            let atlas = Arc::clone(&atlas);
+            let tx = tx.clone();
+            let notify = Arc::clone(&notify);
+
+            set.spawn(async move {
+                let permit = tokio::select! {
+                    _ = token.cancelled() => None,
+                    p = semaphore.acquire_owned() => p.ok(),
+                };
+
+                let (status, results) = match permit {
+                    Some(_permit) => {
+                        analyse_one(&ws, &atlas, &id, token, tx.clone(), notify.clone()).await
+                    }
+                    None => (AnalysisStatus::Cancelled, None),
+                };
+
+                set_status(&ws, std::slice::from_ref(&id), status.clone(), results).await;
+                let _ = tx.send(BatchEvent::Finished { id, status });
+                notify();
+            });
+        }
+
+        while set.join_next().await.is_some() {}
+
+        let _ = tx.send(BatchEvent::Done);
+        notify();
+    };
+
+    (handle, job)
+}
+
+/// Whole-image results, results per region and per atlas structure.
+type AllResults = (Results, BTreeMap<String, Results>, Vec<StructureResults>);
+
+async fn analyse_one(
+    ws: &Arc<Mutex<Workspace>>,
+    atlas: &Arc<Atlas>,
+    id: &str,
+    token: CancellationToken,
+    tx: mpsc::Sender<BatchEvent>,
+    notify: Arc<impl Fn() + Send + Sync + 'static>,
+) -> (AnalysisStatus, Option<AllResults>) {
+    let (img, settings) = {
+        let ws = ws.lock().await;
+        match ws.images.get(id) {
+            Some(img) => (img.clone(), ws.settings_for(img)),
+            None => {
+                return (
+                    AnalysisStatus::Failed("image not in workspace".into()),
+                    None,
+                )
+            }
+        }
+    };
+
+    set_status(ws, &[id.to_owned()], AnalysisStatus::Analysing, None).await;
+
+    let id = id.to_owned();
+    let atlas = Arc::clone(atlas);
+    let res = tokio::task::spawn_blocking(move || {
+        let progress = |stage: &str, percent: f32| {
+            let _ = tx.send(BatchEvent::Progress {
+                id: id.clone(),
+                stage: stage.to_owned(),
+                percent,
+            });
+            notify();
+            !token.is_cancelled()
+        };
+
+        if let Some(reg) = &img.registration {
+            reg.validate(&atlas)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        }
+        let mut table = microcount::from_image(&img, &settings, &progress)?;
+        let structures = img
+            .registration
+            .as_ref()
+            .and_then(|reg| {
+                let annotation = atlas.get_oblique_annotation_img(
+                    reg.orientation,
+                    reg.slice_index as isize,
+                    reg.pitch,
+                    reg.yaw,
+                );
+                let hemispheres = atlas.get_hemisphere_img(
+                    reg.orientation,
+                    reg.slice_index as isize,
+                    reg.pitch,
+                    reg.yaw,
+                );
+                structure_results(
+                    &mut table.cells,
+                    &img,
+                    &annotation,
+                    &hemispheres,
+                    atlas.ontology(),
+                    &settings,
+                )
+            })
+            .unwrap_or_default();
+
+        std::fs::create_dir_all(img.proc_dir())?;
+        save_cells_csv(&table.cells, &img.cells_fn()).map_err(std::io::Error::from)?;
+        if !structures.is_empty() {
+            save_structures_csv(&structures, &img.structures_fn()).map_err(std::io::Error::from)?;
+        }
+        // recomputed so registrations saved before the midline was stored get one
+        if let Some(mut reg) = img.registration.clone() {
+            reg.set_midline(&atlas);
+            if !reg.midline.is_empty() {
+                save_midline_csv(&reg.midline, &img.midline_fn()).map_err(std::io::Error::from)?;
+            }
+        }
+
+        Ok::<AllResults, AnalysisError>((
+            table.results(&settings),
+            table.region_results(&settings),
+            structures,
+        ))
+    })
+    .await;
+
+    match res {
+        Ok(Ok(results)) => (AnalysisStatus::Analysed, Some(results)),
+        Ok(Err(AnalysisError::Cancelled)) => (AnalysisStatus::Cancelled, None),
+        Ok(Err(err)) => (AnalysisStatus::Failed(err.to_string()), None),
+        Err(_) => (AnalysisStatus::Failed("analysis panicked".into()), None),
+    }
+}
+
+async fn set_status(
+    ws: &Arc<Mutex<Workspace>>,
+    ids: &[String],
+    status: AnalysisStatus,
+    results: Option<AllResults>,
+) {
+    let mut ws = ws.lock().await;
+    ids.iter().for_each(|id| {
+        if let Some(img) = ws.images.get_mut(id) {
+            img.analysis_status = status.clone();
+            if let Some((image, regions, structures)) = &results {
+                img.results = Some(image.clone());
+                img.region_results = regions.clone();
+                img.structure_results = structures.clone();
+            }
+        }
+    });
+    let _ = ws.save();
+}
@@ -1,3 +1,5 @@
+pub mod batch;
 pub mod threadpool;
 
+pub use batch::{BatchEvent, BatchHandle};
 pub use threadpool::ThreadPool;
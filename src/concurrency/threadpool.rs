@@ -43,7 +43,7 @@ impl<T: Send + 'static + Eq + Hash + Copy + Debug> ThreadPool<T> {
                                     println!("Job Cancelled");
                                 }
                                 _ = async {
-                                    let _ = permit.acquire().await.unwrap();
+                                    let _permit = permit.acquire().await.unwrap();
                                     job.await;
                                     println!("Job Completed");
                                 } => {}
@@ -64,7 +64,7 @@ impl<T: Send + 'static + Eq + Hash + Copy + Debug> ThreadPool<T> {
         F: Future<Output = ()> + Send + 'static,
     {
         println!("Attempting send!");
-        let _ = self.sender.send((Box::pin(job), None));
+        let _ = self.sender.try_send((Box::pin(job), None));
     }
 
     pub fn dispatch_exclusive<F>(&self, job: F, label: T)
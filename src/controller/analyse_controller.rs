@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+
+use eframe::egui::{Context, Rect, TextureHandle};
+use tokio::sync::Mutex;
+
+use crate::{
+    algorithm::microcount::ArtifactDirs,
+    concurrency::{BatchEvent, BatchHandle},
+    model::{ImageMetadata, Model},
+    utility::{
+        imops::egui_overlay_from_mat,
+        io::{egui_image_from_path, read_mask},
+        types::{Intermediate, Matrix},
+    },
+    ThreadLabel,
+};
+
+/// Longest edge, in pixels, of the image shown in the results viewer.
+const VIEW_SIZE: usize = 2000;
+
+#[derive(Default)]
+pub struct AnalyseTextures {
+    pub image: Option<TextureHandle>,
+    pub segmented: Option<TextureHandle>,
+    pub skeleton: Option<TextureHandle>,
+    pub co_marker: Option<TextureHandle>,
+    /// Why the image or an overlay could not be loaded.
+    pub error: Option<String>,
+}
+
+pub struct AnalyseController {
+    pub selection: HashSet<String>,
+    pub selected_img: Option<String>,
+    pub image_rect: Rect,
+    pub textures: Arc<Mutex<AnalyseTextures>>,
+    pub show_segmented: bool,
+    pub show_skeleton: bool,
+    pub show_co_marker: bool,
+    pub worker_limit: usize,
+    pub batch: Option<BatchHandle>,
+    pub progress: HashMap<String, (String, f32)>,
+    pub message: Option<String>,
+}
+
+impl Default for AnalyseController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AnalyseController {
+    pub fn new() -> AnalyseController {
+        Self {
+            selection: Default::default(),
+            selected_img: None,
+            image_rect: Rect::ZERO,
+            textures: Arc::new(Mutex::new(AnalyseTextures::default())),
+            show_segmented: true,
+            show_skeleton: true,
+            show_co_marker: true,
+            worker_limit: 2,
+            batch: None,
+            progress: HashMap::new(),
+            message: None,
+        }
+    }
+
+    pub fn toggle_selection(&mut self, im_md: &ImageMetadata) {
+        if self.selection.contains(im_md.src_fn()) {
+            self.selection.remove(im_md.src_fn());
+        } else {
+            self.selection.insert(im_md.src_fn().to_string());
+        }
+    }
+
+    pub fn unselect_all(&mut self) {
+        self.selection.clear();
+    }
+
+    pub fn select_all(&mut self, model: &Model) {
+        self.selection = model
+            .get_all_images()
+            .unwrap_or_default()
+            .iter()
+            .map(|img| img.src_fn().to_owned())
+            .collect();
+    }
+
+    pub fn on_preset_selected(&mut self, model: &mut Model, name: &str) {
+        self.message = model
+            .set_active_preset(name)
+            .err()
+            .map(|err| err.to_string());
+    }
+
+    pub fn analyse_selected(&mut self, model: &mut Model) {
+        let ids = self.selection.iter().cloned().collect::<Vec<String>>();
+        if ids.is_empty() {
+            return;
+        }
+
+        self.progress.clear();
+        match model.analyse_images(ids, self.worker_limit) {
+            Ok(handle) => {
+                self.batch = Some(handle);
+                self.message = None;
+            }
+            Err(err) => self.message = Some(err.to_string()),
+        }
+    }
+
+    pub fn cancel_selected(&mut self) {
+        if let Some(batch) = &self.batch {
+            self.selection.iter().for_each(|id| batch.cancel(id));
+        }
+    }
+
+    pub fn cancel_all(&mut self) {
+        if let Some(batch) = &self.batch {
+            batch.cancel_all();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    /// Drains progress events from the running batch, reloading the viewer
+    /// when the selected image finishes. A batch whose events stop without
+    /// `Done` is ended with an error message.
+    pub fn poll_batch(&mut self, model: &mut Model) {
+        let Some(batch) = &self.batch else {
+            return;
+        };
+
+        let mut finished = vec![];
+        let mut done = false;
+
+        loop {
+            let event = match batch.events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // the batch went away without reporting `Done`, e.g. it panicked
+                    if !done {
+                        self.message =
+                            Some("Analysis stopped unexpectedly before it finished".to_string());
+                        self.progress.clear();
+                    }
+                    done = true;
+                    break;
+                }
+            };
+            match event {
+                BatchEvent::Progress { id, stage, percent } => {
+                    self.progress.insert(id, (stage, percent));
+                }
+                BatchEvent::Finished { id, .. } => {
+                    self.progress.remove(&id);
+                    finished.push(id);
+                }
+                BatchEvent::Done => done = true,
+            }
+        }
+
+        if done {
+            self.batch = None;
+        }
+
+        let reload = self
+            .selected_img
+            .as_ref()
+            .is_some_and(|id| finished.contains(id));
+
+        if reload {
+            if let Some(img) = self
+                .selected_img
+                .as_ref()
+                .and_then(|id| model.get_image(id))
+            {
+                self.on_image_selected(&img, model);
+            }
+        }
+    }
+
+    pub fn on_image_selected(&mut self, im_md: &ImageMetadata, model: &mut Model) {
+        self.selected_img = Some(im_md.src_fn().to_string());
+
+        let textures = Arc::clone(&self.textures);
+        let ctx = Arc::clone(&model.frame);
+        let img = im_md.clone();
+
+        model.dispatch_exclusive(ThreadLabel::AnalyseLoadOverlays, true, async move {
+            let ctx = ctx.lock().await.clone();
+            let loaded = tokio::task::spawn_blocking(move || load_textures(&img, &ctx)).await;
+
+            if let Ok(loaded) = loaded {
+                *textures.lock().await = loaded;
+            }
+        });
+    }
+}
+
+fn load_textures(img: &ImageMetadata, ctx: &Context) -> AnalyseTextures {
+    let (w, h) = img.size;
+    let df = std::cmp::max(1, std::cmp::max(w, h).div_ceil(VIEW_SIZE));
+    let mut errors = vec![];
+
+    let image = egui_image_from_path(&img.analysis_fn(), img.full_roi(), df)
+        .map(|im| ctx.load_texture("analyse_image", im, Default::default()))
+        .map_err(|err| errors.push(format!("image: {}", err)))
+        .ok();
+
+    let mut overlay = |a: Intermediate, colour: Option<[u8; 3]>| match read_overlay(img, a, df) {
+        Ok(mask) => mask.map(|mask| {
+            let im = egui_overlay_from_mat(&mask, 1, colour);
+            ctx.load_texture(format!("analyse_{}", a.file_name()), im, Default::default())
+        }),
+        Err(err) => {
+            errors.push(format!("{}: {}", a.file_name(), err));
+            None
+        }
+    };
+
+    let segmented = overlay(Intermediate::Segmented, None);
+    let skeleton = overlay(Intermediate::Skeleton, Some([0, 255, 0]));
+    let co_marker = overlay(Intermediate::CoMarkerMask, Some([255, 0, 255]));
+
+    ctx.request_repaint();
+    AnalyseTextures {
+        image,
+        segmented,
+        skeleton,
+        co_marker,
+        error: (!errors.is_empty()).then(|| errors.join("\n")),
+    }
+}
+
+/// Intermediate `a` of the whole image `df` times smaller, pasting each
+/// region's mask at its bounding box. `None` when the last analysis did not
+/// keep it.
+fn read_overlay(
+    img: &ImageMetadata,
+    a: Intermediate,
+    df: usize,
+) -> Result<Option<Matrix<u32>>, tiff::TiffError> {
+    let out = ArtifactDirs::for_image(img);
+    let parts = if img.regions.is_empty() {
+        vec![(img.full_roi(), out)]
+    } else {
+        img.regions
+            .iter()
+            .map(|r| (r.bounds(img.size), out.for_region(r)))
+            .collect()
+    };
+
+    let (w, h) = img.size;
+    let mut view = Matrix::zeros((h.div_ceil(df), w.div_ceil(df)));
+    let mut found = false;
+    for ((r, c, _, _), dirs) in parts {
+        let path = dirs.path(a);
+        if !path.exists() {
+            continue;
+        }
+        found = true;
+        let mask = read_mask(&path.to_string_lossy())?;
+        for ((i, j), &v) in mask.indexed_iter() {
+            let (y, x) = (r + i, c + j);
+            if v != 0 && y % df == 0 && x % df == 0 {
+                if let Some(px) = view.get_mut((y / df, x / df)) {
+                    *px = v;
+                }
+            }
+        }
+    }
+    Ok(found.then_some(view))
+}
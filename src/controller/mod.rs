@@ -1,7 +1,11 @@
+pub mod analyse_controller;
 pub mod home_controller;
 pub mod register_controller;
 pub mod select_images_controller;
+pub mod select_regions_controller;
 
+pub use analyse_controller::AnalyseController;
 pub use home_controller::HomeController;
 pub use register_controller::RegisterController;
 pub use select_images_controller::SelectImagesController;
+pub use select_regions_controller::SelectRegionsController;
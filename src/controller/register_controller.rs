@@ -1,21 +1,33 @@
-use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use csv::Error;
 use eframe::{
     egui::{Context, Rect, TextureHandle, Vec2},
     emath::TSTransform,
 };
+use imageproc::geometric_transformations::Interpolation;
+use tokio::sync::Mutex;
 
 use crate::{
-    algorithm::proc::iter_align,
-    model::{self, atlas::Orientation, ImageMetadata, Model, Workspace},
+    algorithm::{
+        annotation::write_structure_mask,
+        deformable::{refine, BSplineField, DeformOptions},
+        landmarks::{fit_affine, Landmarks},
+        proc::{iter_align, AlignOptions, AlignReport},
+    },
+    model::{atlas::Orientation, registration, ImageMetadata, Model, Registration},
     utility::{
-        imops::{array2buff, egui_image_from_mat},
+        imops::{array2buff, egui_annotation_from_mat, egui_image_from_mat, egui_tint_from_mat},
         io::{egui_image_from_path, read_tiff_region},
-        types::ROI,
+        types::Matrix,
     },
+    ThreadLabel,
 };
 
+/// Outcome of a background `register_button_pushed`.
+type Alignment = Result<(Registration, AlignReport), String>;
+
 pub struct RegisterController {
     pub selection: std::collections::HashSet<String>,
     pub scene_rect: Rect,
@@ -25,10 +37,58 @@ pub struct RegisterController {
     pub selected_img: Option<String>,
     pub slider_pos: usize,
     pub atlas_orientation: Orientation,
+    /// Cutting angles of the atlas slice, in degrees.
+    pub pitch: f32,
+    pub yaw: f32,
     pub atlas_hex: [(f32, f32); 6],
     pub hist_hex: [(f32, f32); 6],
     pub transform: TSTransform,
     pub transform2: TSTransform,
+    /// Working atlas-to-section matrix (see `Registration::transform`), saved explicitly.
+    pub atlas_transform: Option<[f32; 9]>,
+    pub aligned: Arc<Mutex<Option<Alignment>>>,
+    pub align_options: AlignOptions,
+    pub last_report: Option<AlignReport>,
+    pub aligning: bool,
+    pub overlay: Option<TextureHandle>,
+    pub show_overlay: bool,
+    /// Annotation labels of the atlas slice and the same warped onto the
+    /// section preview, with their coloured overlays.
+    pub annotation: Option<Matrix<u32>>,
+    pub annotation_image: Option<TextureHandle>,
+    pub section_annotation: Option<Matrix<u32>>,
+    pub section_annotation_image: Option<TextureHandle>,
+    /// Ends of the atlas midline in the atlas slice and its polyline in the
+    /// section, as fractions of each image's width and height.
+    pub midline: Option<[(f32, f32); 2]>,
+    pub section_midline: Option<Vec<(f32, f32)>>,
+    pub show_annotation: bool,
+    pub image_size: (usize, usize),
+    pub preview_size: (usize, usize),
+    /// Control points behind `atlas_transform`, cleared by manual edits.
+    pub landmarks: Option<Landmarks>,
+    /// Refit the transform from the hexagons whenever a point moves.
+    pub live_landmarks: bool,
+    pub thin_plate: bool,
+    pub init_from_landmarks: bool,
+    /// Follow the affine alignment with a B-spline deformation.
+    pub deformable: bool,
+    pub deform_options: DeformOptions,
+    /// Deformation on top of `atlas_transform`, dropped whenever that changes by hand.
+    pub deformation: Option<BSplineField>,
+    /// Where the atlas slice and section preview were last drawn in their scenes.
+    pub atlas_image_rect: Rect,
+    pub hist_image_rect: Rect,
+    /// Structure acronym to write a mask for, with its descendants.
+    pub structure_acronym: String,
+    pub mask_downsample: usize,
+    /// Also write the mask split at the atlas midline.
+    pub mask_hemispheres: bool,
+    pub masking: bool,
+    pub mask_written: Arc<Mutex<Option<Result<PathBuf, String>>>>,
+    pub mask_status: Option<String>,
+    pub dirty: bool,
+    pub message: Option<String>,
 }
 
 impl RegisterController {
@@ -41,7 +101,9 @@ impl RegisterController {
             image_data2: None,
             selected_img: None,
             slider_pos: 25,
-            atlas_orientation: Orientation::Axial,
+            atlas_orientation: Orientation::Coronal,
+            pitch: 0.0,
+            yaw: 0.0,
             atlas_hex: [
                 (30.0, 10.0),
                 (70.0, 10.0),
@@ -66,6 +128,39 @@ impl RegisterController {
                 scaling: 1.0,
                 translation: Vec2::ZERO,
             },
+            atlas_transform: None,
+            aligned: Arc::new(Mutex::new(None)),
+            align_options: AlignOptions::default(),
+            last_report: None,
+            aligning: false,
+            overlay: None,
+            show_overlay: true,
+            annotation: None,
+            annotation_image: None,
+            section_annotation: None,
+            section_annotation_image: None,
+            midline: None,
+            section_midline: None,
+            show_annotation: false,
+            image_size: (0, 0),
+            preview_size: (0, 0),
+            landmarks: None,
+            live_landmarks: false,
+            thin_plate: false,
+            init_from_landmarks: true,
+            deformable: false,
+            deform_options: DeformOptions::default(),
+            deformation: None,
+            atlas_image_rect: Rect::NOTHING,
+            hist_image_rect: Rect::NOTHING,
+            structure_acronym: String::new(),
+            mask_downsample: 1,
+            mask_hemispheres: false,
+            masking: false,
+            mask_written: Arc::new(Mutex::new(None)),
+            mask_status: None,
+            dirty: false,
+            message: None,
         }
     }
 
@@ -85,13 +180,41 @@ impl RegisterController {
         }
     }
 
-    pub fn on_image_selected(&mut self, im_md: &ImageMetadata, ctx: &Context) {
+    pub fn on_image_selected(&mut self, im_md: &ImageMetadata, model: &mut Model, ctx: &Context) {
         self.selected_img = Some(im_md.src_fn().to_string());
+        self.image_size = im_md.size;
+        self.dirty = false;
+        self.message = None;
+
         let bbox = (0, 0, im_md.size.1 - 1, im_md.size.0 - 1);
         let _ = egui_image_from_path(im_md.src_fn(), bbox, 25).map(|im| {
+            self.preview_size = (im.size[0], im.size[1]);
             let h = ctx.load_texture("screenshot_demo", im, Default::default());
             self.image_data = Some(h);
         });
+
+        let registration = im_md
+            .registration
+            .as_ref()
+            .filter(|r| match r.validate(&model.atlas) {
+                Ok(()) => true,
+                Err(err) => {
+                    self.message = Some(err);
+                    false
+                }
+            });
+        self.atlas_transform = registration.map(|r| {
+            self.atlas_orientation = r.orientation;
+            self.slider_pos = r.slice_index;
+            self.pitch = r.pitch;
+            self.yaw = r.yaw;
+            r.transform
+        });
+        self.landmarks = registration.and_then(|r| r.landmarks.clone());
+        self.deformation = registration.and_then(|r| r.deformation.clone());
+        self.live_landmarks = false;
+
+        self.on_atlas_interact(model, ctx);
     }
 
     pub fn unselect_all(&mut self) {
@@ -100,40 +223,393 @@ impl RegisterController {
 
     pub fn toggle_atlas_orientation(&mut self) {
         self.atlas_orientation = match self.atlas_orientation {
-            Orientation::Axial => Orientation::Coronal,
-            Orientation::Coronal => Orientation::Sagittal,
-            Orientation::Sagittal => Orientation::Axial,
+            Orientation::Coronal => Orientation::Axial,
+            Orientation::Axial => Orientation::Sagittal,
+            Orientation::Sagittal => Orientation::Coronal,
         }
     }
 
     pub fn on_atlas_interact(&mut self, model: &mut Model, ctx: &Context) {
-        let mat = model
-            .atlas
-            .get_reference_img(self.atlas_orientation, self.slider_pos as isize);
+        let mat = self.atlas_slice(model);
         let image = egui_image_from_mat(mat);
         let h = ctx.load_texture("atlas", image, Default::default());
         self.image_data2 = Some(h);
+
+        let annotation = model.atlas.get_oblique_annotation_img(
+            self.atlas_orientation,
+            self.slider_pos as isize,
+            self.pitch,
+            self.yaw,
+        );
+        let ontology = model.atlas.ontology();
+        let image = egui_annotation_from_mat(&annotation, |l| ontology.colour(l as u64));
+        self.annotation_image = Some(ctx.load_texture("annotation", image, Default::default()));
+        let (h, w) = annotation.dim();
+        self.annotation = Some(annotation);
+
+        self.midline = model
+            .atlas
+            .get_midline(
+                self.atlas_orientation,
+                self.slider_pos as isize,
+                self.pitch,
+                self.yaw,
+            )
+            .map(|ends| ends.map(|(x, y)| ((x + 0.5) / w as f32, (y + 0.5) / h as f32)));
+
+        self.refresh_overlay(model, ctx);
+    }
+
+    /// The atlas reference slice at the current orientation, position and angles.
+    fn atlas_slice(&self, model: &Model) -> Matrix<u16> {
+        model.atlas.get_oblique_reference_img(
+            self.atlas_orientation,
+            self.slider_pos as isize,
+            self.pitch,
+            self.yaw,
+        )
+    }
+
+    /// Redraws the atlas slice warped onto the section preview.
+    pub fn refresh_overlay(&mut self, model: &Model, ctx: &Context) {
+        self.overlay = self.atlas_transform.and_then(|t| {
+            let slice = self.atlas_slice(model);
+            let warped = self.registration_with(t).resample(
+                &slice,
+                self.image_size,
+                self.preview_size,
+                Interpolation::Bilinear,
+            )?;
+            let image = egui_tint_from_mat(&warped, [255, 0, 255]);
+            Some(ctx.load_texture("atlas_overlay", image, Default::default()))
+        });
+
+        self.section_annotation =
+            self.atlas_transform
+                .zip(self.annotation.as_ref())
+                .and_then(|(t, annotation)| {
+                    self.registration_with(t).resample_labels(
+                        annotation,
+                        self.image_size,
+                        self.preview_size,
+                    )
+                });
+        self.section_midline = self.atlas_transform.zip(self.midline).map(|(t, ends)| {
+            let (w, h) = self.image_size;
+            let (sh, sw) = self.annotation.as_ref().map_or((1, 1), |a| a.dim());
+            let ends = ends.map(|(u, v)| (u * sw as f32 - 0.5, v * sh as f32 - 0.5));
+            self.registration_with(t)
+                .section_midline(ends)
+                .into_iter()
+                .map(|(x, y)| (x / w as f32, y / h as f32))
+                .collect()
+        });
+
+        let ontology = model.atlas.ontology();
+        self.section_annotation_image = self.section_annotation.as_ref().map(|labels| {
+            let image = egui_annotation_from_mat(labels, |l| ontology.colour(l as u64));
+            ctx.load_texture("section_annotation", image, Default::default())
+        });
+    }
+
+    fn registration_with(&self, transform: [f32; 9]) -> Registration {
+        Registration {
+            orientation: self.atlas_orientation,
+            slice_index: self.slider_pos,
+            pitch: self.pitch,
+            yaw: self.yaw,
+            transform,
+            landmarks: self.landmarks.clone(),
+            deformation: self.deformation.clone(),
+            midline: vec![],
+        }
+    }
+
+    /// The hexagon vertices as (atlas slice pixel, full-resolution section pixel) pairs.
+    fn landmark_pairs(&self, model: &Model) -> Option<Landmarks> {
+        let (ar, hr) = (self.atlas_image_rect, self.hist_image_rect);
+        if !ar.is_positive() || !hr.is_positive() {
+            return None;
+        }
+
+        let (sh, sw) = self.atlas_slice(model).dim();
+        let (w, h) = self.image_size;
+
+        let to_pixels = |rect: Rect, (x, y): (f32, f32), (pw, ph): (usize, usize)| {
+            (
+                (x - rect.min.x) * pw as f32 / rect.width(),
+                (y - rect.min.y) * ph as f32 / rect.height(),
+            )
+        };
+
+        Some(Landmarks {
+            atlas: self
+                .atlas_hex
+                .iter()
+                .map(|&p| to_pixels(ar, p, (sw, sh)))
+                .collect(),
+            section: self
+                .hist_hex
+                .iter()
+                .map(|&p| to_pixels(hr, p, (w, h)))
+                .collect(),
+            thin_plate: self.thin_plate,
+        })
+    }
+
+    /// Fits the transform to the hexagon control points and redraws the preview.
+    pub fn fit_landmarks(&mut self, model: &Model, ctx: &Context) {
+        let Some(lm) = self.landmark_pairs(model) else {
+            return;
+        };
+
+        match fit_affine(&lm.atlas, &lm.section) {
+            Some(t) => {
+                self.atlas_transform = Some(t);
+                self.landmarks = Some(lm);
+                self.deformation = None;
+                self.dirty = true;
+                self.message = None;
+            }
+            None => self.message = Some("control points are degenerate".into()),
+        }
+        self.refresh_overlay(model, ctx);
+    }
+
+    /// Starts a manual registration that stretches the atlas slice over the section.
+    pub fn start_manual(&mut self, model: &Model, ctx: &Context) {
+        let slice = self.atlas_slice(model);
+        let (h, w) = slice.dim();
+
+        let reg = Registration::from_fit(
+            self.atlas_orientation,
+            self.slider_pos,
+            registration::IDENTITY,
+            (w, h),
+            (w, h),
+            self.image_size,
+        );
+        self.atlas_transform = Some(reg.transform);
+        self.landmarks = None;
+        self.deformation = None;
+        self.dirty = true;
+        self.refresh_overlay(model, ctx);
+    }
+
+    pub fn on_transform_edited(&mut self, model: &Model, ctx: &Context) {
+        self.landmarks = None;
+        self.deformation = None;
+        self.dirty = true;
+        self.refresh_overlay(model, ctx);
+    }
+
+    pub fn save_registration(&mut self, model: &mut Model) {
+        let Some(id) = self.selected_img.clone() else {
+            return;
+        };
+        let reg = self.atlas_transform.map(|t| self.registration_with(t));
+
+        match model.set_registration(&id, reg) {
+            Ok(()) => {
+                self.dirty = false;
+                self.message = None;
+            }
+            Err(err) => self.message = Some(err.to_string()),
+        }
+    }
+
+    pub fn clear_registration(&mut self, model: &mut Model, ctx: &Context) {
+        self.atlas_transform = None;
+        self.landmarks = None;
+        self.deformation = None;
+        self.save_registration(model);
+        self.refresh_overlay(model, ctx);
+    }
+
+    /// Picks up the result of a finished `register_button_pushed`.
+    pub fn poll_alignment(&mut self, model: &mut Model, ctx: &Context) {
+        let Some(res) = self.aligned.try_lock().ok().and_then(|mut a| a.take()) else {
+            return;
+        };
+
+        self.aligning = false;
+        let reg = match res {
+            Ok((reg, report)) => {
+                self.last_report = Some(report);
+                reg
+            }
+            Err(err) => {
+                self.message = Some(err);
+                return;
+            }
+        };
+
+        self.atlas_orientation = reg.orientation;
+        self.slider_pos = reg.slice_index;
+        self.pitch = reg.pitch;
+        self.yaw = reg.yaw;
+        self.atlas_transform = Some(reg.transform);
+        self.landmarks = reg.landmarks;
+        self.deformation = reg.deformation;
+        self.live_landmarks = false;
+        self.dirty = true;
+        self.on_atlas_interact(model, ctx);
     }
 
     pub fn register_button_pushed(&mut self, model: &mut Model) {
-        let moving = model
-            .atlas
-            .get_reference_img(self.atlas_orientation, self.slider_pos as isize)
-            .map(|&a| a as f32);
+        let Some(img_md) = self
+            .selected_img
+            .as_ref()
+            .and_then(|id| model.get_image(id))
+        else {
+            return;
+        };
+
+        let orientation = self.atlas_orientation;
+        let slice_index = self.slider_pos;
+        let (pitch, yaw) = (self.pitch, self.yaw);
+        let slice = self.atlas_slice(model);
 
-        self.selected_img.as_ref().map(|id| {
-            model.get_image(&id).map(|img_md| {
+        // the landmark fit, if any, seeds the optimiser
+        let init = self
+            .atlas_transform
+            .filter(|_| self.init_from_landmarks && self.landmarks.is_some())
+            .map(|t| self.registration_with(t));
+
+        let aligned = Arc::clone(&self.aligned);
+        let options = self.align_options;
+        let deform_options = self.deformable.then_some(DeformOptions {
+            metric: options.metric,
+            seed: options.seed,
+            ..self.deform_options
+        });
+        let ctx = Arc::clone(&model.frame);
+        self.aligning = true;
+
+        model.dispatch_exclusive(ThreadLabel::RegisterAlign, true, async move {
+            let res = tokio::task::spawn_blocking(move || {
                 let bbox = (0, 0, img_md.size.1 - 1, img_md.size.0 - 1);
-                let res = read_tiff_region(img_md.src_fn(), bbox, 25);
-                match res {
-                    Ok(ims) => {
-                        let fixed = array2buff(ims[0].map(|&a| a as f32));
-                        let moving = array2buff(moving.t().to_owned());
-                        iter_align(&moving, &fixed);
-                    }
-                    Err(_) => {}
+                let ims = read_tiff_region(img_md.src_fn(), bbox, 25).map_err(|e| e.to_string())?;
+                let fixed = ims
+                    .get(img_md.registration_channel)
+                    .ok_or("registration channel not in image")?
+                    .to_f32();
+                let (fh, fw) = fixed.dim();
+                let (sh, sw) = slice.dim();
+
+                let fixed = array2buff(fixed);
+                let moving = array2buff(slice.map(|&a| a as f32));
+                let init = init
+                    .and_then(|r| r.to_fit((sw, sh), (fw, fh), img_md.size))
+                    .unwrap_or(registration::IDENTITY);
+                let mut report = iter_align(&moving, &fixed, init, &options);
+
+                let mut reg = Registration {
+                    pitch,
+                    yaw,
+                    ..Registration::from_fit(
+                        orientation,
+                        slice_index,
+                        report.transform,
+                        (sw, sh),
+                        (fw, fh),
+                        img_md.size,
+                    )
+                };
+                if let Some(deform_options) = deform_options {
+                    let (field, deformed) =
+                        refine(&moving, &fixed, report.transform, &deform_options);
+                    let (sx, sy) = (
+                        img_md.size.0 as f32 / fw as f32,
+                        img_md.size.1 as f32 / fh as f32,
+                    );
+                    reg.deformation = Some(field.scaled(sx, sy));
+                    report.deformation = Some(deformed);
                 }
-            });
+                Ok((reg, report))
+            })
+            .await;
+
+            let res = res.unwrap_or_else(|_| Err("registration panicked".into()));
+            *aligned.lock().await = Some(res);
+            ctx.lock().await.request_repaint();
+        });
+    }
+
+    /// Writes the mask of `structure_acronym` for the selected image under its
+    /// saved registration, in the background.
+    pub fn write_mask_button_pushed(&mut self, model: &mut Model) {
+        let Some(img_md) = self
+            .selected_img
+            .as_ref()
+            .and_then(|id| model.get_image(id))
+        else {
+            return;
+        };
+        let Some(reg) = &img_md.registration else {
+            self.mask_status = Some("save a registration first".into());
+            return;
+        };
+        if let Err(err) = reg.validate(&model.atlas) {
+            self.mask_status = Some(err);
+            return;
+        }
+        let acronym = self.structure_acronym.trim().to_string();
+        let Some(ids) = model.atlas.structure_ids(&acronym) else {
+            self.mask_status = Some(format!("no structure {}", acronym));
+            return;
+        };
+
+        let annotation = model.atlas.get_oblique_annotation_img(
+            reg.orientation,
+            reg.slice_index as isize,
+            reg.pitch,
+            reg.yaw,
+        );
+        let hemispheres = self.mask_hemispheres.then(|| {
+            model.atlas.get_hemisphere_img(
+                reg.orientation,
+                reg.slice_index as isize,
+                reg.pitch,
+                reg.yaw,
+            )
+        });
+        let downsample = self.mask_downsample;
+        let written = Arc::clone(&self.mask_written);
+        let ctx = Arc::clone(&model.frame);
+        self.masking = true;
+        self.mask_status = None;
+
+        model.dispatch_exclusive(ThreadLabel::RegisterMasks, true, async move {
+            let res = tokio::task::spawn_blocking(move || {
+                write_structure_mask(
+                    &img_md,
+                    &annotation,
+                    hemispheres.as_ref(),
+                    &acronym,
+                    &ids,
+                    downsample,
+                )
+                .map_err(|e| e.to_string())
+            })
+            .await;
+
+            let res = res.unwrap_or_else(|_| Err("mask writing panicked".into()));
+            *written.lock().await = Some(res);
+            ctx.lock().await.request_repaint();
+        });
+    }
+
+    /// Picks up the result of a finished `write_mask_button_pushed`.
+    pub fn poll_mask(&mut self) {
+        let Some(res) = self.mask_written.try_lock().ok().and_then(|mut a| a.take()) else {
+            return;
+        };
+
+        self.masking = false;
+        self.mask_status = Some(match res {
+            Ok(path) => format!("wrote {}", path.display()),
+            Err(err) => err,
         });
     }
 }
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use eframe::egui::{Rect, TextureHandle};
+use tokio::sync::Mutex;
+
+use crate::{
+    model::{ImageMetadata, Model},
+    utility::{io::egui_image_from_path, types::Region},
+    ThreadLabel,
+};
+
+/// Longest edge, in pixels, of the preview regions are drawn on.
+const PREVIEW_SIZE: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DrawMode {
+    /// Click to add vertices, double-click to close.
+    Polygon,
+    /// Drag to trace, release to close.
+    Freehand,
+}
+
+pub struct SelectRegionsController {
+    pub selected_img: Option<String>,
+    pub image_size: (usize, usize),
+    pub preview_rect: Rect,
+    pub preview_data: Arc<Mutex<Option<TextureHandle>>>,
+    /// Why the preview could not be loaded.
+    pub preview_error: Arc<Mutex<Option<String>>>,
+    pub mode: DrawMode,
+    /// Working copy of the selected image's regions, saved explicitly.
+    pub regions: Vec<Region>,
+    pub active: Option<usize>,
+    /// Vertices of the region being drawn, in full-resolution `(row, col)`.
+    pub draft: Vec<(f64, f64)>,
+    pub dirty: bool,
+    /// Unsaved regions of images switched away from, restored on return.
+    pub unsaved: HashMap<String, Vec<Region>>,
+    pub message: Option<String>,
+}
+
+impl Default for SelectRegionsController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SelectRegionsController {
+    pub fn new() -> SelectRegionsController {
+        Self {
+            selected_img: None,
+            image_size: (0, 0),
+            preview_rect: Rect::ZERO,
+            preview_data: Arc::new(Mutex::new(None)),
+            preview_error: Arc::new(Mutex::new(None)),
+            mode: DrawMode::Polygon,
+            regions: vec![],
+            active: None,
+            draft: vec![],
+            dirty: false,
+            unsaved: HashMap::new(),
+            message: None,
+        }
+    }
+
+    pub fn on_image_selected(&mut self, im_md: &ImageMetadata, model: &mut Model) {
+        if let (true, Some(id)) = (self.dirty, self.selected_img.take()) {
+            self.unsaved.insert(id, std::mem::take(&mut self.regions));
+        }
+
+        self.selected_img = Some(im_md.src_fn().to_string());
+        self.image_size = im_md.size;
+        match self.unsaved.remove(im_md.src_fn()) {
+            Some(regions) => {
+                self.regions = regions;
+                self.dirty = true;
+            }
+            None => {
+                self.regions = im_md.regions.clone();
+                self.dirty = false;
+            }
+        }
+        self.active = None;
+        self.draft.clear();
+        self.message = None;
+
+        let (w, h) = im_md.size;
+        let df = std::cmp::max(1, std::cmp::max(w, h).div_ceil(PREVIEW_SIZE));
+
+        let id = Arc::clone(&self.preview_data);
+        let error = Arc::clone(&self.preview_error);
+        let ctx = Arc::clone(&model.frame);
+        let src_fn = im_md.analysis_fn();
+        let roi = im_md.full_roi();
+
+        model.dispatch_exclusive(ThreadLabel::SelectRegionsLoadPreview, true, async move {
+            let im =
+                tokio::task::spawn_blocking(move || egui_image_from_path(&src_fn, roi, df)).await;
+            let ctx = ctx.lock().await;
+
+            *error.lock().await = match im {
+                Ok(Ok(im)) => {
+                    let h = ctx.load_texture("regions_preview", im, Default::default());
+                    *id.lock().await = Some(h);
+                    None
+                }
+                Ok(Err(err)) => Some(format!("could not load the preview: {}", err)),
+                Err(err) => Some(format!("could not load the preview: {}", err)),
+            };
+
+            ctx.request_repaint();
+        });
+    }
+
+    pub fn add_vertex(&mut self, pt: (f64, f64)) {
+        let (w, h) = self.image_size;
+        self.draft
+            .push((pt.0.clamp(0.0, h as f64), pt.1.clamp(0.0, w as f64)));
+    }
+
+    /// Closes the draft into a new region with a free default name.
+    pub fn finish_draft(&mut self) {
+        if self.draft.len() < 3 {
+            self.draft.clear();
+            return;
+        }
+
+        let name = (1..)
+            .map(|i| format!("Region {}", i))
+            .find(|n| self.regions.iter().all(|r| &r.name != n))
+            .unwrap_or_default();
+
+        self.regions.push(Region {
+            name,
+            vertices: std::mem::take(&mut self.draft),
+        });
+        self.active = Some(self.regions.len() - 1);
+        self.dirty = true;
+    }
+
+    pub fn cancel_draft(&mut self) {
+        self.draft.clear();
+    }
+
+    pub fn move_vertex(&mut self, region: usize, vertex: usize, delta: (f64, f64)) {
+        let (w, h) = self.image_size;
+        if let Some(v) = self
+            .regions
+            .get_mut(region)
+            .and_then(|r| r.vertices.get_mut(vertex))
+        {
+            v.0 = (v.0 + delta.0).clamp(0.0, h as f64);
+            v.1 = (v.1 + delta.1).clamp(0.0, w as f64);
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove_region(&mut self, idx: usize) {
+        if idx < self.regions.len() {
+            self.regions.remove(idx);
+            self.active = None;
+            self.dirty = true;
+        }
+    }
+
+    pub fn save(&mut self, model: &mut Model) {
+        let Some(id) = &self.selected_img else {
+            return;
+        };
+
+        match model.set_regions(id, self.regions.clone()) {
+            Ok(()) => {
+                self.dirty = false;
+                self.message = None;
+            }
+            Err(err) => self.message = Some(err.to_string()),
+        }
+    }
+
+    /// Discards unsaved edits.
+    pub fn revert(&mut self, model: &Model) {
+        if let Some(img) = self
+            .selected_img
+            .as_ref()
+            .and_then(|id| model.get_image(id))
+        {
+            self.regions = img.regions;
+            self.active = None;
+            self.draft.clear();
+            self.dirty = false;
+            self.message = None;
+        }
+    }
+}
@@ -0,0 +1,16 @@
+pub mod algorithm;
+pub mod concurrency;
+pub mod controller;
+pub mod model;
+pub mod utility;
+pub mod view;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ThreadLabel {
+    SelectImagesLoadPreview,
+    AnalyseBatch,
+    AnalyseLoadOverlays,
+    SelectRegionsLoadPreview,
+    RegisterAlign,
+    RegisterMasks,
+}
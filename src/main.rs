@@ -1,10 +1,3 @@
-pub mod algorithm;
-pub mod concurrency;
-pub mod controller;
-pub mod model;
-pub mod utility;
-pub mod view;
-
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::Arc;
 use std::thread::sleep;
@@ -12,11 +5,14 @@ use std::thread::sleep;
 use eframe::egui::mutex::Mutex;
 use eframe::egui::{self, Context};
 
-use crate::concurrency::ThreadPool;
-use crate::controller::{HomeController, RegisterController, SelectImagesController};
-use crate::model::{Model, Workspace};
-use crate::utility::io::{read_tiff_region, save_as_luma16};
-use crate::view::{ui_tab_home, ui_tab_register, ui_tab_select_images};
+use microcount_rs::controller::{
+    AnalyseController, HomeController, RegisterController, SelectImagesController,
+    SelectRegionsController,
+};
+use microcount_rs::model::{self, Model};
+use microcount_rs::view::{
+    ui_tab_analyse, ui_tab_home, ui_tab_register, ui_tab_select_images, ui_tab_select_regions,
+};
 
 // fn main() {
 //     let img_fn = "/Users/albert/projects/microcount-rs/src/assets/test.tiff";
@@ -49,11 +45,6 @@ async fn main() -> eframe::Result {
     )
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub enum ThreadLabel {
-    SelectImagesLoadPreview,
-}
-
 enum Tab {
     Home,
     SelectImages,
@@ -68,6 +59,8 @@ struct MyApp {
     home_controller: HomeController,
     select_images_controller: SelectImagesController,
     register_controller: RegisterController,
+    select_regions_controller: SelectRegionsController,
+    analyse_controller: AnalyseController,
 }
 
 impl MyApp {
@@ -80,6 +73,8 @@ impl MyApp {
             home_controller: HomeController::new(),
             select_images_controller: SelectImagesController::new(),
             register_controller: RegisterController::new(),
+            select_regions_controller: SelectRegionsController::new(),
+            analyse_controller: AnalyseController::new(),
         }
     }
 }
@@ -113,10 +108,12 @@ impl eframe::App for MyApp {
                     ui_tab_select_images(&mut self.model, &mut self.select_images_controller, ui)
                 }
                 Tab::Register => {
-                    // ui_tab_register(&mut self.model, &mut self.register_controller, ui);
+                    ui_tab_register(&mut self.model, &mut self.register_controller, ui)
+                }
+                Tab::SelectRegions => {
+                    ui_tab_select_regions(&mut self.model, &mut self.select_regions_controller, ui)
                 }
-                Tab::SelectRegions => {}
-                Tab::Analyse => {}
+                Tab::Analyse => ui_tab_analyse(&mut self.model, &mut self.analyse_controller, ui),
             }
         });
     }
@@ -1,124 +1,463 @@
-use serde::Deserialize;
-use std::{collections::HashMap, fs};
+use imageproc::geometric_transformations::Interpolation;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fs, path::Path};
 use tiff::TiffError;
 
-use crate::utility::imops::{get_slice, matrix_vec_to_volume};
-use crate::utility::io::read_tiff_region;
-use crate::utility::types::{Matrix, Volume};
+use crate::model::atlas_manifest::{AtlasManifest, StructureColumns};
+use crate::model::ontology::{Ontology, Structure};
+use crate::utility::imops::{
+    get_oblique_labels, get_oblique_slice, get_slice, matrix_vec_to_volume, oblique_plane,
+};
+use crate::utility::io::{read_tiff_region, tiff_info};
+use crate::utility::types::{Matrix, PixelMatrix, Volume};
 
-const ATLAS_READ_ERR: TiffError =
-    TiffError::FormatError(tiff::TiffFormatError::InvalidDimensions(0, 0));
+#[derive(Debug)]
+pub enum AtlasError {
+    Tiff(TiffError),
+    Csv(csv::Error),
+    Manifest(String),
+}
+
+impl std::fmt::Display for AtlasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Tiff(err) => write!(f, "{}", err),
+            Self::Csv(err) => write!(f, "{}", err),
+            Self::Manifest(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<TiffError> for AtlasError {
+    fn from(err: TiffError) -> Self {
+        Self::Tiff(err)
+    }
+}
+
+impl From<csv::Error> for AtlasError {
+    fn from(err: csv::Error) -> Self {
+        Self::Csv(err)
+    }
+}
+
+impl From<std::io::Error> for AtlasError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Tiff(err.into())
+    }
+}
 
 #[derive(Debug)]
 pub struct Atlas {
+    manifest: AtlasManifest,
     reference: Volume<u16>,
-    annotation: Volume<u16>,
+    annotation: Volume<u32>,
     size: (usize, usize, usize),
-    idx_map: HashMap<u64, Vec<u64>>,
-    abr_map: HashMap<String, u64>,
+    ontology: Ontology,
 }
 
 impl Atlas {
-    pub fn new(app_dir: String) -> Result<Atlas, TiffError> {
-        let ref_path = format!("{}/assets/reference.tiff", app_dir);
-        let ann_path = format!("{}/assets/annotation.tiff", app_dir);
-        let str_path = format!("{}/assets/structures.csv", app_dir);
-
-        // println!("{}", ref_path);
-
-        let reference = read_tiff_region(&ref_path, (0, 0, 160, 228), 1)?;
-        let reference = matrix_vec_to_volume(&reference).ok_or(ATLAS_READ_ERR)?;
+    /// Loads the atlas described by `{app_dir}/assets/atlas.json`, or the
+    /// 50 µm Allen CCF files in that folder when there is no manifest.
+    pub fn new(app_dir: String) -> Result<Atlas, AtlasError> {
+        let manifest = AtlasManifest::from_dir(&Path::new(&app_dir).join("assets"))
+            .map_err(AtlasError::Manifest)?;
+        Atlas::from_manifest(manifest)
+    }
 
-        let annotation = read_tiff_region(&ann_path, (0, 0, 160, 228), 1)?;
-        let annotation = matrix_vec_to_volume(&annotation).ok_or(ATLAS_READ_ERR)?;
+    pub fn from_manifest(manifest: AtlasManifest) -> Result<Atlas, AtlasError> {
+        let reference = read_volume(&manifest.reference_path(), |p| Ok(p.to_u16()))?;
+        let annotation = read_volume(&manifest.annotation_path(), labels)?;
+        if reference.dim() != annotation.dim() {
+            return Err(AtlasError::Manifest(format!(
+                "reference is {:?} voxels but annotation is {:?}",
+                reference.dim(),
+                annotation.dim()
+            )));
+        }
 
-        let s_reader = fs::File::open(str_path)?;
-        let s_table = csv::Reader::from_reader(s_reader)
-            .deserialize::<StructureRow>()
-            .filter_map(|a| {
-                // println!("{:?}", a);
-                a.ok()
-            })
-            .collect();
+        let s_reader = fs::File::open(manifest.structures_path())?;
+        let s_table = read_structures(s_reader, &manifest.structures.columns)?;
 
-        let idx_map = Atlas::create_idx_map(&s_table);
-        let abr_map = Atlas::create_abr_map(&s_table);
+        let ontology = Atlas::create_ontology(&s_table);
 
         Ok(Atlas {
+            size: reference.dim(),
+            manifest,
             reference: reference,
             annotation: annotation,
-            size: (264, 160, 228),
-            idx_map: idx_map,
-            abr_map: abr_map,
+            ontology,
         })
     }
 
+    pub fn manifest(&self) -> &AtlasManifest {
+        &self.manifest
+    }
+
+    /// Voxels along (page, row, column).
+    pub fn size(&self) -> (usize, usize, usize) {
+        self.size
+    }
+
     pub fn get_reference_img(&self, ori: Orientation, idx: isize) -> Matrix<u16> {
-        match ori {
-            Orientation::Axial => get_slice(&self.reference, idx, 0),
-            Orientation::Sagittal => get_slice(&self.reference, idx, 2),
-            Orientation::Coronal => get_slice(&self.reference, idx, 1),
+        get_slice(&self.reference, idx, self.manifest.slice_axis(ori))
+    }
+
+    pub fn get_annotation_img(&self, ori: Orientation, idx: isize) -> Matrix<u32> {
+        get_slice(&self.annotation, idx, self.manifest.slice_axis(ori))
+    }
+
+    /// The reference slice `idx` of `ori` tilted by `pitch` and `yaw` degrees,
+    /// trilinearly interpolated; see `get_oblique_slice`.
+    pub fn get_oblique_reference_img(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        pitch: f32,
+        yaw: f32,
+    ) -> Matrix<u16> {
+        let axis = self.manifest.slice_axis(ori);
+        if (pitch, yaw) == (0.0, 0.0) {
+            return get_slice(&self.reference, idx, axis);
         }
+        get_oblique_slice(
+            &self.reference,
+            idx,
+            axis,
+            (pitch, yaw),
+            self.manifest.voxel_size,
+            Interpolation::Bilinear,
+        )
     }
 
-    pub fn n_slices(&self, ori: Orientation) -> usize {
-        let (a, c, s) = self.reference.dim();
-        match ori {
-            Orientation::Axial => a,
-            Orientation::Sagittal => s,
-            Orientation::Coronal => c,
+    /// The annotation slice like `get_oblique_reference_img`, with labels kept
+    /// by nearest-neighbour sampling.
+    pub fn get_oblique_annotation_img(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        pitch: f32,
+        yaw: f32,
+    ) -> Matrix<u32> {
+        let axis = self.manifest.slice_axis(ori);
+        if (pitch, yaw) == (0.0, 0.0) {
+            return get_slice(&self.annotation, idx, axis);
         }
+        get_oblique_labels(
+            &self.annotation,
+            idx,
+            axis,
+            (pitch, yaw),
+            self.manifest.voxel_size,
+        )
     }
 
-    fn create_idx_map(s_table: &Vec<StructureRow>) -> HashMap<u64, Vec<u64>> {
-        s_table
-            .iter()
-            .map(|a| (a.id, Atlas::child_idxs_from_table(s_table, a.id)))
-            .collect()
+    /// Voxel coordinate of the midline along the LR axis.
+    pub fn midline(&self) -> f64 {
+        let lr = self.manifest.lr_axis();
+        let n = [self.size.0, self.size.1, self.size.2][lr];
+        self.manifest.midline.unwrap_or((n as f64 - 1.0) / 2.0)
     }
 
-    fn create_abr_map(s_table: &Vec<StructureRow>) -> HashMap<String, u64> {
-        s_table
-            .iter()
-            .map(|a| (a.acronym.to_string(), a.id))
-            .collect()
+    /// The hemisphere of each pixel of the (possibly tilted) slice, as
+    /// `Hemisphere::label`s.
+    pub fn get_hemisphere_img(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        pitch: f32,
+        yaw: f32,
+    ) -> Matrix<u16> {
+        let lr = self.manifest.lr_axis();
+        let midline = self.midline() as f32;
+        let (dim, point) = self.plane(ori, idx, (pitch, yaw));
+        Matrix::from_shape_fn(dim, |(r, c)| {
+            let side = if point(r as f32, c as f32)[lr] < midline {
+                Hemisphere::Left
+            } else {
+                Hemisphere::Right
+            };
+            side.label()
+        })
     }
 
-    fn child_idxs_from_table(s_table: &Vec<StructureRow>, idx: u64) -> Vec<u64> {
-        let finder = |j| {
-            s_table
+    /// Where the midline crosses the (possibly tilted) slice, as the two ends
+    /// of a segment in slice pixels (x, y); `None` when it runs parallel to
+    /// the slice or misses it.
+    pub fn get_midline(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        pitch: f32,
+        yaw: f32,
+    ) -> Option<[(f32, f32); 2]> {
+        let lr = self.manifest.lr_axis();
+        let midline = self.midline() as f32;
+        let ((h, w), point) = self.plane(ori, idx, (pitch, yaw));
+        let (h, w) = (h as f32 - 1.0, w as f32 - 1.0);
+
+        // the LR coordinate is linear over the slice: a0 + a1 * row + a2 * col
+        let a0 = point(0.0, 0.0)[lr] - midline;
+        let a1 = point(1.0, 0.0)[lr] - midline - a0;
+        let a2 = point(0.0, 1.0)[lr] - midline - a0;
+
+        let mut ends: Vec<(f32, f32)> = vec![];
+        let mut add = |x: f32, y: f32| {
+            let inside = (-1e-3..=w + 1e-3).contains(&x) && (-1e-3..=h + 1e-3).contains(&y);
+            let seen = ends
                 .iter()
-                .filter(|r| r.parent_structure_id as u64 == j)
-                .map(|r| r.id)
-                .collect()
+                .any(|&(ex, ey)| (ex - x).abs() < 1e-3 && (ey - y).abs() < 1e-3);
+            if inside && !seen {
+                ends.push((x, y));
+            }
         };
+        if a2.abs() > 1e-6 {
+            [0.0, h]
+                .into_iter()
+                .for_each(|y| add(-(a0 + a1 * y) / a2, y));
+        }
+        if a1.abs() > 1e-6 {
+            [0.0, w]
+                .into_iter()
+                .for_each(|x| add(x, -(a0 + a2 * x) / a1));
+        }
+        match ends[..] {
+            [a, b, ..] => Some([a, b]),
+            _ => None,
+        }
+    }
 
-        let mut children: Vec<u64> = finder(idx);
-        let mut i = 0;
+    fn plane(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        angles: (f32, f32),
+    ) -> ((usize, usize), impl Fn(f32, f32) -> [f32; 3]) {
+        oblique_plane(
+            self.annotation.shape(),
+            idx,
+            self.manifest.slice_axis(ori),
+            angles,
+            self.manifest.voxel_size,
+        )
+    }
 
-        while i < children.len() {
-            let sub_idx = children[i];
-            let subchildren = finder(sub_idx);
-            children = [children, subchildren].concat();
-            i += 1;
-        }
+    pub fn ontology(&self) -> &Ontology {
+        &self.ontology
+    }
 
-        return children;
+    /// Ids of the structure `acronym` and all of its descendants.
+    pub fn structure_ids(&self, acronym: &str) -> Option<HashSet<u64>> {
+        let id = self.ontology.by_acronym(acronym)?.id;
+        let mut ids = self
+            .ontology
+            .descendants(id)
+            .into_iter()
+            .collect::<HashSet<u64>>();
+        ids.insert(id);
+        Some(ids)
+    }
+
+    /// The deepest structure labelled at voxel (page, row, column), if any.
+    pub fn structure_at(&self, voxel: (usize, usize, usize)) -> Option<&Structure> {
+        let label = *self.annotation.get(voxel)?;
+        self.ontology.get(label as u64)
+    }
+
+    /// Structures labelled anywhere in the (possibly tilted) slice, shallowest first.
+    pub fn structures_in_slice(
+        &self,
+        ori: Orientation,
+        idx: isize,
+        pitch: f32,
+        yaw: f32,
+    ) -> Vec<&Structure> {
+        let labels = self
+            .get_oblique_annotation_img(ori, idx, pitch, yaw)
+            .iter()
+            .map(|&l| l as u64)
+            .collect::<HashSet<u64>>();
+        let mut found = labels
+            .into_iter()
+            .filter_map(|l| self.ontology.get(l))
+            .collect::<Vec<&Structure>>();
+        found.sort_by_cached_key(|s| (self.ontology.depth(s.id), s.acronym.clone()));
+        found
+    }
+
+    pub fn n_slices(&self, ori: Orientation) -> usize {
+        let (p, r, c) = self.size;
+        [p, r, c][self.manifest.slice_axis(ori)]
+    }
+
+    fn create_ontology(s_table: &[StructureRow]) -> Ontology {
+        Ontology::new(
+            s_table
+                .iter()
+                .map(|r| Structure {
+                    id: r.id,
+                    acronym: r.acronym.clone(),
+                    name: r.name.clone(),
+                    parent: r.parent,
+                    colour: r.colour,
+                })
+                .collect(),
+        )
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug)]
 struct StructureRow {
     acronym: String,
     id: u64,
     name: String,
-    structure_id_path: String,
-    parent_structure_id: f64,
+    parent: Option<u64>,
+    colour: Option<[u8; 3]>,
+}
+
+/// Reads every page of a TIFF into a (page, row, column) volume, converting
+/// the samples with `convert`.
+fn read_volume<T: Clone>(
+    path: &Path,
+    convert: impl Fn(&PixelMatrix) -> Result<Matrix<T>, AtlasError>,
+) -> Result<Volume<T>, AtlasError> {
+    let path = path.to_string_lossy();
+    let info = tiff_info(&path)?;
+    let (w, h) = info.dimensions;
+    let pages = read_tiff_region(&path, (0, 0, h, w), 1)?
+        .iter()
+        .map(convert)
+        .collect::<Result<Vec<Matrix<T>>, AtlasError>>()?;
+    matrix_vec_to_volume(&pages).ok_or(AtlasError::Manifest(format!("{} is empty", path)))
+}
+
+/// Annotation samples as `u32` labels, failing on any that is not a whole
+/// number in range rather than clamping it onto another structure.
+fn labels(pixels: &PixelMatrix) -> Result<Matrix<u32>, AtlasError> {
+    if let PixelMatrix::U8(_) | PixelMatrix::U16(_) | PixelMatrix::U32(_) = pixels {
+        return Ok(pixels.to_u32());
+    }
+    let invalid = pixels
+        .to_f64()
+        .into_iter()
+        .find(|&a| a.fract() != 0.0 || !(0.0..=u32::MAX as f64).contains(&a));
+    match invalid {
+        Some(a) => Err(AtlasError::Manifest(format!(
+            "annotation label {} is not a whole number from 0 to {}",
+            a,
+            u32::MAX
+        ))),
+        None => Ok(pixels.to_u32()),
+    }
 }
 
-#[derive(Copy, Clone)]
+/// Parses the structure table, taking each field from its mapped column.
+/// Rows without a numeric id are skipped; an empty or non-numeric parent
+/// marks a root.
+fn read_structures(
+    reader: impl std::io::Read,
+    columns: &StructureColumns,
+) -> Result<Vec<StructureRow>, AtlasError> {
+    let mut rdr = csv::Reader::from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .ok_or(AtlasError::Manifest(format!(
+                "structure table has no column {}",
+                name
+            )))
+    };
+    let (id, acronym, name, parent) = (
+        column(&columns.id)?,
+        column(&columns.acronym)?,
+        column(&columns.name)?,
+        column(&columns.parent)?,
+    );
+    // colours are optional
+    let colour = column(&columns.colour).ok();
+
+    // parents may be written as floats, e.g. "997.0"
+    let parse_id = |s: &str| {
+        s.trim().parse::<u64>().ok().or_else(|| {
+            s.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|a| a.is_finite())
+                .map(|a| a as u64)
+        })
+    };
+
+    let mut rows = vec![];
+    for record in rdr.records() {
+        let record = record?;
+        let Some(row_id) = parse_id(&record[id]) else {
+            continue;
+        };
+        rows.push(StructureRow {
+            acronym: record[acronym].to_owned(),
+            id: row_id,
+            name: record[name].to_owned(),
+            parent: parse_id(&record[parent]),
+            colour: colour.and_then(|c| parse_hex_colour(&record[c])),
+        });
+    }
+    Ok(rows)
+}
+
+/// Parses "RRGGBB", with or without a leading '#'.
+fn parse_hex_colour(s: &str) -> Option<[u8; 3]> {
+    let s = s.trim().trim_start_matches('#');
+    if s.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(s.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Orientation {
     Axial,
     Sagittal,
     Coronal,
 }
+
+/// Side of the atlas midline.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Hemisphere {
+    Left,
+    Right,
+}
+
+impl Hemisphere {
+    pub const ALL: [Self; 2] = [Self::Left, Self::Right];
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            Self::Left => "Left",
+            Self::Right => "Right",
+        }
+    }
+
+    /// Value marking the hemisphere in a hemisphere image; 0 is neither.
+    pub fn label(&self) -> u16 {
+        match self {
+            Self::Left => 1,
+            Self::Right => 2,
+        }
+    }
+
+    pub fn from_label(label: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|h| h.label() == label)
+    }
+}
+
+impl Orientation {
+    pub fn to_str(&self) -> &str {
+        match self {
+            Self::Axial => "Axial",
+            Self::Sagittal => "Sagittal",
+            Self::Coronal => "Coronal",
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::atlas::Orientation;
+
+pub const MANIFEST_FILE: &str = "atlas.json";
+
+/// Anatomical axes an atlas volume can be stored along.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnatomicalAxis {
+    /// Anterior–posterior, normal to coronal sections.
+    AP,
+    /// Dorsal–ventral, normal to axial (horizontal) sections.
+    DV,
+    /// Left–right, normal to sagittal sections.
+    LR,
+}
+
+/// Which structure table columns hold each field.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StructureColumns {
+    pub id: String,
+    pub acronym: String,
+    pub name: String,
+    /// Empty for root structures.
+    pub parent: String,
+    /// Optional hex RGB display colour, e.g. "FF7080".
+    pub colour: String,
+}
+
+impl Default for StructureColumns {
+    fn default() -> Self {
+        Self {
+            id: "id".into(),
+            acronym: "acronym".into(),
+            name: "name".into(),
+            parent: "parent_structure_id".into(),
+            colour: "color_hex_triplet".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StructureTable {
+    pub file: String,
+    #[serde(default)]
+    pub columns: StructureColumns,
+}
+
+/// Describes an atlas on disk. File paths are relative to the manifest.
+///
+/// The volumes are multi-page TIFFs read as (page, row, column); `axis_order`
+/// names the anatomical axis along each of those and `voxel_size` its spacing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AtlasManifest {
+    pub name: String,
+    pub reference: String,
+    pub annotation: String,
+    pub structures: StructureTable,
+    /// Micrometres per voxel along (page, row, column).
+    pub voxel_size: [f64; 3],
+    pub axis_order: [AnatomicalAxis; 3],
+    /// Voxel coordinate of the midline along the LR axis, the centre of the
+    /// volume when unset. Voxels below it are the left hemisphere.
+    #[serde(default)]
+    pub midline: Option<f64>,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl AtlasManifest {
+    /// The 50 µm Allen CCF files expected in `dir` when it has no manifest.
+    pub fn allen_50um(dir: &Path) -> Self {
+        Self {
+            name: "Allen CCF 50 µm".into(),
+            reference: "reference.tiff".into(),
+            annotation: "annotation.tiff".into(),
+            structures: StructureTable {
+                file: "structures.csv".into(),
+                columns: StructureColumns::default(),
+            },
+            voxel_size: [50.0; 3],
+            axis_order: [AnatomicalAxis::AP, AnatomicalAxis::DV, AnatomicalAxis::LR],
+            midline: None,
+            dir: dir.to_owned(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut manifest =
+            serde_json::from_str::<Self>(&s).map_err(|e| format!("{}: {}", path.display(), e))?;
+        manifest.dir = path.parent().map(Path::to_owned).unwrap_or_default();
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Reads `dir`'s manifest, falling back to `allen_50um` if it has none.
+    pub fn from_dir(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(MANIFEST_FILE);
+        if path.exists() {
+            Self::from_file(&path)
+        } else {
+            Ok(Self::allen_50um(dir))
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.voxel_size.iter().any(|&v| !(v.is_finite() && v > 0.0)) {
+            return Err("voxel sizes must be positive".into());
+        }
+        if self.midline.is_some_and(|m| !m.is_finite()) {
+            return Err("midline must be finite".into());
+        }
+        let [a, b, c] = self.axis_order;
+        if a == b || b == c || a == c {
+            return Err("axis_order must name each of AP, DV and LR once".into());
+        }
+        Ok(())
+    }
+
+    pub fn reference_path(&self) -> PathBuf {
+        self.dir.join(&self.reference)
+    }
+
+    pub fn annotation_path(&self) -> PathBuf {
+        self.dir.join(&self.annotation)
+    }
+
+    pub fn structures_path(&self) -> PathBuf {
+        self.dir.join(&self.structures.file)
+    }
+
+    /// The volume axis running left to right.
+    pub fn lr_axis(&self) -> usize {
+        self.axis_order
+            .iter()
+            .position(|&a| a == AnatomicalAxis::LR)
+            .expect("validated axis order")
+    }
+
+    /// The volume axis sections of `ori` are cut across.
+    pub fn slice_axis(&self, ori: Orientation) -> usize {
+        let normal = match ori {
+            Orientation::Coronal => AnatomicalAxis::AP,
+            Orientation::Axial => AnatomicalAxis::DV,
+            Orientation::Sagittal => AnatomicalAxis::LR,
+        };
+        self.axis_order
+            .iter()
+            .position(|&a| a == normal)
+            .expect("validated axis order")
+    }
+}
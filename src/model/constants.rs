@@ -2,3 +2,11 @@ pub const DIR_CONVERT: &str = "ws_converted";
 pub const DIR_DOWN: &str = "ws_downsampled";
 pub const DIR_PROC: &str = "ws_processed";
 pub const DIR_MASK: &str = "ws_masks";
+pub const CELLS_FILE: &str = "cells.csv";
+pub const STRUCTURES_FILE: &str = "structures.csv";
+pub const MIDLINE_FILE: &str = "midline.csv";
+
+/// Lower-case fragments of channel names, used to pick each image's channels.
+pub const REGISTRATION_CHANNEL_NAMES: &[&str] = &["dapi", "hoechst", "nissl", "nuclear", "nuclei"];
+pub const CELL_CHANNEL_NAMES: &[&str] = &["iba1", "iba-1", "aif1", "cx3cr1", "tmem119", "p2ry12"];
+pub const COMARKER_CHANNEL_NAMES: &[&str] = &["cd68"];
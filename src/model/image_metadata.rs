@@ -1,8 +1,16 @@
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{model::DIR_CONVERT, utility::io};
+use crate::{
+    algorithm::anatomy::StructureResults,
+    model::{constants, Registration, DIR_CONVERT},
+    utility::{
+        io,
+        types::{AcquisitionMetadata, Region, Results, Settings, ROI},
+    },
+};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ImageMetadata {
@@ -23,6 +31,32 @@ pub struct ImageMetadata {
     pub comarker_buffer: String,
 
     pub conversion_status: ConvertStatus,
+
+    #[serde(default)]
+    pub settings_override: Option<Settings>,
+
+    #[serde(default)]
+    pub analysis_status: AnalysisStatus,
+    #[serde(default)]
+    pub results: Option<Results>,
+
+    /// Regions analysis is restricted to; empty means the whole image.
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    #[serde(default)]
+    pub region_results: BTreeMap<String, Results>,
+
+    #[serde(default)]
+    pub registration: Option<Registration>,
+    /// Results per atlas structure from the last analysis of a registered image.
+    #[serde(default)]
+    pub structure_results: Vec<StructureResults>,
+
+    /// Micrometres per full-resolution pixel, if known.
+    #[serde(default)]
+    pub pixel_size: Option<f64>,
+    #[serde(default)]
+    pub acquisition: AcquisitionMetadata,
 }
 
 impl ImageMetadata {
@@ -45,6 +79,15 @@ impl ImageMetadata {
             comarker_buffer: String::new(),
             registration_buffer: String::new(),
             conversion_status: ConvertStatus::Unconverted,
+            settings_override: None,
+            analysis_status: AnalysisStatus::Unanalysed,
+            results: None,
+            regions: vec![],
+            region_results: BTreeMap::new(),
+            registration: None,
+            structure_results: vec![],
+            pixel_size: None,
+            acquisition: AcquisitionMetadata::default(),
         }
     }
 
@@ -53,9 +96,17 @@ impl ImageMetadata {
         let _ = io::tiff_info(&conv_fn).map(|info| {
             self.size = info.dimensions;
             self.channel_count = info.n_channels;
-            self.registration_channel = 0;
-            self.cell_channel = 1 % (1 + info.n_channels);
-            self.comarker_channel = 2 % (1 + info.n_channels);
+            self.pixel_size = info.metadata.pixel_size.map(|(x, y)| (x * y).sqrt());
+            let named = |names| info.metadata.channel_named(names);
+            self.registration_channel = named(constants::REGISTRATION_CHANNEL_NAMES).unwrap_or(0);
+            self.cell_channel =
+                named(constants::CELL_CHANNEL_NAMES).unwrap_or(1 % (1 + info.n_channels));
+            self.comarker_channel =
+                named(constants::COMARKER_CHANNEL_NAMES).unwrap_or(2 % (1 + info.n_channels));
+            self.registration_buffer = self.registration_channel.to_string();
+            self.cell_buffer = self.cell_channel.to_string();
+            self.comarker_buffer = self.comarker_channel.to_string();
+            self.acquisition = info.metadata;
         });
     }
 
@@ -78,6 +129,105 @@ impl ImageMetadata {
         )
     }
 
+    /// Per-image folder for processed analysis outputs.
+    pub fn proc_dir(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.img_ws_dir,
+            constants::DIR_PROC,
+            self.img_id
+        )
+    }
+
+    /// Per-image folder for analysis masks.
+    pub fn mask_dir(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.img_ws_dir,
+            constants::DIR_MASK,
+            self.img_id
+        )
+    }
+
+    /// Folder for the annotation warped onto this image and its structure masks.
+    pub fn atlas_mask_dir(&self) -> String {
+        format!("{}/atlas", self.mask_dir())
+    }
+
+    pub fn annotation_fn(&self) -> String {
+        format!("{}/annotation.tiff", self.atlas_mask_dir())
+    }
+
+    pub fn structure_mask_fn(&self, acronym: &str) -> String {
+        let stem: String = acronym
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}/{}.tiff", self.atlas_mask_dir(), stem)
+    }
+
+    pub fn cells_fn(&self) -> String {
+        format!("{}/{}", self.proc_dir(), constants::CELLS_FILE)
+    }
+
+    pub fn structures_fn(&self) -> String {
+        format!("{}/{}", self.proc_dir(), constants::STRUCTURES_FILE)
+    }
+
+    pub fn midline_fn(&self) -> String {
+        format!("{}/{}", self.proc_dir(), constants::MIDLINE_FILE)
+    }
+
+    /// The file analysis should read from: the converted copy once it exists,
+    /// otherwise the original source image.
+    pub fn analysis_fn(&self) -> String {
+        match self.conversion_status {
+            ConvertStatus::Converted => self.conv_fn(),
+            _ => self.source_fn.clone(),
+        }
+    }
+
+    pub fn full_roi(&self) -> ROI {
+        (0, 0, self.size.1, self.size.0)
+    }
+
+    pub fn set_settings_override(&mut self, settings: Option<Settings>) -> Result<(), String> {
+        if let Some(s) = &settings {
+            s.validate()?;
+        }
+        self.settings_override = settings;
+        Ok(())
+    }
+
+    /// Replaces the regions, rejecting invalid polygons, regions outside the
+    /// image and names that are used twice or would share an output folder,
+    /// compared case-insensitively for case-insensitive file systems.
+    pub fn set_regions(&mut self, regions: Vec<Region>) -> Result<(), String> {
+        let mut names = HashSet::new();
+        let mut dirs = BTreeMap::new();
+        for region in &regions {
+            region.validate(self.size)?;
+            if !names.insert(region.name.as_str()) {
+                return Err(format!("region name {} is used twice", region.name));
+            }
+            let dir = region.dir_name().to_lowercase();
+            if let Some(other) = dirs.insert(dir, region.name.as_str()) {
+                return Err(format!(
+                    "regions {} and {} would share an output folder, rename one",
+                    other, region.name
+                ));
+            }
+        }
+        self.regions = regions;
+        Ok(())
+    }
+
     pub fn refresh_channels(&mut self) {
         str::parse::<usize>(&self.registration_buffer)
             .map(|v| self.registration_channel = v)
@@ -109,3 +259,27 @@ impl ConvertStatus {
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum AnalysisStatus {
+    #[default]
+    Unanalysed,
+    Queued,
+    Analysing,
+    Analysed,
+    Failed(String),
+    Cancelled,
+}
+
+impl AnalysisStatus {
+    pub fn to_str(&self) -> &str {
+        match self {
+            Self::Unanalysed => "Unanalysed",
+            Self::Queued => "Queued",
+            Self::Analysing => "Analysing",
+            Self::Analysed => "Analysed",
+            Self::Failed(_) => "Failed",
+            Self::Cancelled => "Cancelled",
+        }
+    }
+}
@@ -1,11 +1,17 @@
 pub mod atlas;
+pub mod atlas_manifest;
 pub mod constants;
 pub mod image_metadata;
 pub mod model;
+pub mod ontology;
+pub mod registration;
+pub mod settings;
 pub mod workspace;
 
 pub use atlas::Atlas;
 pub use constants::DIR_CONVERT;
-pub use image_metadata::{ConvertStatus, ImageMetadata};
+pub use image_metadata::{AnalysisStatus, ConvertStatus, ImageMetadata};
 pub use model::Model;
+pub use registration::Registration;
+pub use settings::SettingsStore;
 pub use workspace::Workspace;
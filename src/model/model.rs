@@ -7,14 +7,17 @@ use std::sync::Arc;
 use std::{collections::HashSet, fs};
 use tokio::sync::Mutex;
 
-use crate::concurrency::ThreadPool;
-use crate::model::{constants, Atlas, ConvertStatus, ImageMetadata, Workspace};
+use crate::concurrency::{batch, BatchHandle, ThreadPool};
+use crate::model::{
+    constants, Atlas, ConvertStatus, ImageMetadata, Registration, SettingsStore, Workspace,
+};
+use crate::utility::{tile_cache, types::Region};
 use crate::ThreadLabel;
 
 // #[derive(Debug)]
 pub struct Model {
     pub workspace: Option<Arc<Mutex<Workspace>>>,
-    pub atlas: Atlas,
+    pub atlas: Arc<Atlas>,
     pub counter: Arc<Mutex<i32>>,
     pub threadpool: ThreadPool<ThreadLabel>,
     pub frame: Arc<Mutex<Context>>,
@@ -24,7 +27,7 @@ impl Model {
     pub fn new(app_dir: String, frame: Context) -> Model {
         Model {
             workspace: None,
-            atlas: Atlas::new(app_dir).unwrap(),
+            atlas: Arc::new(Atlas::new(app_dir).unwrap()),
             counter: Arc::new(Mutex::new(0)),
             threadpool: ThreadPool::new(5, 5),
             frame: Arc::new(Mutex::new(frame)),
@@ -61,20 +64,7 @@ impl Model {
             None => return Err(Error::new(std::io::ErrorKind::NotADirectory, "")),
         };
 
-        let ws_s = match fs::read(Path::new(&ws_dir).join("ws.json")) {
-            Ok(v) => match String::from_utf8(v) {
-                Ok(s) => s,
-                Err(err) => {
-                    return Err(Error::new(std::io::ErrorKind::InvalidData, err.to_string()))
-                }
-            },
-            Err(err) => return Err(err),
-        };
-
-        let ws = match serde_json::from_str::<Workspace>(&ws_s) {
-            Ok(w) => w,
-            Err(err) => return Err(Error::new(std::io::ErrorKind::InvalidData, err.to_string())),
-        };
+        let ws = Workspace::from_dir(&ws_dir)?;
 
         self.workspace = Some(Arc::new(Mutex::new(ws)));
 
@@ -100,6 +90,7 @@ impl Model {
                 Some(files) => {
                     files.iter().for_each(|file| {
                         let mut img = ImageMetadata::new(file.to_str().unwrap(), &ws.dir_name);
+                        tile_cache::invalidate(img.src_fn());
                         img.set_metadata();
                         ws.images.insert(img.src_fn().to_string(), img);
                     });
@@ -128,6 +119,7 @@ impl Model {
     fn convert(img: &mut ImageMetadata) {
         img.conversion_status = ConvertStatus::Converting;
         std::fs::copy(img.src_fn(), img.conv_fn());
+        tile_cache::invalidate(&img.conv_fn());
         img.conversion_status = ConvertStatus::Converted;
     }
 
@@ -155,6 +147,68 @@ impl Model {
         }
     }
 
+    pub fn get_settings(&self) -> Option<SettingsStore> {
+        self.workspace
+            .as_ref()
+            .and_then(|ws| ws.try_lock().map(|w| w.settings.clone()).ok())
+    }
+
+    pub fn set_active_preset(&mut self, name: &str) -> Result<(), Error> {
+        let ws = self
+            .workspace
+            .as_ref()
+            .ok_or(Error::other("No workspace loaded!"))?;
+        let mut ws = ws
+            .try_lock()
+            .map_err(|_| Error::other("could not acquire lock"))?;
+        ws.settings
+            .set_active(name)
+            .map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        ws.save()
+    }
+
+    pub fn set_regions(&mut self, id: &str, regions: Vec<Region>) -> Result<(), Error> {
+        self.update_image(id, |img| {
+            img.set_regions(regions)
+                .map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))
+        })
+    }
+
+    pub fn set_registration(
+        &mut self,
+        id: &str,
+        registration: Option<Registration>,
+    ) -> Result<(), Error> {
+        let registration = registration.map(|mut r| {
+            r.set_midline(&self.atlas);
+            r
+        });
+        self.update_image(id, |img| {
+            img.registration = registration;
+            Ok(())
+        })
+    }
+
+    /// Applies `f` to the image `id` and saves the workspace if it succeeds.
+    fn update_image<F>(&mut self, id: &str, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut ImageMetadata) -> Result<(), Error>,
+    {
+        let ws = self
+            .workspace
+            .as_ref()
+            .ok_or(Error::other("No workspace loaded!"))?;
+        let mut ws = ws
+            .try_lock()
+            .map_err(|_| Error::other("could not acquire lock"))?;
+        let img = ws.images.get_mut(id).ok_or(Error::new(
+            std::io::ErrorKind::NotFound,
+            "image not in workspace",
+        ))?;
+        f(img)?;
+        ws.save()
+    }
+
     fn save_workspace(&self) {
         self.workspace.as_ref().map(|ws| {
             let dir_name = self.get_dir_name().clone();
@@ -166,6 +220,33 @@ impl Model {
         });
     }
 
+    /// Starts analysing the images `ids` on the thread pool, `worker_limit` at a
+    /// time. Starting another batch cancels this one.
+    pub fn analyse_images(
+        &mut self,
+        ids: Vec<String>,
+        worker_limit: usize,
+    ) -> Result<BatchHandle, Error> {
+        let ws = self
+            .workspace
+            .as_ref()
+            .map(Arc::clone)
+            .ok_or(Error::other("No workspace loaded!"))?;
+
+        // `notify` runs on both async and blocking threads, so the repaint is
+        // requested from a task rather than by blocking on the lock.
+        let frame = Arc::clone(&self.frame);
+        let notify = move || {
+            let frame = Arc::clone(&frame);
+            tokio::spawn(async move { frame.lock().await.request_repaint() });
+        };
+
+        let atlas = Arc::clone(&self.atlas);
+        let (handle, job) = batch::analyse_batch(ws, atlas, ids, worker_limit, notify);
+        self.dispatch_exclusive(ThreadLabel::AnalyseBatch, true, job);
+        Ok(handle)
+    }
+
     pub fn dispatch<F>(&self, repaint: bool, f: F)
     where
         F: Future<Output = ()> + Send + 'static,
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::utility::imops::label_colour;
+
+/// One structure of the atlas hierarchy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Structure {
+    pub id: u64,
+    pub acronym: String,
+    pub name: String,
+    pub parent: Option<u64>,
+    /// Display colour from the structure table, if it has one.
+    pub colour: Option<[u8; 3]>,
+}
+
+/// The atlas structure hierarchy, keyed by the ids used in the annotation volume.
+#[derive(Clone, Debug, Default)]
+pub struct Ontology {
+    structures: HashMap<u64, Structure>,
+    children: HashMap<u64, Vec<u64>>,
+    acronyms: HashMap<String, u64>,
+}
+
+impl Ontology {
+    pub fn new(structures: Vec<Structure>) -> Self {
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        structures.iter().for_each(|s| {
+            if let Some(p) = s.parent {
+                children.entry(p).or_default().push(s.id);
+            }
+        });
+        children.values_mut().for_each(|c| c.sort_unstable());
+
+        Self {
+            acronyms: structures
+                .iter()
+                .map(|s| (s.acronym.clone(), s.id))
+                .collect(),
+            structures: structures.into_iter().map(|s| (s.id, s)).collect(),
+            children,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.structures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.structures.is_empty()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Structure> {
+        self.structures.get(&id)
+    }
+
+    pub fn by_acronym(&self, acronym: &str) -> Option<&Structure> {
+        self.acronyms.get(acronym).and_then(|&id| self.get(id))
+    }
+
+    /// Display colour of `id`: the table's, or one picked from the id.
+    pub fn colour(&self, id: u64) -> [u8; 3] {
+        self.get(id)
+            .and_then(|s| s.colour)
+            .unwrap_or_else(|| label_colour(id as u32))
+    }
+
+    /// Structures whose acronym or name contains `query`, ignoring case;
+    /// exact acronym matches first, then shallowest first.
+    pub fn search(&self, query: &str) -> Vec<&Structure> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return vec![];
+        }
+
+        let mut found = self
+            .structures
+            .values()
+            .filter(|s| {
+                s.acronym.to_lowercase().contains(&query) || s.name.to_lowercase().contains(&query)
+            })
+            .collect::<Vec<&Structure>>();
+        found.sort_by_cached_key(|s| {
+            (
+                s.acronym.to_lowercase() != query,
+                self.depth(s.id),
+                s.acronym.clone(),
+            )
+        });
+        found
+    }
+
+    /// Ids from the parent of `id` up to the root.
+    pub fn ancestors(&self, id: u64) -> Vec<u64> {
+        let mut out = vec![];
+        let mut next = self.get(id).and_then(|s| s.parent);
+        while let Some(p) = next {
+            // guard against cycles in a malformed table
+            if out.contains(&p) || p == id {
+                break;
+            }
+            out.push(p);
+            next = self.get(p).and_then(|s| s.parent);
+        }
+        out
+    }
+
+    pub fn children(&self, id: u64) -> &[u64] {
+        self.children.get(&id).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Ids of every structure below `id`, breadth first.
+    pub fn descendants(&self, id: u64) -> Vec<u64> {
+        let mut seen = HashSet::from([id]);
+        let mut out = vec![];
+        let mut i = 0;
+        let mut next = id;
+        loop {
+            self.children(next).iter().for_each(|&c| {
+                if seen.insert(c) {
+                    out.push(c);
+                }
+            });
+            let Some(&n) = out.get(i) else {
+                break;
+            };
+            next = n;
+            i += 1;
+        }
+        out
+    }
+
+    /// Steps from `id` up to its root; roots have depth 0.
+    pub fn depth(&self, id: u64) -> usize {
+        self.ancestors(id).len()
+    }
+}
@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+
+use imageproc::geometric_transformations::Interpolation;
+
+use crate::algorithm::deformable::BSplineField;
+use crate::algorithm::landmarks::{Landmarks, Point, ThinPlate};
+use crate::algorithm::proc::{warp_labels, warp_slice};
+use crate::model::atlas::{Atlas, Orientation};
+use crate::utility::types::Matrix;
+
+pub const IDENTITY: [f32; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+
+/// Corrections `Registration::forward` makes before giving up.
+const MAX_FORWARD_STEPS: usize = 50;
+
+/// Where a section sits in the atlas and how the atlas slice maps onto it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub orientation: Orientation,
+    pub slice_index: usize,
+    /// Cutting angles in degrees of the atlas slice off the orientation's
+    /// plane; see `Atlas::get_oblique_reference_img`.
+    #[serde(default)]
+    pub pitch: f32,
+    #[serde(default)]
+    pub yaw: f32,
+    /// Row-major 3x3 matrix taking atlas slice pixels `(x, y)` to
+    /// full-resolution section pixels `(x, y)`.
+    pub transform: [f32; 9],
+    /// Control points the transform was fitted from, if any.
+    #[serde(default)]
+    pub landmarks: Option<Landmarks>,
+    /// Non-rigid refinement displacing full-resolution section pixels before
+    /// they are mapped back through `transform`.
+    #[serde(default)]
+    pub deformation: Option<BSplineField>,
+    /// The atlas midline carried into the section through `inverse`, as a
+    /// polyline of full-resolution section pixels `(x, y)`; empty when the
+    /// midline misses the slice. Set by `set_midline`.
+    #[serde(default)]
+    pub midline: Vec<Point>,
+}
+
+/// Section-to-atlas mapping of a registration.
+pub enum InverseMap {
+    Affine([f32; 9]),
+    ThinPlate(ThinPlate),
+    Deformed(Box<InverseMap>, BSplineField),
+}
+
+impl InverseMap {
+    pub fn apply(&self, pt: Point) -> Point {
+        match self {
+            Self::Affine(t) => project(t, pt),
+            Self::ThinPlate(tps) => tps.apply(pt),
+            Self::Deformed(map, field) => map.apply(field.displace(pt)),
+        }
+    }
+}
+
+impl Registration {
+    /// Checks the slice exists in `atlas`, which may not be the atlas the
+    /// registration was made against.
+    pub fn validate(&self, atlas: &Atlas) -> Result<(), String> {
+        let n = atlas.n_slices(self.orientation);
+        if self.slice_index >= n {
+            return Err(format!(
+                "registered to {} slice {} but the atlas has {}",
+                self.orientation.to_str(),
+                self.slice_index,
+                n
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lifts a transform fitted between two working images to the stored frame.
+    /// The slice is taken uncut; set `pitch` and `yaw` for an oblique one.
+    ///
+    /// `fit` maps the atlas slice resized to `fitted` (width, height) onto the
+    /// section downsampled to the same size; `slice` is the atlas slice's
+    /// (width, height) and `section` the full-resolution (width, height).
+    pub fn from_fit(
+        orientation: Orientation,
+        slice_index: usize,
+        fit: [f32; 9],
+        slice: (usize, usize),
+        fitted: (usize, usize),
+        section: (usize, usize),
+    ) -> Self {
+        let to_fitted = scale(
+            fitted.0 as f32 / slice.0 as f32,
+            fitted.1 as f32 / slice.1 as f32,
+        );
+        let to_full = scale(
+            section.0 as f32 / fitted.0 as f32,
+            section.1 as f32 / fitted.1 as f32,
+        );
+
+        Self {
+            orientation,
+            slice_index,
+            pitch: 0.0,
+            yaw: 0.0,
+            transform: mat3_mul(&to_full, &mat3_mul(&fit, &to_fitted)),
+            landmarks: None,
+            deformation: None,
+            midline: vec![],
+        }
+    }
+
+    /// Inverse of `from_fit`: the transform between the working images.
+    pub fn to_fit(
+        &self,
+        slice: (usize, usize),
+        fitted: (usize, usize),
+        section: (usize, usize),
+    ) -> Option<[f32; 9]> {
+        let from_fitted = scale(
+            slice.0 as f32 / fitted.0 as f32,
+            slice.1 as f32 / fitted.1 as f32,
+        );
+        let from_full = scale(
+            fitted.0 as f32 / section.0 as f32,
+            fitted.1 as f32 / section.1 as f32,
+        );
+
+        let t = mat3_mul(&from_full, &mat3_mul(&self.transform, &from_fitted));
+        t.iter().all(|a| a.is_finite()).then_some(t)
+    }
+
+    /// Maps section pixels back into the atlas slice: through a spline fitted
+    /// the other way when the landmarks ask for one, otherwise by inverting
+    /// `transform`, after any deformation.
+    pub fn inverse(&self) -> Option<InverseMap> {
+        let map = match &self.landmarks {
+            Some(lm) if lm.thin_plate => {
+                ThinPlate::fit(&lm.section, &lm.atlas).map(InverseMap::ThinPlate)
+            }
+            _ => mat3_inv(&self.transform).map(InverseMap::Affine),
+        }?;
+
+        Some(match &self.deformation {
+            Some(field) => InverseMap::Deformed(Box::new(map), field.clone()),
+            None => map,
+        })
+    }
+
+    /// Resamples an atlas slice, reference or annotation, onto a `canvas`
+    /// (width, height) covering the whole section of full-resolution size
+    /// `section`. Use nearest-neighbour interpolation for annotation labels.
+    pub fn resample(
+        &self,
+        slice: &Matrix<u16>,
+        section: (usize, usize),
+        canvas: (usize, usize),
+        interpolation: Interpolation,
+    ) -> Option<Matrix<u16>> {
+        let inverse = self.inverse()?;
+        let (fx, fy) = (
+            section.0 as f32 / canvas.0 as f32,
+            section.1 as f32 / canvas.1 as f32,
+        );
+        let mapping = |x: f32, y: f32| inverse.apply((x * fx, y * fy));
+        Some(warp_slice(slice, mapping, canvas, interpolation))
+    }
+
+    /// Like `resample` for labels of any type, by nearest neighbour.
+    pub fn resample_labels<T: Copy + Default>(
+        &self,
+        labels: &Matrix<T>,
+        section: (usize, usize),
+        canvas: (usize, usize),
+    ) -> Option<Matrix<T>> {
+        let inverse = self.inverse()?;
+        let (fx, fy) = (
+            section.0 as f32 / canvas.0 as f32,
+            section.1 as f32 / canvas.1 as f32,
+        );
+        let mapping = |x: f32, y: f32| inverse.apply((x * fx, y * fy));
+        Some(warp_labels(labels, mapping, canvas))
+    }
+
+    pub fn apply(&self, pt: Point) -> Point {
+        project(&self.transform, pt)
+    }
+
+    /// The section pixel `inverse` takes to atlas slice pixel `pt`, found by
+    /// correcting the affine estimate until it maps within a hundredth of a
+    /// pixel. `None` where the warp does not converge, as outside a thin-plate
+    /// spline's fold-free range.
+    pub fn forward(&self, inverse: &InverseMap, pt: Point) -> Option<Point> {
+        // Jacobian of the affine part at `pt`, steering the corrections
+        let at = self.apply(pt);
+        let (dx, dy) = (
+            self.apply((pt.0 + 1.0, pt.1)),
+            self.apply((pt.0, pt.1 + 1.0)),
+        );
+        let j = [dx.0 - at.0, dy.0 - at.0, dx.1 - at.1, dy.1 - at.1];
+
+        let mut q = at;
+        for _ in 0..MAX_FORWARD_STEPS {
+            let back = inverse.apply(q);
+            let (ex, ey) = (pt.0 - back.0, pt.1 - back.1);
+            if !(ex.is_finite() && ey.is_finite()) {
+                return None;
+            }
+            if ex.hypot(ey) < 1e-2 {
+                return Some(q);
+            }
+            q = (q.0 + j[0] * ex + j[1] * ey, q.1 + j[2] * ex + j[3] * ey);
+        }
+        None
+    }
+
+    /// Maps the atlas midline `ends`, in slice pixels `(x, y)` as given by
+    /// `Atlas::get_midline`, into the section, sampling about once per slice
+    /// pixel so non-affine warps bend it.
+    pub fn section_midline(&self, ends: [Point; 2]) -> Vec<Point> {
+        let Some(inverse) = self.inverse() else {
+            return vec![];
+        };
+        let [(x0, y0), (x1, y1)] = ends;
+        let n = ((x1 - x0).hypot(y1 - y0).ceil() as usize).max(1);
+        (0..=n)
+            .filter_map(|i| {
+                let t = i as f32 / n as f32;
+                self.forward(&inverse, (x0 + t * (x1 - x0), y0 + t * (y1 - y0)))
+            })
+            .collect()
+    }
+
+    /// Stores where `atlas`'s midline crosses the registered slice.
+    pub fn set_midline(&mut self, atlas: &Atlas) {
+        self.midline = atlas
+            .get_midline(
+                self.orientation,
+                self.slice_index as isize,
+                self.pitch,
+                self.yaw,
+            )
+            .map_or(vec![], |ends| self.section_midline(ends));
+    }
+}
+
+pub fn project(t: &[f32; 9], (x, y): Point) -> Point {
+    let w = t[6] * x + t[7] * y + t[8];
+    (
+        (t[0] * x + t[1] * y + t[2]) / w,
+        (t[3] * x + t[4] * y + t[5]) / w,
+    )
+}
+
+pub fn scale(sx: f32, sy: f32) -> [f32; 9] {
+    [sx, 0.0, 0.0, 0.0, sy, 0.0, 0.0, 0.0, 1.0]
+}
+
+pub fn mat3_mul(a: &[f32; 9], b: &[f32; 9]) -> [f32; 9] {
+    std::array::from_fn(|i| {
+        let (r, c) = (i / 3, i % 3);
+        (0..3).map(|k| a[3 * r + k] * b[3 * k + c]).sum()
+    })
+}
+
+pub fn mat3_inv(m: &[f32; 9]) -> Option<[f32; 9]> {
+    let m = m.map(|a| a as f64);
+    let cof = |r0: usize, r1: usize, c0: usize, c1: usize| {
+        m[3 * r0 + c0] * m[3 * r1 + c1] - m[3 * r0 + c1] * m[3 * r1 + c0]
+    };
+
+    // adjugate, transposed cofactors
+    let adj = [
+        cof(1, 2, 1, 2),
+        -cof(0, 2, 1, 2),
+        cof(0, 1, 1, 2),
+        -cof(1, 2, 0, 2),
+        cof(0, 2, 0, 2),
+        -cof(0, 1, 0, 2),
+        cof(1, 2, 0, 1),
+        -cof(0, 2, 0, 1),
+        cof(0, 1, 0, 1),
+    ];
+    let det = m[0] * adj[0] + m[1] * adj[3] + m[2] * adj[6];
+
+    (det.abs() > 1e-12).then(|| adj.map(|a| (a / det) as f32))
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utility::types::Settings;
+
+/// Bumped whenever the serialized layout of `SettingsStore` or `Settings` changes.
+pub const SETTINGS_VERSION: u32 = 3;
+
+pub const DEFAULT_PRESET: &str = "Iba1/CD68 mouse cortex 20x";
+
+/// Named analysis presets kept in a workspace, one of which is active.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SettingsStore {
+    pub version: u32,
+    pub active: String,
+    pub presets: BTreeMap<String, Settings>,
+}
+
+impl Default for SettingsStore {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            active: DEFAULT_PRESET.to_owned(),
+            presets: BTreeMap::from([(DEFAULT_PRESET.to_owned(), Settings::default())]),
+        }
+    }
+}
+
+impl SettingsStore {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version > SETTINGS_VERSION {
+            return Err(format!(
+                "settings version {} is newer than supported version {}",
+                self.version, SETTINGS_VERSION
+            ));
+        }
+        if !self.presets.contains_key(&self.active) {
+            return Err(format!("active preset \"{}\" does not exist", self.active));
+        }
+        self.presets.iter().try_for_each(|(name, s)| {
+            s.validate()
+                .map_err(|e| format!("preset \"{}\": {}", name, e))
+        })
+    }
+
+    /// Brings a store written by an older version up to `SETTINGS_VERSION`.
+    pub fn migrate(&mut self) {
+        // v2 added `intermediates` and v3 `tile_size`/`tile_overlap`, all of
+        // which deserialize to their defaults when missing
+        self.version = SETTINGS_VERSION;
+    }
+
+    pub fn active(&self) -> &Settings {
+        &self.presets[&self.active]
+    }
+
+    pub fn preset(&self, name: &str) -> Option<&Settings> {
+        self.presets.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(|k| k.as_str())
+    }
+
+    pub fn set_active(&mut self, name: &str) -> Result<(), String> {
+        if !self.presets.contains_key(name) {
+            return Err(format!("no preset named \"{}\"", name));
+        }
+        self.active = name.to_owned();
+        Ok(())
+    }
+
+    pub fn save_preset(&mut self, name: &str, settings: Settings) -> Result<(), String> {
+        if name.trim().is_empty() {
+            return Err("preset name must not be empty".into());
+        }
+        settings.validate()?;
+        self.presets.insert(name.to_owned(), settings);
+        Ok(())
+    }
+
+    pub fn remove_preset(&mut self, name: &str) -> Result<Settings, String> {
+        if name == self.active {
+            return Err(format!("cannot remove the active preset \"{}\"", name));
+        }
+        self.presets
+            .remove(name)
+            .ok_or(format!("no preset named \"{}\"", name))
+    }
+}
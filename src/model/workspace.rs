@@ -1,14 +1,20 @@
-use std::{collections::HashMap, sync::Arc};
+use std::io::Error;
+use std::path::Path;
+use std::{collections::HashMap, fs};
 
-use eframe::egui::mutex::Mutex;
 use serde::{Deserialize, Serialize};
 
-use crate::model::ImageMetadata;
+use crate::model::{ImageMetadata, SettingsStore};
+use crate::utility::types::Settings;
+
+pub const WS_FILE: &str = "ws.json";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Workspace {
     pub dir_name: String,
     pub images: HashMap<String, ImageMetadata>,
+    #[serde(default)]
+    pub settings: SettingsStore,
 }
 
 impl Workspace {
@@ -16,6 +22,44 @@ impl Workspace {
         Workspace {
             images: HashMap::new(),
             dir_name,
+            settings: SettingsStore::default(),
         }
     }
+
+    pub fn from_dir(ws_dir: &Path) -> Result<Workspace, Error> {
+        let ws_s = fs::read_to_string(ws_dir.join(WS_FILE))
+            .map_err(|err| Error::new(err.kind(), format!("{}: {}", WS_FILE, err)))?;
+
+        let mut ws = serde_json::from_str::<Workspace>(&ws_s)
+            .map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+
+        ws.validate_settings()
+            .map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+        ws.settings.migrate();
+
+        Ok(ws)
+    }
+
+    fn validate_settings(&self) -> Result<(), String> {
+        self.settings.validate()?;
+        self.images.values().try_for_each(|img| {
+            img.settings_override
+                .as_ref()
+                .map_or(Ok(()), |s| s.validate())
+                .map_err(|e| format!("{}: {}", img.id(), e))
+        })
+    }
+
+    /// The settings analysis should use for `img`: its override if set,
+    /// otherwise the workspace's active preset.
+    pub fn settings_for(&self, img: &ImageMetadata) -> Settings {
+        img.settings_override
+            .clone()
+            .unwrap_or_else(|| self.settings.active().clone())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let ws_s = serde_json::to_string(self)?;
+        fs::write(Path::new(&self.dir_name).join(WS_FILE), ws_s)
+    }
 }
@@ -2,6 +2,7 @@ use crate::utility::types::{Matrix, Volume};
 use eframe::egui::{self, ColorImage};
 use image::{ImageBuffer, Luma, Primitive, Rgb};
 use imageproc::definitions::Image;
+use imageproc::geometric_transformations::Interpolation;
 use ndarray::{concatenate, prelude::*, Slice};
 
 pub fn volume_to_matrix_vec<T: Clone>(
@@ -78,9 +79,196 @@ pub fn get_slice<T: Clone>(vol: &Volume<T>, idx: isize, axis: usize) -> Matrix<T
         .to_owned()
 }
 
+/// The plane through slice `idx` of `axis` in a volume of `shape`, tilted by
+/// `pitch` and `yaw` degrees about its centre. Pitch turns the plane about its
+/// column axis, so rows move through the volume; yaw turns it about its row
+/// axis. Angles are physical given `voxel_size` along (page, row, column).
+///
+/// Returns the (rows, columns) of the axis-aligned slice and the volume point
+/// under each slice pixel (row, column).
+pub fn oblique_plane(
+    shape: &[usize],
+    idx: isize,
+    axis: usize,
+    (pitch, yaw): (f32, f32),
+    voxel_size: [f64; 3],
+) -> ((usize, usize), impl Fn(f32, f32) -> [f32; 3]) {
+    let (ra, ca) = match axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+    let (h, w) = (shape[ra], shape[ca]);
+    let (sp, cp) = (pitch.to_radians().sin(), pitch.to_radians().cos());
+    let (sy, cy) = (yaw.to_radians().sin(), yaw.to_radians().cos());
+    let v = voxel_size.map(|a| a as f32);
+    let centre = ((h as f32 - 1.0) / 2.0, (w as f32 - 1.0) / 2.0);
+
+    let point = move |r: f32, c: f32| {
+        let pr = (r - centre.0) * v[ra];
+        let pc = (c - centre.1) * v[ca];
+        let mut pt = [0.0f32; 3];
+        pt[axis] = idx as f32 + (pr * sp * cy + pc * sy) / v[axis];
+        pt[ra] = centre.0 + pr * cp / v[ra];
+        pt[ca] = centre.1 + (pc * cy - pr * sp * sy) / v[ca];
+        pt
+    };
+    ((h, w), point)
+}
+
+/// Samples the `oblique_plane` through `vol`. Points outside the volume are 0;
+/// `Nearest` keeps label values, anything else is trilinear.
+pub fn get_oblique_slice(
+    vol: &Volume<u16>,
+    idx: isize,
+    axis: usize,
+    angles: (f32, f32),
+    voxel_size: [f64; 3],
+    interpolation: Interpolation,
+) -> Matrix<u16> {
+    let (dim, point) = oblique_plane(vol.shape(), idx, axis, angles, voxel_size);
+    Matrix::from_shape_fn(dim, |(r, c)| {
+        let pt = point(r as f32, c as f32);
+        match interpolation {
+            Interpolation::Nearest => sample_nearest(vol, pt),
+            _ => sample_trilinear(vol, pt),
+        }
+    })
+}
+
+/// Samples the `oblique_plane` through a label volume by nearest neighbour,
+/// points outside the volume being 0.
+pub fn get_oblique_labels<T: Copy + Default>(
+    vol: &Volume<T>,
+    idx: isize,
+    axis: usize,
+    angles: (f32, f32),
+    voxel_size: [f64; 3],
+) -> Matrix<T> {
+    let (dim, point) = oblique_plane(vol.shape(), idx, axis, angles, voxel_size);
+    Matrix::from_shape_fn(dim, |(r, c)| sample_nearest(vol, point(r as f32, c as f32)))
+}
+
+fn sample_nearest<T: Copy + Default>(vol: &Volume<T>, pt: [f32; 3]) -> T {
+    let [a, b, c] = pt.map(|p| p.round());
+    if a < 0.0 || b < 0.0 || c < 0.0 {
+        return T::default();
+    }
+    vol.get((a as usize, b as usize, c as usize))
+        .copied()
+        .unwrap_or_default()
+}
+
+fn sample_trilinear(vol: &Volume<u16>, pt: [f32; 3]) -> u16 {
+    let dims = vol.shape();
+    if (0..3).any(|i| pt[i] < 0.0 || pt[i] > (dims[i] - 1) as f32) {
+        return 0;
+    }
+    let lo = pt.map(|p| p.floor() as usize);
+    let t = std::array::from_fn::<f32, 3, _>(|i| pt[i] - lo[i] as f32);
+    let hi = std::array::from_fn::<usize, 3, _>(|i| (lo[i] + 1).min(dims[i] - 1));
+
+    let mut acc = 0.0;
+    for corner in 0..8 {
+        let (mut weight, mut at) = (1.0, [0usize; 3]);
+        for i in 0..3 {
+            if corner >> i & 1 == 1 {
+                weight *= t[i];
+                at[i] = hi[i];
+            } else {
+                weight *= 1.0 - t[i];
+                at[i] = lo[i];
+            }
+        }
+        if weight > 0.0 {
+            acc += weight * vol[at] as f32;
+        }
+    }
+    acc.round() as u16
+}
+
 pub fn egui_image_from_mat(mat: Matrix<u16>) -> ColorImage {
     let im = array2buff(mat.map(|&p| std::cmp::min(p, 255) as u8));
     let (h, w) = mat.dim();
     let pixels = im.as_flat_samples();
     egui::ColorImage::from_gray([w, h], pixels.as_slice())
 }
+
+/// A deterministic, well-spread colour for a label id (golden-ratio hue steps).
+pub fn label_colour(label: u32) -> [u8; 3] {
+    let h = (label as f32 * 0.618_034).fract() * 6.0;
+    let (s, v) = (0.75, 0.95);
+    let c = v * s;
+    let x = c * (1.0 - ((h % 2.0) - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [r, g, b].map(|a| ((a + m) * 255.0) as u8)
+}
+
+/// Turns a mask into a translucent overlay, sampling every `df`th pixel.
+/// Non-zero pixels take `colour`, or a per-label colour when `colour` is `None`.
+pub fn egui_overlay_from_mat(mat: &Matrix<u32>, df: usize, colour: Option<[u8; 3]>) -> ColorImage {
+    let small = mat.slice(s![..;df, ..;df]);
+    let (h, w) = small.dim();
+    let rgba = small
+        .iter()
+        .flat_map(|&a| match (a, colour) {
+            (0, _) => [0, 0, 0, 0],
+            (_, Some([r, g, b])) => [r, g, b, 160],
+            (l, None) => {
+                let [r, g, b] = label_colour(l);
+                [r, g, b, 110]
+            }
+        })
+        .collect::<Vec<u8>>();
+    ColorImage::from_rgba_unmultiplied([w, h], &rgba)
+}
+
+/// Turns an annotation slice into a translucent overlay with each structure
+/// in `colour(label)`, outlining where labels change. Label 0 is transparent.
+pub fn egui_annotation_from_mat(
+    labels: &Matrix<u32>,
+    colour: impl Fn(u32) -> [u8; 3],
+) -> ColorImage {
+    let (h, w) = labels.dim();
+    let mut rgba = Vec::with_capacity(4 * h * w);
+    for ((r, c), &l) in labels.indexed_iter() {
+        let boundary = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|&(dr, dc)| {
+            let (nr, nc) = (r as isize + dr, c as isize + dc);
+            nr >= 0
+                && nc >= 0
+                && labels
+                    .get((nr as usize, nc as usize))
+                    .is_some_and(|&n| n != l)
+        });
+        let px = match (l, boundary) {
+            (0, false) => [0, 0, 0, 0],
+            (_, true) => [255, 255, 255, 220],
+            (l, false) => {
+                let [r, g, b] = colour(l);
+                [r, g, b, 90]
+            }
+        };
+        rgba.extend(px);
+    }
+    ColorImage::from_rgba_unmultiplied([w, h], &rgba)
+}
+
+/// Turns an intensity image into a single-colour overlay whose opacity
+/// follows the intensity.
+pub fn egui_tint_from_mat(mat: &Matrix<u16>, colour: [u8; 3]) -> ColorImage {
+    let (h, w) = mat.dim();
+    let [r, g, b] = colour;
+    let rgba = mat
+        .iter()
+        .flat_map(|&a| [r, g, b, (a.min(255) as f32 * 0.6) as u8])
+        .collect::<Vec<u8>>();
+    ColorImage::from_rgba_unmultiplied([w, h], &rgba)
+}
@@ -1,15 +1,25 @@
+use crate::algorithm::anatomy::StructureResults;
 use crate::utility::{
-    imops::{array2buff, array2rgb_buff, stack_rgb, volume_to_matrix_vec},
-    types::{Matrix, TiffInfo, TiffType, ROI},
+    imops::{array2buff, array2rgb_buff, stack_rgb},
+    ome::{micrometres_per, OmeXml},
+    tile_cache::{self, TileKey},
+    types::{
+        AcquisitionMetadata, CellRecord, Matrix, PixelMatrix, Pnt, ResolutionLevel, Results,
+        TiffInfo, TiffLayout, ROI,
+    },
 };
 
 use eframe::egui;
 use image::open;
 use itertools::Itertools;
 use ndarray::prelude::*;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 use tiff::{
     decoder::{Decoder, DecodingResult},
-    TiffError,
+    encoder::{colortype, TiffEncoder},
+    tags::{SampleFormat, Tag},
+    TiffError, TiffUnsupportedError,
 };
 
 pub fn read_as_channels(file_name: &str) -> Vec<Matrix<f64>> {
@@ -29,23 +39,72 @@ pub fn read_as_channels(file_name: &str) -> Vec<Matrix<f64>> {
         .collect()
 }
 
+/// Reads `bbox` of every channel `df` times smaller, from the coarsest
+/// pyramid level that is still at least as fine as `df`. Levels that do not
+/// divide `df` are read whole and sampled at the nearest pixels. `bbox` is
+/// clipped to the image, and samples keep the type they are stored as.
 pub fn read_tiff_region(
     file_name: &str,
     bbox: ROI,
     df: usize,
-) -> Result<Vec<Matrix<u16>>, TiffError> {
-    let h = std::fs::File::open(file_name)?;
-    let mut tr = Decoder::new(h).unwrap();
-    tiff_type(&mut tr).and_then(|tp| read_as_multi_panel(&mut tr, &tp, bbox, df))
+) -> Result<Vec<PixelMatrix>, TiffError> {
+    let layout = tiff_layout(file_name)?;
+    read_region(file_name, &layout, bbox, df)
+}
+
+fn read_region(
+    file_name: &str,
+    layout: &TiffLayout,
+    bbox: ROI,
+    df: usize,
+) -> Result<Vec<PixelMatrix>, TiffError> {
+    let df = df.max(1);
+    let bbox = level_roi(bbox, 1, layout.levels[0].dimensions);
+    let (level, factor) = layout.level_for(df);
+    let level = &layout.levels[level];
+    let level_bbox = level_roi(bbox, factor, level.dimensions);
+    if df.is_multiple_of(factor) {
+        return read_level_pixels(file_name, layout, level, level_bbox, df / factor);
+    }
+
+    let (r, c, h, w) = bbox;
+    let (lr, lc, _, _) = level_bbox;
+    let ims = read_level_pixels(file_name, layout, level, level_bbox, 1)?;
+    Ok(ims
+        .iter()
+        .map(|im| {
+            let (lh, lw) = im.dim();
+            im.sample((h.div_ceil(df), w.div_ceil(df)), |(i, j)| {
+                let y = ((r + i * df) / factor).saturating_sub(lr);
+                let x = ((c + j * df) / factor).saturating_sub(lc);
+                (y.min(lh.saturating_sub(1)), x.min(lw.saturating_sub(1)))
+            })
+        })
+        .collect())
+}
+
+/// `bbox` in full-resolution pixels as pixels of a level `factor` times
+/// smaller of `dimensions` (width, height).
+fn level_roi((r, c, h, w): ROI, factor: usize, (lw, lh): Pnt) -> ROI {
+    let (r0, c0) = ((r / factor).min(lh), (c / factor).min(lw));
+    let r1 = (r + h).div_ceil(factor).clamp(r0, lh);
+    let c1 = (c + w).div_ceil(factor).clamp(c0, lw);
+    (r0, c0, r1 - r0, c1 - c0)
 }
 
+/// The first three channels of `bbox` as RGB, each scaled to 8 bits by
+/// `PixelMatrix::to_display_u8`.
 pub fn egui_image_from_path(
     path: &str,
     bbox: ROI,
     df: usize,
 ) -> Result<egui::ColorImage, TiffError> {
-    let image = read_tiff_region(path, bbox, df)?;
-    let image: Vec<Matrix<u8>> = image.iter().map(|i| i.map(|a| *a as u8)).collect();
+    let layout = tiff_layout(path)?;
+    let image = read_region(path, &layout, bbox, df)?;
+    let image: Vec<Matrix<u8>> = image
+        .iter()
+        .map(|i| i.to_display_u8(layout.significant_bits))
+        .collect();
     let rgb = stack_rgb(&image[0], &image[1], &image[2]);
     let im = array2rgb_buff(rgb);
     let (h, w) = image[0].dim();
@@ -54,144 +113,487 @@ pub fn egui_image_from_path(
     Ok(egui::ColorImage::from_rgb([w, h], pixels.as_slice()))
 }
 
-fn read_as_multi_panel(
-    tr: &mut Decoder<std::fs::File>,
-    tp: &TiffType,
+/// Reads a level with the sample type its sample format and bit depth
+/// decode to.
+fn read_level_pixels(
+    file_name: &str,
+    layout: &TiffLayout,
+    level: &ResolutionLevel,
     bbox: ROI,
     df: usize,
-) -> Result<Vec<Matrix<u16>>, TiffError> {
-    let (_, _, w, h) = bbox;
-    match tp {
-        TiffType::SinglePanel { bps, cc } => {
-            let img = get_pixels(tr, tp, bbox, df)?;
-            let im_vol = Array3::from_shape_vec((w.div_ceil(df), h.div_ceil(df), *cc), img)
-                .expect("Array failure");
-            Ok(volume_to_matrix_vec(&im_vol, (2, 0, 1)))
-        }
-        TiffType::MultiPanel { bps, spp, cc } => Ok((0..*cc)
-            .map(|a| {
-                if a > 0 {
-                    let _ = tr.next_image();
-                }
-                let img = get_pixels(tr, tp, bbox, df).unwrap();
-                Array2::from_shape_vec((w.div_ceil(df), h.div_ceil(df)), img)
-                    .expect("Array failure")
-            })
-            .collect()),
+) -> Result<Vec<PixelMatrix>, TiffError> {
+    use SampleFormat::{Int, IEEEFP};
+
+    let args = (file_name, level, bbox, df);
+    match (layout.sample_format, layout.bits_per_sample) {
+        (IEEEFP, 64) => read_level_region::<f64>(args),
+        (IEEEFP, _) => read_level_region::<f32>(args),
+        (Int, 8) => read_level_region::<i8>(args),
+        (Int, 16) => read_level_region::<i16>(args),
+        (Int, 32) => read_level_region::<i32>(args),
+        (Int, _) => read_level_region::<i64>(args),
+        (_, 16) => read_level_region::<u16>(args),
+        (_, 32) => read_level_region::<u32>(args),
+        (_, 64) => read_level_region::<u64>(args),
+        _ => read_level_region::<u8>(args),
     }
 }
 
-fn get_pixels(
-    tr: &mut Decoder<std::fs::File>,
-    tp: &TiffType,
+/// Every sample of every page of the level, in page order.
+fn read_level_region<T: Sample>(
+    (file_name, level, bbox, df): (&str, &ResolutionLevel, ROI, usize),
+) -> Result<Vec<PixelMatrix>, TiffError> {
+    let mut channels = vec![];
+    for &page in &level.pages {
+        let mut tr = open_page(file_name, page)?;
+        channels.extend(
+            get_pixels::<T>(&mut tr, (file_name, page), bbox, df)?
+                .into_iter()
+                .map(T::into_pixels),
+        );
+    }
+    Ok(channels)
+}
+
+/// Every `df`-th row and column of `bbox` for each sample of the page at IFD
+/// offset `page.1` of file `page.0`, decoding only the strips or tiles holding
+/// them, through the tile cache. Samples stored as separate planes
+/// (PlanarConfiguration 2) are read from their own chunks, and pixels of
+/// `bbox` outside the page are left at zero.
+fn get_pixels<T: Sample>(
+    tr: &mut Decoder<PageReader>,
+    page: (&str, u64),
     (r, c, h, w): ROI,
     df: usize,
-) -> Result<Vec<u16>, TiffError> {
+) -> Result<Vec<Matrix<T>>, TiffError> {
+    let (width, height) = tr.dimensions()?;
+    let (width, height) = (width as usize, height as usize);
+    let spp = tr
+        .find_tag_unsigned::<u16>(Tag::SamplesPerPixel)?
+        .unwrap_or(1) as usize;
+    let planar = spp > 1 && tr.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)? == Some(2);
+    let (planes, chunk_spp) = if planar { (spp, 1) } else { (1, spp) };
     let (cw, ch) = tr.chunk_dimensions();
-    let spp = match tp {
-        TiffType::SinglePanel { bps, cc } => cc,
-        TiffType::MultiPanel { bps, spp, cc } => spp,
-    };
+    let (cw, ch) = (cw as usize, ch as usize);
+    let (across, down) = (width.div_ceil(cw), height.div_ceil(ch));
 
-    let start_idx = r as u32 / ch;
-    let end_idx = (r + h) as u32 / ch;
+    let mut out = vec![Matrix::from_elem((h.div_ceil(df), w.div_ceil(df)), T::default()); spp];
+    let (r1, c1) = ((r + h).min(height), (c + w).min(width));
+    if r1 <= r || c1 <= c {
+        return Ok(out);
+    }
+    // Output indices of the sampled pixels in [lo, hi) along one axis.
+    let sampled = |start: usize, lo: usize, hi: usize| {
+        lo.saturating_sub(start).div_ceil(df)..hi.saturating_sub(start).div_ceil(df)
+    };
 
-    if start_idx == end_idx {
-        let chunk = read_chunk(tr, start_idx)?;
-        Ok(chunk
-            .chunks_exact(cw as usize)
-            .map(|a| {
-                let idx = c * spp..(c + w) * spp;
-                a[idx].iter().step_by(2).cloned().collect_vec()
-            })
-            .collect::<Vec<Vec<u16>>>()
-            .concat())
-    } else {
-        Ok((start_idx..end_idx)
-            .flat_map(|idx| {
-                let ck = read_chunk(tr, idx).unwrap();
-                let row_idx = (idx * ch) as usize;
-                let lower_idx = std::cmp::max((idx * ch) as usize, r) - row_idx;
-                let upper_idx = std::cmp::min(((idx + 1) * ch) as usize, r + h) - row_idx;
-                ck.chunks_exact(cw as usize * spp)
-                    .skip(lower_idx)
-                    .take(upper_idx - lower_idx)
-                    .map(|a| &a[c * spp..(c + w) * spp])
-                    .map(|a| {
-                        a.chunks_exact(*spp)
-                            .into_iter()
-                            .step_by(df)
-                            .collect::<Vec<&[u16]>>()
-                            .concat()
-                    })
-                    .collect::<Vec<Vec<u16>>>()
-            })
-            .step_by(df)
-            .collect::<Vec<Vec<u16>>>()
-            .concat())
+    for plane in 0..planes {
+        for tile_row in r / ch..r1.div_ceil(ch) {
+            let rows = sampled(r, tile_row * ch, ((tile_row + 1) * ch).min(r1));
+            if rows.is_empty() {
+                continue;
+            }
+            for tile_col in c / cw..c1.div_ceil(cw) {
+                let cols = sampled(c, tile_col * cw, ((tile_col + 1) * cw).min(c1));
+                if cols.is_empty() {
+                    continue;
+                }
+                let idx = (plane * down + tile_row) * across + tile_col;
+                let chunk = read_chunk::<T>(tr, page, idx as u32)?;
+                let stride = tr.chunk_data_dimensions(idx as u32).0 as usize * chunk_spp;
+                for i in rows.clone() {
+                    let y = r + i * df - tile_row * ch;
+                    for j in cols.clone() {
+                        let x = c + j * df - tile_col * cw;
+                        let px = &chunk[y * stride + x * chunk_spp..][..chunk_spp];
+                        for (s, &v) in px.iter().enumerate() {
+                            out[plane + s][(i, j)] = v;
+                        }
+                    }
+                }
+            }
+        }
     }
+    Ok(out)
 }
 
-fn image_count(tr: &mut Decoder<std::fs::File>) -> Result<usize, TiffError> {
-    tr.seek_to_image(0)?;
-    let mut num_images = 1;
-    while tr.more_images() {
-        num_images += 1;
+/// Classifies the pages of a TIFF or BigTIFF into channels and resolution
+/// levels.
+///
+/// With OME-XML the first plane of each channel is a channel page, in
+/// `DimensionOrder`. Otherwise every full-size page not flagged as reduced
+/// resolution by `NewSubfileType` is one. Levels come from the channel pages'
+/// SubIFDs when they all have them, else from smaller top-level pages of the
+/// same aspect ratio; other pages, like thumbnails of the slide label, are
+/// ignored.
+pub fn tiff_layout(file_name: &str) -> Result<TiffLayout, TiffError> {
+    let mut tr = Decoder::new(std::fs::File::open(file_name)?)?;
+    let bits_per_sample = tr.get_tag(Tag::BitsPerSample)?.into_u16_vec()?[0] as usize;
+    let samples_per_pixel = tr
+        .find_tag_unsigned::<u16>(Tag::SamplesPerPixel)?
+        .unwrap_or(1) as usize;
+    let sample_format = sample_format(&mut tr)?;
+    let ome = tr
+        .find_tag(Tag::ImageDescription)
+        .ok()
+        .flatten()
+        .and_then(|d| d.into_string().ok())
+        .and_then(|d| OmeXml::parse(&d));
+
+    let mut pages = vec![];
+    loop {
+        pages.push(PageInfo::read(&mut tr)?);
+        if !tr.more_images() {
+            break;
+        }
         tr.next_image()?;
     }
-    tr.seek_to_image(0)?;
-    return Ok(num_images);
+
+    let full = pages[0].dimensions;
+    let channels = match &ome {
+        Some(ome) => (0..ome.channel_pages(samples_per_pixel))
+            .filter_map(|c| pages.get(ome.plane_index(c)))
+            .collect::<Vec<&PageInfo>>(),
+        None => pages
+            .iter()
+            .filter(|p| !p.is_reduced() && p.dimensions == full)
+            .collect(),
+    };
+    let mut levels = vec![ResolutionLevel {
+        dimensions: full,
+        pages: channels.iter().map(|p| p.ifd).collect(),
+    }];
+
+    let n_sub = channels.iter().map(|p| p.sub_ifds.len()).min().unwrap_or(0);
+    if n_sub > 0 {
+        for k in 0..n_sub {
+            let pages = channels.iter().map(|p| p.sub_ifds[k]).collect::<Vec<u64>>();
+            let dims = open_page(file_name, pages[0])?.dimensions()?;
+            levels.push(ResolutionLevel {
+                dimensions: (dims.0 as usize, dims.1 as usize),
+                pages,
+            });
+        }
+    } else if ome.is_none() {
+        let aspect = |(w, h): Pnt| w as f64 / h.max(1) as f64;
+        let reduced = pages
+            .iter()
+            .filter(|p| p.dimensions.0 < full.0 && !p.is_mask())
+            .filter(|p| (aspect(p.dimensions) / aspect(full) - 1.0).abs() < 0.02)
+            .collect::<Vec<&PageInfo>>();
+        reduced
+            .iter()
+            .map(|p| p.dimensions)
+            .unique()
+            .for_each(|dimensions| {
+                let pages = reduced
+                    .iter()
+                    .filter(|p| p.dimensions == dimensions)
+                    .map(|p| p.ifd)
+                    .collect::<Vec<u64>>();
+                if pages.len() == channels.len() {
+                    levels.push(ResolutionLevel { dimensions, pages });
+                }
+            });
+    }
+    levels.sort_by_key(|l| std::cmp::Reverse(l.dimensions.0));
+    levels.dedup_by_key(|l| l.dimensions);
+
+    Ok(TiffLayout {
+        bigtiff: is_bigtiff(file_name)?,
+        bits_per_sample,
+        significant_bits: ome
+            .and_then(|o| o.significant_bits)
+            .filter(|&b| b > 0)
+            .map_or(bits_per_sample, |b| b.min(bits_per_sample)),
+        sample_format,
+        samples_per_pixel,
+        levels,
+    })
 }
 
-fn tiff_type(tr: &mut Decoder<std::fs::File>) -> Result<TiffType, TiffError> {
-    let bps = tr.get_tag(tiff::tags::Tag::BitsPerSample)?.into_u16_vec()?[0] as usize;
-    let spp = tr.get_tag(tiff::tags::Tag::SamplesPerPixel)?.into_u16()? as usize;
-    let panel_count = image_count(tr)?;
+/// Tags of one top-level page used by `tiff_layout`.
+struct PageInfo {
+    ifd: u64,
+    dimensions: Pnt,
+    subfile_type: u32,
+    sub_ifds: Vec<u64>,
+}
+
+impl PageInfo {
+    fn read(tr: &mut Decoder<std::fs::File>) -> Result<Self, TiffError> {
+        let (w, h) = tr.dimensions()?;
+        Ok(Self {
+            ifd: tr.ifd_pointer().map(|p| p.0).unwrap_or_default(),
+            dimensions: (w as usize, h as usize),
+            subfile_type: tr.find_tag_unsigned(Tag::NewSubfileType)?.unwrap_or(0),
+            sub_ifds: match tr.find_tag(Tag::SubIfd)? {
+                Some(v) => v.into_ifd_vec()?.into_iter().map(|p| p.0).collect(),
+                None => vec![],
+            },
+        })
+    }
 
-    // println!("Panel Count: {:?}", panel_count);
-    // println!("Samples: {:?}", spp);
+    /// `NewSubfileType` bit 0: a reduced-resolution copy of another page.
+    fn is_reduced(&self) -> bool {
+        self.subfile_type & 1 != 0
+    }
+
+    /// `NewSubfileType` bit 2: a transparency mask.
+    fn is_mask(&self) -> bool {
+        self.subfile_type & 4 != 0
+    }
+}
 
-    if panel_count == 1 {
-        Ok(TiffType::SinglePanel { bps, cc: spp })
-    } else {
-        Ok(TiffType::MultiPanel {
-            bps,
-            spp,
-            cc: panel_count,
+fn is_bigtiff(file_name: &str) -> Result<bool, TiffError> {
+    let mut header = [0u8; 4];
+    std::fs::File::open(file_name)?.read_exact(&mut header)?;
+    Ok(matches!(header, [b'I', b'I', 43, 0] | [b'M', b'M', 0, 43]))
+}
+
+/// Opens the page whose IFD is at `ifd`, which may be a SubIFD.
+fn open_page(file_name: &str, ifd: u64) -> Result<Decoder<PageReader>, TiffError> {
+    Decoder::new(PageReader::open(file_name, ifd)?)
+}
+
+/// A TIFF file read as if its first IFD were at `ifd`, letting the decoder
+/// open pages, such as SubIFDs, that its own IFD chain does not reach.
+struct PageReader {
+    file: std::fs::File,
+    header: Vec<u8>,
+    pos: u64,
+}
+
+impl PageReader {
+    fn open(file_name: &str, ifd: u64) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(file_name)?;
+        let mut header = vec![0u8; 4];
+        file.read_exact(&mut header)?;
+        let little = header[..2] == *b"II";
+        match header[2..4] {
+            [43, 0] | [0, 43] => {
+                // BigTIFF: offset size and padding, then an 8 byte offset
+                let mut rest = [0u8; 4];
+                file.read_exact(&mut rest)?;
+                header.extend(rest);
+                header.extend(if little {
+                    ifd.to_le_bytes()
+                } else {
+                    ifd.to_be_bytes()
+                });
+            }
+            _ => {
+                let ifd = u32::try_from(ifd).map_err(std::io::Error::other)?;
+                header.extend(if little {
+                    ifd.to_le_bytes()
+                } else {
+                    ifd.to_be_bytes()
+                });
+            }
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok(Self {
+            file,
+            header,
+            pos: 0,
         })
     }
 }
 
+impl Read for PageReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self.pos as usize;
+        if start < self.header.len() {
+            let n = buf.len().min(self.header.len() - start);
+            buf[..n].copy_from_slice(&self.header[start..start + n]);
+            self.pos = self.file.seek(SeekFrom::Start(self.pos + n as u64))?;
+            return Ok(n);
+        }
+        let n = self.file.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PageReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.file.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
 pub fn tiff_info(file_name: &str) -> Result<TiffInfo, TiffError> {
-    let h = std::fs::File::open(file_name).unwrap();
-    let mut tr = Decoder::new(h).unwrap();
-    let panel_type = tiff_type(&mut tr)?;
-    let dims = tr.dimensions()?;
+    let h = std::fs::File::open(file_name)?;
+    let mut tr = Decoder::new(h)?;
+    let layout = tiff_layout(file_name)?;
     Ok(TiffInfo {
-        dimensions: (dims.0 as usize, dims.1 as usize),
-        n_channels: match panel_type {
-            TiffType::SinglePanel { bps, cc } => cc,
-            TiffType::MultiPanel { bps, spp, cc } => cc,
-        },
+        dimensions: layout.levels[0].dimensions,
+        n_channels: layout.n_channels(),
+        metadata: acquisition_metadata(&mut tr, &layout),
+        levels: layout.levels.iter().map(|l| l.dimensions).collect(),
     })
 }
 
-fn read_chunk(tr: &mut Decoder<std::fs::File>, idx: u32) -> Result<Vec<u16>, TiffError> {
-    tr.read_chunk(idx).map(|chunk| match chunk {
-        DecodingResult::U16(v) => v,
-        DecodingResult::U8(v) => v.iter().map(|&a| a as u16).collect::<Vec<u16>>(),
-        DecodingResult::U32(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::U64(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::F16(v) => v.iter().map(|&a| a.to_f32() as u16).collect(),
-        DecodingResult::F32(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::F64(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::I8(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::I16(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::I32(v) => v.iter().map(|&a| a as u16).collect(),
-        DecodingResult::I64(v) => v.iter().map(|&a| a as u16).collect(),
+/// Acquisition metadata from the OME-XML description. Without it the pixel
+/// size comes from the resolution tags, in the unit of an ImageJ description
+/// if there is one, else in the ResolutionUnit.
+fn acquisition_metadata(
+    tr: &mut Decoder<std::fs::File>,
+    layout: &TiffLayout,
+) -> AcquisitionMetadata {
+    let description = tr
+        .find_tag(Tag::ImageDescription)
+        .ok()
+        .flatten()
+        .and_then(|d| d.into_string().ok())
+        .unwrap_or_default();
+    match OmeXml::parse(&description) {
+        Some(ome) => AcquisitionMetadata {
+            pixel_size: ome.physical_size.or_else(|| pixel_size(tr, None)),
+            channels: ome.channels,
+            objective: ome.objective,
+            bit_depth: layout.significant_bits,
+        },
+        None => AcquisitionMetadata {
+            pixel_size: pixel_size(tr, imagej_unit(&description)),
+            bit_depth: layout.significant_bits,
+            ..Default::default()
+        },
+    }
+}
+
+/// Micrometres per unit of the `unit=` entry of an ImageJ description.
+fn imagej_unit(description: &str) -> Option<f64> {
+    if !description.starts_with("ImageJ=") {
+        return None;
+    }
+    description
+        .lines()
+        .find_map(|l| l.strip_prefix("unit="))
+        .and_then(micrometres_per)
+}
+
+/// Micrometres per pixel along (x, y) from the resolution tags, which count
+/// pixels per `um_per_unit` micrometres, or per ResolutionUnit if not given.
+fn pixel_size(tr: &mut Decoder<std::fs::File>, um_per_unit: Option<f64>) -> Option<(f64, f64)> {
+    let um_per_unit = match um_per_unit {
+        Some(um) => um,
+        None => match tr.find_tag(Tag::ResolutionUnit).ok()??.into_u16().ok()? {
+            2 => 25_400.0,
+            3 => 10_000.0,
+            _ => return None,
+        },
+    };
+    let x = um_per_unit / resolution(tr, Tag::XResolution)?;
+    let y = resolution(tr, Tag::YResolution).map_or(x, |r| um_per_unit / r);
+    Some((x, y))
+}
+
+fn resolution(tr: &mut Decoder<std::fs::File>, tag: Tag) -> Option<f64> {
+    use tiff::decoder::ifd::Value;
+
+    let per_unit = match tr.find_tag(tag).ok()?? {
+        Value::Rational(n, d) if d > 0 => n as f64 / d as f64,
+        v => v.into_f64().ok()?,
+    };
+    (per_unit > 0.0).then_some(per_unit)
+}
+
+/// Chunk `idx` of the page at IFD offset `ifd` of `file_name`, decoded
+/// through the shared tile cache.
+fn read_chunk<T: Sample>(
+    tr: &mut Decoder<PageReader>,
+    (file_name, ifd): (&str, u64),
+    idx: u32,
+) -> Result<Arc<Vec<T>>, TiffError> {
+    let key = TileKey {
+        file: file_name.to_owned(),
+        ifd,
+        chunk: idx,
+    };
+    tile_cache::get_or_decode(key, || {
+        let chunk = tr.read_chunk(idx)?;
+        match T::from_chunk(chunk) {
+            Some(v) => Ok(v),
+            None => Err(TiffError::UnsupportedError(
+                TiffUnsupportedError::UnsupportedSampleFormat(vec![sample_format(tr)?]),
+            )),
+        }
     })
 }
 
+/// The page's SampleFormat, unsigned integer when absent.
+fn sample_format<R: Read + Seek>(tr: &mut Decoder<R>) -> Result<SampleFormat, TiffError> {
+    Ok(tr
+        .find_tag_unsigned_vec::<u16>(Tag::SampleFormat)?
+        .and_then(|v| v.first().copied())
+        .map_or(SampleFormat::Uint, SampleFormat::from_u16_exhaustive))
+}
+
+/// A sample type pages decode to, and the `PixelMatrix` variant holding it.
+trait Sample: Copy + Default + Send + Sync + 'static {
+    fn from_chunk(chunk: DecodingResult) -> Option<Vec<Self>>;
+    fn into_pixels(m: Matrix<Self>) -> PixelMatrix;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty => $variant:ident),*) => {
+        $(impl Sample for $t {
+            fn from_chunk(chunk: DecodingResult) -> Option<Vec<Self>> {
+                match chunk {
+                    DecodingResult::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+
+            fn into_pixels(m: Matrix<Self>) -> PixelMatrix {
+                PixelMatrix::$variant(m)
+            }
+        })*
+    };
+}
+
+impl_sample!(
+    u8 => U8, u16 => U16, u32 => U32, u64 => U64,
+    i8 => I8, i16 => I16, i32 => I32, i64 => I64,
+    f64 => F64
+);
+
+impl Sample for f32 {
+    fn from_chunk(chunk: DecodingResult) -> Option<Vec<Self>> {
+        match chunk {
+            DecodingResult::F32(v) => Some(v),
+            DecodingResult::F16(v) => Some(v.iter().map(|a| a.to_f32()).collect()),
+            _ => None,
+        }
+    }
+
+    fn into_pixels(m: Matrix<Self>) -> PixelMatrix {
+        PixelMatrix::F32(m)
+    }
+}
+
+/// Reads a single-channel mask or label image of 8, 16 or 32-bit samples,
+/// bypassing the tile cache since intermediates are rewritten by each run.
+pub fn read_mask(file_name: &str) -> Result<Matrix<u32>, TiffError> {
+    let file = std::fs::File::open(file_name)?;
+    let mut tr = Decoder::new(std::io::BufReader::new(file))?;
+    let (w, h) = tr.dimensions()?;
+    let colortype = tr.colortype()?;
+    let unsupported =
+        || TiffError::UnsupportedError(TiffUnsupportedError::UnsupportedColorType(colortype));
+    if !matches!(colortype, tiff::ColorType::Gray(_)) {
+        return Err(unsupported());
+    }
+    let px: Vec<u32> = match tr.read_image()? {
+        DecodingResult::U8(v) => v.into_iter().map(u32::from).collect(),
+        DecodingResult::U16(v) => v.into_iter().map(u32::from).collect(),
+        DecodingResult::U32(v) => v,
+        _ => return Err(unsupported()),
+    };
+    Array2::from_shape_vec((h as usize, w as usize), px).map_err(|_| unsupported())
+}
+
 pub fn save_as_luma8(arr: &Matrix<u32>, file_name: &str) {
     let img = array2buff(arr.map(|a| std::cmp::min(*a, 255) as u8));
     let luma = image::DynamicImage::ImageLuma8(img);
@@ -204,10 +606,19 @@ pub fn save_as_luma16(arr: &Matrix<u16>, file_name: &str) {
     let _ = luma.save(file_name);
 }
 
-pub fn save_as_binary(arr: &Matrix<bool>, file_name: &str) {
+/// Writes labels as a 32-bit greyscale TIFF, which `image` cannot encode.
+pub fn save_as_luma32(arr: &Matrix<u32>, file_name: &str) -> Result<(), TiffError> {
+    let (h, w) = arr.dim();
+    let file = std::fs::File::create(file_name)?;
+    let mut encoder = TiffEncoder::new(std::io::BufWriter::new(file))?;
+    let data = arr.iter().copied().collect::<Vec<u32>>();
+    encoder.write_image::<colortype::Gray32>(w as u32, h as u32, &data)
+}
+
+pub fn save_as_binary(arr: &Matrix<bool>, file_name: &str) -> image::ImageResult<()> {
     let img = array2buff(arr.map(|a| if *a { 255 } else { 0 }));
     let luma = image::DynamicImage::ImageLuma8(img);
-    let _ = luma.save(file_name);
+    luma.save(file_name)
 }
 
 pub fn save_as_rgb_bool(a: &Matrix<bool>, b: &Matrix<bool>, c: &Matrix<bool>, file_name: &str) {
@@ -216,3 +627,106 @@ pub fn save_as_rgb_bool(a: &Matrix<bool>, b: &Matrix<bool>, c: &Matrix<bool>, fi
     let composite_rgb = image::DynamicImage::ImageRgb8(composite_img);
     let _ = composite_rgb.save(file_name);
 }
+
+/// A CSV cell for a value that may be missing, blank when it is.
+fn optional(a: Option<f64>) -> String {
+    a.map(|a| a.to_string()).unwrap_or_default()
+}
+
+/// Writes one row per `(image id, region, results)`, with an empty region for
+/// whole-image results and blank averages where there are no cells.
+pub fn save_results_csv(
+    rows: &[(String, String, Results)],
+    file_name: &str,
+) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(file_name)?;
+    wtr.write_record([
+        "image_id",
+        "region",
+        "cell_count",
+        "average_rotundity",
+        "average_branch_length",
+        "average_scholl",
+        "percentage_cd68_area",
+        "percentage_cd68_num",
+    ])?;
+
+    for (id, region, r) in rows {
+        wtr.write_record([
+            id.to_owned(),
+            region.to_owned(),
+            r.cell_count.to_string(),
+            optional(r.average_rotundity),
+            optional(r.average_branch_length),
+            optional(r.average_scholl),
+            r.percentage_cd68_area.to_string(),
+            optional(r.percentage_cd68_num),
+        ])?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes one row per atlas structure, parents' totals including their children.
+pub fn save_structures_csv(rows: &[StructureResults], file_name: &str) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(file_name)?;
+    wtr.write_record([
+        "structure_id",
+        "acronym",
+        "name",
+        "parent_id",
+        "hemisphere",
+        "cell_count",
+        "area_px",
+        "area_mm2",
+        "density_per_mm2",
+        "average_rotundity",
+        "average_branch_length",
+        "average_scholl",
+        "percentage_cd68_num",
+    ])?;
+
+    for r in rows {
+        wtr.write_record([
+            r.id.to_string(),
+            r.acronym.to_owned(),
+            r.name.to_owned(),
+            r.parent.map(|p| p.to_string()).unwrap_or_default(),
+            r.hemisphere
+                .map(|h| h.to_str().to_owned())
+                .unwrap_or("Both".into()),
+            r.cell_count.to_string(),
+            r.area_px.to_string(),
+            optional(r.area_mm2),
+            optional(r.density_per_mm2),
+            optional(r.average_rotundity),
+            optional(r.average_branch_length),
+            optional(r.average_scholl),
+            optional(r.percentage_cd68_num),
+        ])?;
+    }
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes a polyline of full-resolution pixels `(x, y)`, one vertex per row.
+pub fn save_midline_csv(points: &[(f32, f32)], file_name: &str) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(file_name)?;
+    wtr.write_record(["x", "y"])?;
+    for (x, y) in points {
+        wtr.write_record([x.to_string(), y.to_string()])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn save_cells_csv(cells: &[CellRecord], file_name: &str) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(file_name)?;
+    for cell in cells {
+        wtr.serialize(cell)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
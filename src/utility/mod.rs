@@ -1,3 +1,5 @@
 pub mod imops;
 pub mod io;
+pub mod ome;
+pub mod tile_cache;
 pub mod types;
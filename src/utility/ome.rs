@@ -0,0 +1,179 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::utility::types::{ChannelInfo, ObjectiveInfo};
+
+/// The parts of an OME-XML image description used to lay out the TIFF's
+/// pages and describe the acquisition. Only the first image is read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OmeXml {
+    pub size_c: usize,
+    pub size_z: usize,
+    pub size_t: usize,
+    /// e.g. "XYCZT": planes are stored with the earlier dimensions varying fastest.
+    pub dimension_order: String,
+    /// Micrometres per pixel along (x, y).
+    pub physical_size: Option<(f64, f64)>,
+    pub significant_bits: Option<usize>,
+    pub channels: Vec<ChannelInfo>,
+    /// The objective referenced by the image, else the first one listed.
+    pub objective: Option<ObjectiveInfo>,
+}
+
+impl OmeXml {
+    /// Parses `xml`, returning `None` if it is not OME-XML or has no `Pixels`.
+    pub fn parse(xml: &str) -> Option<Self> {
+        let mut reader = Reader::from_str(xml);
+        let mut is_ome = false;
+        let mut ome: Option<Self> = None;
+        let mut objectives = vec![];
+        let mut objective_ref = None;
+        let mut depth = 0;
+        let mut image_depth = None;
+        loop {
+            let (e, empty) = match reader.read_event().ok()? {
+                Event::Start(e) => (e, false),
+                Event::Empty(e) => (e, true),
+                Event::End(_) => {
+                    depth -= 1;
+                    if image_depth == Some(depth) {
+                        break;
+                    }
+                    continue;
+                }
+                Event::Eof => break,
+                _ => continue,
+            };
+            let first_image = image_depth.is_some();
+            match e.local_name().as_ref() {
+                b"OME" => is_ome = true,
+                b"Objective" if is_ome => objectives.push((
+                    attribute(&e, "ID"),
+                    ObjectiveInfo {
+                        model: attribute(&e, "Model"),
+                        magnification: number(&e, "NominalMagnification"),
+                        numerical_aperture: number(&e, "LensNA"),
+                    },
+                )),
+                b"Image" if is_ome && image_depth.is_none() => image_depth = Some(depth),
+                b"ObjectiveSettings" if first_image => objective_ref = attribute(&e, "ID"),
+                b"Pixels" if first_image && ome.is_none() => ome = Some(Self::from_pixels(&e)),
+                b"Channel" if first_image => {
+                    if let Some(ome) = ome.as_mut() {
+                        ome.channels.push(ChannelInfo::from_ome(&e));
+                    }
+                }
+                _ => (),
+            }
+            if !empty {
+                depth += 1;
+            }
+        }
+
+        let mut ome = ome?;
+        ome.objective = objectives
+            .iter()
+            .find(|(id, _)| objective_ref.is_some() && *id == objective_ref)
+            .or(objectives.first())
+            .map(|(_, o)| o.clone());
+        Some(ome)
+    }
+
+    fn from_pixels(e: &BytesStart) -> Self {
+        let size = |name: &str| {
+            attribute(e, name)
+                .and_then(|s| s.parse::<usize>().ok())
+                .unwrap_or(1)
+                .max(1)
+        };
+        let physical_size = |axis: &str| {
+            let unit = attribute(e, &format!("PhysicalSize{axis}Unit"));
+            number(e, &format!("PhysicalSize{axis}"))
+                .zip(micrometres_per(unit.as_deref().unwrap_or("µm")))
+                .map(|(v, um)| v * um)
+                .filter(|v| *v > 0.0)
+        };
+        let x = physical_size("X");
+        Self {
+            size_c: size("SizeC"),
+            size_z: size("SizeZ"),
+            size_t: size("SizeT"),
+            dimension_order: attribute(e, "DimensionOrder").unwrap_or("XYCZT".into()),
+            physical_size: x.map(|x| (x, physical_size("Y").unwrap_or(x))),
+            significant_bits: attribute(e, "SignificantBits").and_then(|s| s.parse().ok()),
+            channels: vec![],
+            objective: None,
+        }
+    }
+
+    /// IFD index of the first plane (z = 0, t = 0) of channel page `c`.
+    pub fn plane_index(&self, c: usize) -> usize {
+        self.dimension_order
+            .chars()
+            .skip(2)
+            .take_while(|&d| d != 'C')
+            .map(|d| match d {
+                'Z' => self.size_z,
+                'T' => self.size_t,
+                _ => 1,
+            })
+            .product::<usize>()
+            * c
+    }
+
+    /// Channel pages per plane set when each page holds `samples_per_pixel`
+    /// channels, as in RGB OME-TIFFs.
+    pub fn channel_pages(&self, samples_per_pixel: usize) -> usize {
+        if samples_per_pixel > 1 && self.size_c.is_multiple_of(samples_per_pixel) {
+            self.size_c / samples_per_pixel
+        } else {
+            self.size_c
+        }
+    }
+}
+
+impl ChannelInfo {
+    fn from_ome(e: &BytesStart) -> Self {
+        let wavelength = |name: &str| {
+            let unit = attribute(e, &format!("{name}Unit"));
+            number(e, name)
+                .zip(micrometres_per(unit.as_deref().unwrap_or("nm")))
+                .map(|(v, um)| v * um * 1e3)
+        };
+        Self {
+            name: attribute(e, "Name").unwrap_or_default(),
+            emission_wavelength: wavelength("EmissionWavelength"),
+            excitation_wavelength: wavelength("ExcitationWavelength"),
+        }
+    }
+}
+
+/// Micrometres in one `unit`, for the OME length units and those ImageJ writes.
+pub fn micrometres_per(unit: &str) -> Option<f64> {
+    match unit.trim() {
+        "pm" => Some(1e-6),
+        "Å" => Some(1e-4),
+        "nm" => Some(1e-3),
+        "µm" | "μm" | "um" | "micron" | "microns" | "\\u00B5m" => Some(1.0),
+        "mm" => Some(1e3),
+        "cm" => Some(1e4),
+        "in" | "inch" => Some(25_400.0),
+        "m" => Some(1e6),
+        _ => None,
+    }
+}
+
+/// The unescaped value of attribute `name` of element `e`.
+pub(crate) fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+fn number(e: &BytesStart, name: &str) -> Option<f64> {
+    attribute(e, name)
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|v| v.is_finite())
+}
@@ -0,0 +1,194 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+/// Decoded bytes kept until `set_capacity` says otherwise.
+pub const DEFAULT_CAPACITY: usize = 512 * 1024 * 1024;
+
+/// One decoded strip or tile: the file as passed to the reader, the IFD
+/// offset of its page and its chunk index within the page.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub file: String,
+    pub ifd: u64,
+    pub chunk: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MB: f64 = 1024.0 * 1024.0;
+        write!(
+            f,
+            "{} tiles, {:.1}/{:.0} MB, {} hits, {} misses ({:.0}% hit)",
+            self.entries,
+            self.bytes as f64 / MB,
+            self.capacity as f64 / MB,
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+struct Entry {
+    data: Arc<dyn Any + Send + Sync>,
+    bytes: usize,
+    used: u64,
+}
+
+/// Least recently used chunks are evicted first; `recency` maps each entry's
+/// last use to its key.
+struct TileCache {
+    entries: HashMap<TileKey, Entry>,
+    recency: BTreeMap<u64, TileKey>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl TileCache {
+    fn get<T: Send + Sync + 'static>(&mut self, key: &TileKey) -> Option<Arc<Vec<T>>> {
+        let entry = self.entries.get_mut(key)?;
+        let data = Arc::clone(&entry.data).downcast::<Vec<T>>().ok()?;
+        self.recency.remove(&entry.used);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        Some(data)
+    }
+
+    fn insert(&mut self, key: TileKey, data: Arc<dyn Any + Send + Sync>, bytes: usize) {
+        self.remove(&key);
+        if bytes > self.stats.capacity {
+            return;
+        }
+        self.evict(self.stats.capacity - bytes);
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                data,
+                bytes,
+                used: self.tick,
+            },
+        );
+        self.stats.bytes += bytes;
+        self.stats.entries = self.entries.len();
+    }
+
+    fn remove(&mut self, key: &TileKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.stats.bytes -= entry.bytes;
+            self.stats.entries = self.entries.len();
+        }
+    }
+
+    /// Drops the least recently used entries until at most `bytes` are held.
+    fn evict(&mut self, bytes: usize) {
+        while self.stats.bytes > bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            self.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+static CACHE: OnceLock<Mutex<TileCache>> = OnceLock::new();
+
+fn cache() -> MutexGuard<'static, TileCache> {
+    CACHE
+        .get_or_init(|| {
+            Mutex::new(TileCache {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                stats: CacheStats {
+                    capacity: DEFAULT_CAPACITY,
+                    ..Default::default()
+                },
+            })
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The chunk under `key`, decoded by `decode` and kept on a miss. The cache
+/// is not locked while decoding, so threads missing the same chunk may each
+/// decode it. Chunks larger than the whole capacity are not kept.
+pub fn get_or_decode<T, E>(
+    key: TileKey,
+    decode: impl FnOnce() -> Result<Vec<T>, E>,
+) -> Result<Arc<Vec<T>>, E>
+where
+    T: Send + Sync + 'static,
+{
+    {
+        let mut cache = cache();
+        if let Some(data) = cache.get::<T>(&key) {
+            cache.stats.hits += 1;
+            return Ok(data);
+        }
+    }
+    let data = Arc::new(decode()?);
+    let bytes = data.len() * std::mem::size_of::<T>();
+    let mut cache = cache();
+    cache.stats.misses += 1;
+    cache.insert(key, Arc::clone(&data) as Arc<dyn Any + Send + Sync>, bytes);
+    Ok(data)
+}
+
+/// Forgets every chunk of `file`. Entries are not checked against the file
+/// on disk, so this must be called whenever a file is rewritten.
+pub fn invalidate(file: &str) {
+    let mut cache = cache();
+    let keys = cache
+        .entries
+        .keys()
+        .filter(|k| k.file == file)
+        .cloned()
+        .collect::<Vec<TileKey>>();
+    keys.iter().for_each(|k| cache.remove(k));
+}
+
+/// Forgets every chunk, keeping the statistics.
+pub fn clear() {
+    let mut cache = cache();
+    cache.entries.clear();
+    cache.recency.clear();
+    cache.stats.bytes = 0;
+    cache.stats.entries = 0;
+}
+
+/// Bounds the decoded bytes kept, evicting down to it straight away.
+pub fn set_capacity(bytes: usize) {
+    let mut cache = cache();
+    cache.stats.capacity = bytes;
+    cache.evict(bytes);
+}
+
+pub fn stats() -> CacheStats {
+    cache().stats
+}
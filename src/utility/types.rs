@@ -1,26 +1,243 @@
 use ndarray::prelude::*;
 use ndarray::OwnedRepr;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tiff::tags::SampleFormat;
 
 pub type Pnt = (usize, usize);
 pub type ROI = (usize, usize, usize, usize);
 pub type Matrix<T> = ArrayBase<OwnedRepr<T>, Dim<[usize; 2]>>;
 pub type Volume<T> = ArrayBase<OwnedRepr<T>, Dim<[usize; 3]>>;
 
+pub const MIN_TILE_SIZE: usize = 64;
+/// Largest cell territory radius in pixels tiled analysis allows for.
+pub const MAX_CELL_RADIUS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub cell_marker_threshold: f64,
     pub co_marker_threshold: f64,
     pub overlap_percentage_threshold: f64,
     pub soma_threshold: f64,
     pub max_co_marker_size: usize,
+    pub intermediates: Vec<Intermediate>,
+    /// Core tile edge in pixels; `None` analyses the ROI in a single pass.
+    pub tile_size: Option<usize>,
+    /// Pixels of context read around each tile core, at least `MAX_CELL_RADIUS`
+    /// so cells straddling a core border are seen whole, and less than `tile_size`.
+    pub tile_overlap: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            cell_marker_threshold: 0.02,
+            co_marker_threshold: 2.0,
+            overlap_percentage_threshold: 10.0,
+            soma_threshold: 2.0,
+            max_co_marker_size: 500,
+            intermediates: vec![
+                Intermediate::CoMarkerMask,
+                Intermediate::Segmented,
+                Intermediate::Skeleton,
+            ],
+            tile_size: None,
+            tile_overlap: 256,
+        }
+    }
 }
 
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        let finite = [
+            ("cell_marker_threshold", self.cell_marker_threshold),
+            ("co_marker_threshold", self.co_marker_threshold),
+            (
+                "overlap_percentage_threshold",
+                self.overlap_percentage_threshold,
+            ),
+            ("soma_threshold", self.soma_threshold),
+        ];
+
+        if let Some((name, _)) = finite.iter().find(|(_, v)| !v.is_finite()) {
+            return Err(format!("{} must be a finite number", name));
+        }
+        if self.cell_marker_threshold < 0.0 {
+            return Err("cell_marker_threshold must not be negative".into());
+        }
+        if !(0.0..=100.0).contains(&self.overlap_percentage_threshold) {
+            return Err("overlap_percentage_threshold must be between 0 and 100".into());
+        }
+        if self.max_co_marker_size == 0 {
+            return Err("max_co_marker_size must be at least 1 pixel".into());
+        }
+        if self.tile_size.is_some_and(|t| t < MIN_TILE_SIZE) {
+            return Err(format!(
+                "tile_size must be at least {} pixels",
+                MIN_TILE_SIZE
+            ));
+        }
+        if self.tile_overlap < MAX_CELL_RADIUS {
+            return Err(format!(
+                "tile_overlap must be at least {} pixels, the largest cell radius",
+                MAX_CELL_RADIUS
+            ));
+        }
+        if self.tile_size.is_some_and(|t| self.tile_overlap >= t) {
+            return Err("tile_overlap must be smaller than tile_size".into());
+        }
+        Ok(())
+    }
+}
+
+/// Slide-level results. Averages and the co-marker positive share of cells
+/// are `None` without any cells to take them over.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Results {
     pub cell_count: usize,
-    pub average_rotundity: f64,
-    pub average_branch_length: f64,
-    pub average_scholl: f64,
+    pub average_rotundity: Option<f64>,
+    pub average_branch_length: Option<f64>,
+    pub average_scholl: Option<f64>,
+    pub percentage_cd68_area: f64,
+    pub percentage_cd68_num: Option<f64>,
+}
+
+/// Images produced along the analysis pipeline that can be kept for inspection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Intermediate {
+    CoMarkerMask,
+    Somas,
+    Branches,
+    Segmented,
+    Skeleton,
+    BranchPoints,
+    Perimeter,
+    Overlay,
+    BranchLength,
+}
+
+impl Intermediate {
+    pub const ALL: [Intermediate; 9] = [
+        Self::CoMarkerMask,
+        Self::Somas,
+        Self::Branches,
+        Self::Segmented,
+        Self::Skeleton,
+        Self::BranchPoints,
+        Self::Perimeter,
+        Self::Overlay,
+        Self::BranchLength,
+    ];
+
+    pub fn file_name(&self) -> &str {
+        match self {
+            Self::CoMarkerMask => "cd68_mask.tif",
+            Self::Somas => "somas.tif",
+            Self::Branches => "branches.tif",
+            Self::Segmented => "segmented.tif",
+            Self::Skeleton => "skelly.tif",
+            Self::BranchPoints => "detected.tif",
+            Self::Perimeter => "poly.tif",
+            Self::Overlay => "overlay.tif",
+            Self::BranchLength => "branch_length.tif",
+        }
+    }
+
+    /// Masks go to the workspace's mask directory, everything else is a processed image.
+    pub fn is_mask(&self) -> bool {
+        !matches!(self, Self::Perimeter | Self::Overlay | Self::BranchLength)
+    }
+}
+
+/// Morphology and co-marker measurements for a single segmented cell.
+#[derive(Clone, Debug, Serialize)]
+pub struct CellRecord {
+    pub label_id: u32,
+    pub centroid_row: usize,
+    pub centroid_col: usize,
+    pub soma_area: usize,
+    pub territory_area: usize,
+    pub rotundity: f64,
+    pub mean_branch_length: f64,
+    pub max_branch_length: usize,
+    pub scholl_slope: f64,
+    pub branch_points: usize,
+    pub co_marker_overlap: f64,
+    /// Name of the region the cell was found in, empty when the whole image was analysed.
+    pub region: String,
+    /// Atlas structure at the centroid, 0 and empty when unregistered or outside the atlas.
+    pub structure_id: u64,
+    pub structure: String,
+    /// Side of the atlas midline of the centroid, empty when unregistered.
+    pub hemisphere: String,
+}
+
+/// Pixel area of one analysed region and the share of it that is co-marker positive.
+#[derive(Clone, Debug)]
+pub struct RegionArea {
+    pub name: String,
+    pub area: usize,
+    pub percentage_cd68_area: f64,
+}
+
+/// Per-cell output of an analysis run. Slide-level `Results` are derived from it.
+///
+/// When the image has regions, `regions` holds one entry per region and each
+/// cell is tagged with the region it came from. A cell inside two overlapping
+/// regions appears once for each.
+#[derive(Clone, Debug)]
+pub struct CellTable {
+    pub cells: Vec<CellRecord>,
     pub percentage_cd68_area: f64,
-    pub percentage_cd68_num: f64,
+    pub regions: Vec<RegionArea>,
+}
+
+impl CellTable {
+    /// Results for each region, keyed by region name.
+    pub fn region_results(&self, settings: &Settings) -> BTreeMap<String, Results> {
+        self.regions
+            .iter()
+            .map(|r| {
+                let table = CellTable {
+                    cells: self
+                        .cells
+                        .iter()
+                        .filter(|c| c.region == r.name)
+                        .cloned()
+                        .collect(),
+                    percentage_cd68_area: r.percentage_cd68_area,
+                    regions: vec![],
+                };
+                (r.name.clone(), table.results(settings))
+            })
+            .collect()
+    }
+
+    pub fn results(&self, settings: &Settings) -> Results {
+        let cell_count = self.cells.len();
+        let mean = |f: fn(&CellRecord) -> f64| {
+            let vals = self.cells.iter().map(f).filter(|a| a.is_finite());
+            let (sum, n) = vals.fold((0.0, 0), |(s, n), a| (s + a, n + 1));
+            (n > 0).then(|| sum / n as f64)
+        };
+
+        let n_overlap = self
+            .cells
+            .iter()
+            .filter(|c| c.co_marker_overlap > settings.overlap_percentage_threshold)
+            .count();
+
+        Results {
+            cell_count,
+            average_rotundity: mean(|c| c.rotundity),
+            average_branch_length: mean(|c| c.mean_branch_length),
+            average_scholl: mean(|c| c.scholl_slope),
+            percentage_cd68_area: self.percentage_cd68_area,
+            percentage_cd68_num: (cell_count > 0)
+                .then(|| 100.0 * n_overlap as f64 / cell_count as f64),
+        }
+    }
 }
 
 impl std::fmt::Debug for Results {
@@ -35,14 +252,360 @@ impl std::fmt::Debug for Results {
     }
 }
 
+/// A named polygon drawn on an image. Vertices are `(row, col)` in
+/// full-resolution pixels, the polygon is implicitly closed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Region {
+    pub name: String,
+    pub vertices: Vec<(f64, f64)>,
+}
+
+impl Region {
+    /// Checks the region against an image of `(width, height)`.
+    pub fn validate(&self, size: (usize, usize)) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("region name must not be empty".into());
+        }
+        if self.vertices.len() < 3 {
+            return Err(format!("region {} needs at least 3 vertices", self.name));
+        }
+        if self
+            .vertices
+            .iter()
+            .any(|(r, c)| !r.is_finite() || !c.is_finite())
+        {
+            return Err(format!("region {} has a non-finite vertex", self.name));
+        }
+        let (r0, c0, h, w) = self.bounds(size);
+        if !(r0..r0 + h).any(|r| !self.spans(r, c0, w).is_empty()) {
+            return Err(format!(
+                "region {} covers no pixels of the image",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Folder the region's intermediates are written to, its name with
+    /// anything but letters, digits and '-' replaced by '_'.
+    pub fn dir_name(&self) -> String {
+        self.name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Smallest ROI holding the polygon, clipped to an image of `(width, height)`.
+    pub fn bounds(&self, (width, height): (usize, usize)) -> ROI {
+        let fold = |f: fn(&(f64, f64)) -> f64| {
+            self.vertices
+                .iter()
+                .map(f)
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), a| {
+                    (lo.min(a), hi.max(a))
+                })
+        };
+        let (r_lo, r_hi) = fold(|v| v.0);
+        let (c_lo, c_hi) = fold(|v| v.1);
+
+        let clip = |a: f64, max: usize| (a.max(0.0) as usize).min(max);
+        let (r0, r1) = (clip(r_lo.floor(), height), clip(r_hi.ceil(), height));
+        let (c0, c1) = (clip(c_lo.floor(), width), clip(c_hi.ceil(), width));
+
+        (r0, c0, r1 - r0, c1 - c0)
+    }
+
+    /// Even-odd test of a point in full-resolution coordinates.
+    pub fn contains(&self, (r, c): (f64, f64)) -> bool {
+        self.crossings(r).iter().filter(|&&x| x < c).count() % 2 == 1
+    }
+
+    /// Pixels of `roi` whose centres fall inside the polygon, filled by scanline.
+    pub fn mask(&self, roi: ROI) -> Matrix<bool> {
+        let (r0, c0, h, w) = roi;
+        let mut out = Array2::from_elem((h, w), false);
+
+        for (i, mut row) in out.outer_iter_mut().enumerate() {
+            self.spans(r0 + i, c0, w)
+                .into_iter()
+                .for_each(|span| row.slice_mut(s![span]).fill(true));
+        }
+
+        out
+    }
+
+    /// Non-empty runs of columns `c0..c0 + w`, relative to `c0`, whose pixel
+    /// centres on row `r` fall inside the polygon.
+    fn spans(&self, r: usize, c0: usize, w: usize) -> Vec<std::ops::Range<usize>> {
+        let mut xs = self.crossings(r as f64 + 0.5);
+        xs.sort_by(|a, b| a.total_cmp(b));
+
+        xs.chunks_exact(2)
+            .filter_map(|span| {
+                // pixel c is inside when span[0] < c + 0.5 < span[1]
+                let from = (span[0] - 0.5 - c0 as f64).floor() + 1.0;
+                let to = (span[1] - 0.5 - c0 as f64).ceil();
+                let from = from.clamp(0.0, w as f64) as usize;
+                let to = to.clamp(0.0, w as f64) as usize;
+                (from < to).then_some(from..to)
+            })
+            .collect()
+    }
+
+    /// Columns at which the polygon's edges cross the horizontal line at row `r`.
+    fn crossings(&self, r: f64) -> Vec<f64> {
+        let n = self.vertices.len();
+        (0..n)
+            .filter_map(|i| {
+                let (ra, ca) = self.vertices[i];
+                let (rb, cb) = self.vertices[(i + 1) % n];
+                if (ra <= r) != (rb <= r) {
+                    Some(ca + (r - ra) / (rb - ra) * (cb - ca))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug)]
 pub struct TiffInfo {
     pub dimensions: Pnt,
     pub n_channels: usize,
+    pub metadata: AcquisitionMetadata,
+    /// (width, height) of each resolution level, full resolution first.
+    pub levels: Vec<Pnt>,
 }
 
-#[derive(Debug)]
-pub enum TiffType {
-    MultiPanel { bps: usize, spp: usize, cc: usize },
-    SinglePanel { bps: usize, cc: usize },
+/// What the file says about how the image was acquired.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AcquisitionMetadata {
+    /// Micrometres per full-resolution pixel along (x, y).
+    pub pixel_size: Option<(f64, f64)>,
+    /// Empty unless the file names its channels.
+    pub channels: Vec<ChannelInfo>,
+    pub objective: Option<ObjectiveInfo>,
+    /// Significant bits per sample, which may be fewer than are stored.
+    pub bit_depth: usize,
+}
+
+impl AcquisitionMetadata {
+    /// Index of the first channel whose name contains one of `keywords`,
+    /// ignoring case.
+    pub fn channel_named(&self, keywords: &[&str]) -> Option<usize> {
+        self.channels.iter().position(|c| {
+            let name = c.name.to_lowercase();
+            keywords.iter().any(|k| name.contains(k))
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    /// Nanometres.
+    pub emission_wavelength: Option<f64>,
+    /// Nanometres.
+    pub excitation_wavelength: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectiveInfo {
+    pub model: Option<String>,
+    pub magnification: Option<f64>,
+    pub numerical_aperture: Option<f64>,
+}
+
+/// How the pages of a TIFF make up one image.
+#[derive(Clone, Debug)]
+pub struct TiffLayout {
+    pub bigtiff: bool,
+    pub bits_per_sample: usize,
+    /// Bits per sample that carry data, e.g. 12 for 12-bit data stored in 16.
+    pub significant_bits: usize,
+    pub sample_format: SampleFormat,
+    pub samples_per_pixel: usize,
+    /// Full resolution first, then ever smaller.
+    pub levels: Vec<ResolutionLevel>,
+}
+
+/// One resolution of the image: a single page holding every channel as
+/// samples, or one page per channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolutionLevel {
+    /// (width, height)
+    pub dimensions: Pnt,
+    /// IFD offsets of the level's pages, in channel order.
+    pub pages: Vec<u64>,
+}
+
+impl TiffLayout {
+    /// Every sample of every full-resolution page is a channel.
+    pub fn n_channels(&self) -> usize {
+        self.levels[0].pages.len() * self.samples_per_pixel
+    }
+
+    /// Whole-pixel downsample of `level` relative to full resolution.
+    pub fn level_factor(&self, level: usize) -> usize {
+        let full = self.levels[0].dimensions.0 as f64;
+        let w = self.levels[level].dimensions.0.max(1) as f64;
+        ((full / w).round() as usize).max(1)
+    }
+
+    /// The coarsest level no coarser than downsample `df`, with its factor.
+    pub fn level_for(&self, df: usize) -> (usize, usize) {
+        (0..self.levels.len())
+            .map(|l| (l, self.level_factor(l)))
+            .filter(|&(_, f)| f <= df.max(1))
+            .max_by_key(|&(_, f)| f)
+            .unwrap_or((0, 1))
+    }
+}
+
+/// One channel of a region with the samples as stored in the file. Half
+/// floats are widened to `F32`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PixelMatrix {
+    U8(Matrix<u8>),
+    U16(Matrix<u16>),
+    U32(Matrix<u32>),
+    U64(Matrix<u64>),
+    I8(Matrix<i8>),
+    I16(Matrix<i16>),
+    I32(Matrix<i32>),
+    I64(Matrix<i64>),
+    F32(Matrix<f32>),
+    F64(Matrix<f64>),
+}
+
+/// Evaluates `$e` with `$m` bound to the matrix of any variant.
+macro_rules! with_pixels {
+    ($pixels:expr, $m:ident => $e:expr) => {
+        match $pixels {
+            PixelMatrix::U8($m) => $e,
+            PixelMatrix::U16($m) => $e,
+            PixelMatrix::U32($m) => $e,
+            PixelMatrix::U64($m) => $e,
+            PixelMatrix::I8($m) => $e,
+            PixelMatrix::I16($m) => $e,
+            PixelMatrix::I32($m) => $e,
+            PixelMatrix::I64($m) => $e,
+            PixelMatrix::F32($m) => $e,
+            PixelMatrix::F64($m) => $e,
+        }
+    };
+}
+
+/// Like `with_pixels`, keeping the variant of the matrix `$e` returns.
+macro_rules! map_pixels {
+    ($pixels:expr, $m:ident => $e:expr) => {
+        match $pixels {
+            PixelMatrix::U8($m) => PixelMatrix::U8($e),
+            PixelMatrix::U16($m) => PixelMatrix::U16($e),
+            PixelMatrix::U32($m) => PixelMatrix::U32($e),
+            PixelMatrix::U64($m) => PixelMatrix::U64($e),
+            PixelMatrix::I8($m) => PixelMatrix::I8($e),
+            PixelMatrix::I16($m) => PixelMatrix::I16($e),
+            PixelMatrix::I32($m) => PixelMatrix::I32($e),
+            PixelMatrix::I64($m) => PixelMatrix::I64($e),
+            PixelMatrix::F32($m) => PixelMatrix::F32($e),
+            PixelMatrix::F64($m) => PixelMatrix::F64($e),
+        }
+    };
+}
+
+impl PixelMatrix {
+    /// (rows, columns)
+    pub fn dim(&self) -> Pnt {
+        with_pixels!(self, m => m.dim())
+    }
+
+    /// A `shape` matrix whose pixel (i, j) is this one's pixel `at((i, j))`.
+    pub fn sample(&self, shape: Pnt, at: impl Fn(Pnt) -> Pnt) -> Self {
+        map_pixels!(self, m => Matrix::from_shape_fn(shape, |p| m[at(p)]))
+    }
+
+    /// Samples as `f64`, exact for every type except 64-bit integers
+    /// beyond 2^53.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_f64(&self) -> Matrix<f64> {
+        with_pixels!(self, m => m.mapv(|a| a as f64))
+    }
+
+    /// Samples as `f32`, exact for types of up to 16 bits and for `F32`.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_f32(&self) -> Matrix<f32> {
+        with_pixels!(self, m => m.mapv(|a| a as f32))
+    }
+
+    /// Samples rounded and clamped to `0..=65535`, NaN becoming 0. Exact for
+    /// `U8` and `U16` and for labels that fit; meant for label volumes.
+    pub fn to_u16(&self) -> Matrix<u16> {
+        match self {
+            Self::U16(m) => m.clone(),
+            _ => self
+                .to_f64()
+                .mapv(|a| a.round().clamp(0.0, u16::MAX as f64) as u16),
+        }
+    }
+
+    /// Samples rounded and clamped to `0..=u32::MAX`, NaN becoming 0. Exact
+    /// for unsigned types of up to 32 bits; meant for label volumes whose ids
+    /// exceed `u16`.
+    pub fn to_u32(&self) -> Matrix<u32> {
+        match self {
+            Self::U8(m) => m.mapv(u32::from),
+            Self::U16(m) => m.mapv(u32::from),
+            Self::U32(m) => m.clone(),
+            _ => self
+                .to_f64()
+                .mapv(|a| a.round().clamp(0.0, u32::MAX as f64) as u32),
+        }
+    }
+
+    /// The intensity range to display: zero to the largest value of
+    /// `significant_bits` for integers, the finite extent of the data for
+    /// floats.
+    pub fn display_range(&self, significant_bits: usize) -> (f64, f64) {
+        let bits = |type_bits: usize| significant_bits.clamp(1, type_bits);
+        let max = |bits: usize| 2f64.powi(bits as i32) - 1.0;
+        match self {
+            Self::U8(_) => (0.0, max(bits(8))),
+            Self::U16(_) => (0.0, max(bits(16))),
+            Self::U32(_) => (0.0, max(bits(32))),
+            Self::U64(_) => (0.0, max(bits(64))),
+            Self::I8(_) => (0.0, max(bits(8) - 1)),
+            Self::I16(_) => (0.0, max(bits(16) - 1)),
+            Self::I32(_) => (0.0, max(bits(32) - 1)),
+            Self::I64(_) => (0.0, max(bits(64) - 1)),
+            Self::F32(_) | Self::F64(_) => self
+                .to_f64()
+                .iter()
+                .filter(|a| a.is_finite())
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &a| {
+                    (lo.min(a), hi.max(a))
+                }),
+        }
+    }
+
+    /// Intensities mapped linearly from `display_range` to `0..=255`.
+    pub fn to_display_u8(&self, significant_bits: usize) -> Matrix<u8> {
+        if let Self::U8(m) = self {
+            if significant_bits >= 8 {
+                return m.clone();
+            }
+        }
+        let (lo, hi) = self.display_range(significant_bits);
+        let scale = if hi > lo { 255.0 / (hi - lo) } else { 0.0 };
+        self.to_f64()
+            .mapv(|a| ((a - lo) * scale).round().clamp(0.0, 255.0) as u8)
+    }
 }
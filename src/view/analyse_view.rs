@@ -0,0 +1,296 @@
+use std::ops::Div;
+
+use eframe::egui::{self, Color32, Pos2, Rect, Scene, TextureHandle, Ui};
+
+use crate::controller::AnalyseController;
+use crate::model::atlas::Hemisphere;
+use crate::model::{AnalysisStatus, Model};
+
+pub fn ui_tab_analyse(model: &mut Model, con: &mut AnalyseController, ui: &mut egui::Ui) {
+    con.poll_batch(model);
+
+    ui.horizontal(|ui| {
+        if ui.button("Select All").clicked() {
+            con.select_all(model);
+        }
+
+        preset_picker(model, con, ui);
+
+        ui.label("Workers");
+        ui.add(egui::DragValue::new(&mut con.worker_limit).range(1..=16));
+
+        if ui
+            .add_enabled(!con.is_running(), egui::Button::new("Analyse Selected"))
+            .clicked()
+        {
+            con.analyse_selected(model);
+        }
+        if ui
+            .add_enabled(con.is_running(), egui::Button::new("Cancel Selected"))
+            .clicked()
+        {
+            con.cancel_selected();
+        }
+        if ui
+            .add_enabled(con.is_running(), egui::Button::new("Cancel All"))
+            .clicked()
+        {
+            con.cancel_all();
+        }
+
+        if let Some(msg) = &con.message {
+            ui.colored_label(Color32::RED, msg);
+        }
+    });
+
+    ui.vertical(|ui| {
+        table_ui(model, con, ui);
+
+        ui.separator();
+
+        image_viewer(model, con, ui);
+    });
+}
+
+fn preset_picker(model: &mut Model, con: &mut AnalyseController, ui: &mut egui::Ui) {
+    let Some(settings) = model.get_settings() else {
+        return;
+    };
+
+    let mut chosen = None;
+
+    egui::ComboBox::from_label("Preset")
+        .selected_text(&settings.active)
+        .show_ui(ui, |ui| {
+            settings.names().for_each(|name| {
+                if ui.selectable_label(name == settings.active, name).clicked() {
+                    chosen = Some(name.to_owned());
+                }
+            });
+        });
+
+    if let Some(name) = chosen {
+        con.on_preset_selected(model, &name);
+    }
+}
+
+fn black_box(ui: &mut Ui, name: &str, add_contents: impl FnOnce(&mut Ui)) {
+    egui::containers::Window::new(name.to_string())
+        .current_pos(ui.max_rect().min)
+        .max_size(ui.available_size())
+        .min_size(ui.available_size())
+        .interactable(false)
+        .title_bar(false)
+        .frame(
+            egui::Frame::new()
+                .corner_radius(0)
+                .fill(Color32::BLACK)
+                .outer_margin(0),
+        )
+        .show(ui.ctx(), add_contents);
+}
+
+fn image_viewer(model: &mut Model, con: &mut AnalyseController, ui: &mut egui::Ui) {
+    let image_metadata = con.selected_img.as_ref().and_then(|id| model.get_image(id));
+
+    ui.columns(2, |ui| {
+        black_box(&mut ui[0], "analyse_left", |ui| {
+            let mut inner_rect = Rect::NAN;
+            let textures = con.textures.try_lock();
+
+            let response = Scene::new()
+                .zoom_range(0.0..=f32::INFINITY)
+                .show(ui, &mut con.image_rect, |ui: &mut Ui| {
+                    if let Ok(t) = &textures {
+                        if let Some(im) = &t.image {
+                            let rect = ui.image(im).rect;
+                            let layers = [
+                                (con.show_segmented, &t.segmented),
+                                (con.show_co_marker, &t.co_marker),
+                                (con.show_skeleton, &t.skeleton),
+                            ];
+                            layers.iter().for_each(|(show, layer)| {
+                                if let (true, Some(layer)) = (show, layer) {
+                                    paint_overlay(ui, layer, rect);
+                                }
+                            });
+                        }
+                    }
+                    inner_rect = ui.min_rect();
+                })
+                .response;
+
+            if response.double_clicked() {
+                con.image_rect = inner_rect;
+            }
+        });
+
+        black_box(&mut ui[1], "analyse_right", |ui| {
+            ui.checkbox(&mut con.show_segmented, "Segmentation");
+            ui.checkbox(&mut con.show_skeleton, "Skeleton");
+            ui.checkbox(&mut con.show_co_marker, "Co-marker");
+
+            if let Some(err) = con.textures.try_lock().ok().and_then(|t| t.error.clone()) {
+                ui.colored_label(Color32::RED, err);
+            }
+
+            ui.separator();
+
+            if let Some(img) = &image_metadata {
+                results_ui(img, ui);
+            }
+        });
+    });
+}
+
+fn paint_overlay(ui: &mut Ui, layer: &TextureHandle, rect: Rect) {
+    let uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+    ui.painter().image(layer.id(), rect, uv, Color32::WHITE);
+}
+
+fn results_ui(img: &crate::model::ImageMetadata, ui: &mut Ui) {
+    ui.strong(img.id());
+
+    if let AnalysisStatus::Failed(err) = &img.analysis_status {
+        ui.colored_label(Color32::RED, err);
+    }
+
+    let Some(res) = &img.results else {
+        ui.label(img.analysis_status.to_str());
+        return;
+    };
+
+    egui::Grid::new("analyse_results")
+        .striped(true)
+        .show(ui, |ui| {
+            let rows = [
+                ("Cell Count", res.cell_count.to_string()),
+                ("Rotundity", optional(res.average_rotundity, 3, "")),
+                (
+                    "Branch Length",
+                    optional(res.average_branch_length, 2, "px"),
+                ),
+                ("Scholl Index", optional(res.average_scholl, 4, "")),
+                ("CoM Area (%)", format!("{:.2}", res.percentage_cd68_area)),
+                ("CoM Num (%)", optional(res.percentage_cd68_num, 2, "")),
+            ];
+            rows.iter().for_each(|(name, value)| {
+                ui.label(*name);
+                ui.label(value);
+                ui.end_row();
+            });
+        });
+
+    if !img.structure_results.is_empty() {
+        structures_ui(img, ui);
+    }
+}
+
+/// `a` with `decimals` places and `unit`, or "-" when missing.
+fn optional(a: Option<f64>, decimals: usize, unit: &str) -> String {
+    a.map_or("-".into(), |a| format!("{:.*}{}", decimals, a, unit))
+}
+
+fn structures_ui(img: &crate::model::ImageMetadata, ui: &mut Ui) {
+    egui::CollapsingHeader::new("Atlas Structures").show(ui, |ui| {
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("analyse_structures")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong("Structure");
+                    ui.strong("Side");
+                    ui.strong("Cells");
+                    ui.strong("Cells/mm²");
+                    ui.strong("Rotundity");
+                    ui.end_row();
+
+                    img.structure_results.iter().for_each(|s| {
+                        ui.label(&s.acronym).on_hover_text(&s.name);
+                        ui.label(s.hemisphere.as_ref().map_or("Both", Hemisphere::to_str));
+                        ui.label(s.cell_count.to_string());
+                        ui.label(optional(s.density_per_mm2, 1, ""));
+                        ui.label(optional(s.average_rotundity, 3, ""));
+                        ui.end_row();
+                    });
+                });
+        });
+    });
+}
+
+fn table_ui(model: &mut Model, con: &mut AnalyseController, ui: &mut egui::Ui) {
+    use egui_extras::{Column, TableBuilder};
+
+    let available_height = ui.available_height();
+
+    TableBuilder::new(ui)
+        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+        .column(Column::remainder())
+        .column(Column::remainder())
+        .column(Column::remainder())
+        .column(Column::remainder())
+        .auto_shrink(false)
+        .min_scrolled_height(available_height.div(5.0))
+        .max_scroll_height(available_height.div(5.0))
+        .striped(true)
+        .sense(egui::Sense::click())
+        .header(20.0, |mut header| {
+            header.col(|ui| {
+                ui.strong("Image");
+            });
+            header.col(|ui| {
+                ui.strong("Status");
+            });
+            header.col(|ui| {
+                ui.strong("Progress");
+            });
+            header.col(|ui| {
+                ui.strong("Cells");
+            });
+        })
+        .body(|body| {
+            let mut img_ids = model.get_all_images().unwrap_or(vec![]);
+            img_ids.sort_by(|a, b| a.id().cmp(b.id()));
+
+            body.rows(18.0, img_ids.len(), |mut row| {
+                let img = &img_ids[row.index()];
+
+                row.set_selected(con.selection.contains(img.src_fn()));
+                row.set_overline(true);
+
+                row.col(|ui| {
+                    ui.label(img.id());
+                });
+                row.col(|ui| {
+                    ui.label(img.analysis_status.to_str());
+                });
+                row.col(|ui| {
+                    if let Some((stage, percent)) = con.progress.get(img.src_fn()) {
+                        let bar = egui::ProgressBar::new(percent / 100.0).text(stage);
+                        ui.add(bar);
+                    }
+                });
+                row.col(|ui| {
+                    if let Some(res) = &img.results {
+                        ui.label(res.cell_count.to_string());
+                    }
+                });
+
+                let mut modifier = false;
+                let clicked = row.response().clicked();
+
+                row.response().ctx.input(|i| {
+                    if i.key_down(egui::Key::Space) {
+                        modifier = true;
+                    }
+                });
+
+                if modifier && clicked {
+                    con.toggle_selection(img);
+                } else if clicked {
+                    con.unselect_all();
+                    con.toggle_selection(img);
+                    con.on_image_selected(img, model);
+                }
+            });
+        });
+}
@@ -1,7 +1,11 @@
+pub mod analyse_view;
 pub mod home_view;
 pub mod register_view;
 pub mod select_images_view;
+pub mod select_regions_view;
 
+pub use analyse_view::ui_tab_analyse;
 pub use home_view::ui_tab_home;
 pub use register_view::ui_tab_register;
 pub use select_images_view::ui_tab_select_images;
+pub use select_regions_view::ui_tab_select_regions;
@@ -4,20 +4,155 @@ use eframe::egui::{
     self, Color32, Pos2, Rect, Response, Scene, Sense, Shape, Stroke, TextureHandle, Ui, Vec2,
 };
 
+use crate::algorithm::metrics::Metric;
+use crate::algorithm::proc::AlignReport;
 use crate::controller::RegisterController;
 use crate::model::Model;
 use crate::utility::imops::egui_image_from_mat;
 use crate::utility::io::egui_image_from_path;
+use crate::utility::types::Matrix;
+
+const UNIT_UV: Rect = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+
+/// Largest cutting angle offered for the atlas slice, in degrees.
+const MAX_CUT_ANGLE: f32 = 20.0;
 
 pub fn ui_tab_register(model: &mut Model, con: &mut RegisterController, ui: &mut egui::Ui) {
+    con.poll_alignment(model, ui.ctx());
+    con.poll_mask();
+
     ui.vertical(|ui| {
         table_ui(model, con, ui);
 
         ui.separator();
 
-        if ui.button("Register").clicked() {
-            con.register_button_pushed(model);
-        }
+        ui.horizontal(|ui| {
+            let selected = con.selected_img.is_some();
+            let label = if con.aligning {
+                "Registering..."
+            } else {
+                "Register"
+            };
+
+            if ui
+                .add_enabled(selected && !con.aligning, egui::Button::new(label))
+                .clicked()
+            {
+                con.register_button_pushed(model);
+            }
+            if ui
+                .add_enabled(selected, egui::Button::new("Manual"))
+                .clicked()
+            {
+                con.start_manual(model, ui.ctx());
+            }
+            if ui
+                .add_enabled(con.dirty, egui::Button::new("Save Registration"))
+                .clicked()
+            {
+                con.save_registration(model);
+            }
+            if ui
+                .add_enabled(con.atlas_transform.is_some(), egui::Button::new("Clear"))
+                .clicked()
+            {
+                con.clear_registration(model, ui.ctx());
+            }
+            ui.checkbox(&mut con.show_overlay, "Show Atlas");
+            ui.checkbox(&mut con.show_annotation, "Show Annotation");
+
+            ui.separator();
+
+            if ui
+                .add_enabled(selected, egui::Button::new("Fit Landmarks"))
+                .clicked()
+            {
+                con.fit_landmarks(model, ui.ctx());
+            }
+            ui.checkbox(&mut con.live_landmarks, "Live");
+            if ui
+                .checkbox(&mut con.thin_plate, "Thin-plate Spline")
+                .changed()
+                && con.landmarks.is_some()
+            {
+                con.fit_landmarks(model, ui.ctx());
+            }
+            ui.checkbox(&mut con.init_from_landmarks, "Register from Landmarks");
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Levels");
+            ui.add(egui::DragValue::new(&mut con.align_options.levels).range(1..=6));
+            ui.label("Iterations");
+            ui.add(egui::DragValue::new(&mut con.align_options.max_iterations).range(10..=5000));
+            ui.label("Seed");
+            ui.add(egui::DragValue::new(&mut con.align_options.seed));
+            egui::ComboBox::from_label("Metric")
+                .selected_text(con.align_options.metric.to_str())
+                .show_ui(ui, |ui| {
+                    Metric::ALL.iter().for_each(|&metric| {
+                        ui.selectable_value(&mut con.align_options.metric, metric, metric.to_str());
+                    });
+                });
+
+            ui.separator();
+            ui.checkbox(&mut con.deformable, "B-spline");
+            ui.add_enabled_ui(con.deformable, |ui| {
+                ui.label("Control Points");
+                ui.add(egui::DragValue::new(&mut con.deform_options.control_points).range(3..=20));
+                ui.label("Regularization");
+                ui.add(
+                    egui::DragValue::new(&mut con.deform_options.regularization)
+                        .range(0.0..=100.0)
+                        .speed(0.01),
+                );
+            });
+
+            if let Some(report) = &con.last_report {
+                ui.separator();
+                ui.label(report_summary(report));
+            }
+
+            if let Some(msg) = &con.message {
+                ui.colored_label(Color32::RED, msg);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Structure");
+            ui.text_edit_singleline(&mut con.structure_acronym);
+            structure_picker(model, con, ui);
+            ui.label("Downsample");
+            ui.add(egui::DragValue::new(&mut con.mask_downsample).range(1..=64));
+            ui.checkbox(&mut con.mask_hemispheres, "By Hemisphere");
+
+            let has_registration = con
+                .selected_img
+                .as_ref()
+                .and_then(|id| model.get_image(id))
+                .is_some_and(|img| img.registration.is_some());
+            let label = if con.masking {
+                "Writing..."
+            } else {
+                "Write Mask"
+            };
+            if ui
+                .add_enabled(
+                    has_registration && !con.masking && !con.structure_acronym.trim().is_empty(),
+                    egui::Button::new(label),
+                )
+                .on_hover_text(
+                    "Masks the structure and its descendants under the saved registration",
+                )
+                .clicked()
+            {
+                con.write_mask_button_pushed(model);
+            }
+
+            if let Some(status) = &con.mask_status {
+                ui.label(status);
+            }
+        });
 
         ui.separator();
 
@@ -42,6 +177,8 @@ fn black_box(ui: &mut Ui, name: &str, add_contents: impl FnOnce(&mut Ui) -> ())
 }
 
 fn image_viewer(model: &mut Model, con: &mut RegisterController, ui: &mut egui::Ui) {
+    let hexes = (con.atlas_hex, con.hist_hex);
+
     ui.columns(2, |ui| {
         black_box(&mut ui[0], "left", |ui| {
             ui.vertical(|ui| {
@@ -50,13 +187,23 @@ fn image_viewer(model: &mut Model, con: &mut RegisterController, ui: &mut egui::
                 if toggle_ori_button.clicked() {
                     con.toggle_atlas_orientation();
                     con.on_atlas_interact(model, &toggle_ori_button.ctx);
+                    con.dirty |= con.atlas_transform.is_some();
                 }
 
                 let mut inner_rect = Rect::NAN;
 
                 let f = |ui: &mut Ui| {
                     if let Some(im) = &con.image_data2 {
-                        ui.image(im);
+                        let response = ui.image(im);
+                        let rect = response.rect;
+                        con.atlas_image_rect = rect;
+                        if let (true, Some(overlay)) = (con.show_annotation, &con.annotation_image)
+                        {
+                            ui.painter()
+                                .image(overlay.id(), rect, UNIT_UV, Color32::WHITE);
+                            draw_midline(con.midline.as_ref().map_or(&[], |m| &m[..]), rect, ui);
+                        }
+                        hover_structure(model, con.annotation.as_ref(), response);
                     }
 
                     inner_rect = ui.min_rect();
@@ -80,6 +227,35 @@ fn image_viewer(model: &mut Model, con: &mut RegisterController, ui: &mut egui::
 
                 if slider.dragged() {
                     con.on_atlas_interact(model, &slider.ctx);
+                    con.dirty |= con.atlas_transform.is_some();
+                }
+
+                let angles = ui
+                    .horizontal(|ui| {
+                        ui.label("Pitch");
+                        let pitch = ui.add(
+                            egui::DragValue::new(&mut con.pitch)
+                                .range(-MAX_CUT_ANGLE..=MAX_CUT_ANGLE)
+                                .speed(0.1)
+                                .suffix("°"),
+                        );
+                        ui.label("Yaw");
+                        let yaw = ui.add(
+                            egui::DragValue::new(&mut con.yaw)
+                                .range(-MAX_CUT_ANGLE..=MAX_CUT_ANGLE)
+                                .speed(0.1)
+                                .suffix("°"),
+                        );
+                        pitch | yaw
+                    })
+                    .inner;
+                if angles.changed() {
+                    con.on_atlas_interact(model, &angles.ctx);
+                    con.dirty |= con.atlas_transform.is_some();
+                }
+
+                if transform_editor(con, ui) {
+                    con.on_transform_edited(model, ui.ctx());
                 }
             });
         });
@@ -89,7 +265,21 @@ fn image_viewer(model: &mut Model, con: &mut RegisterController, ui: &mut egui::
 
             let f = |ui: &mut Ui| {
                 if let Some(im) = &con.image_data {
-                    ui.image(im);
+                    let response = ui.image(im);
+                    let rect = response.rect;
+                    con.hist_image_rect = rect;
+                    if let (true, Some(overlay)) = (con.show_overlay, &con.overlay) {
+                        ui.painter()
+                            .image(overlay.id(), rect, UNIT_UV, Color32::WHITE);
+                    }
+                    if let (true, Some(overlay)) =
+                        (con.show_annotation, &con.section_annotation_image)
+                    {
+                        ui.painter()
+                            .image(overlay.id(), rect, UNIT_UV, Color32::WHITE);
+                        draw_midline(con.section_midline.as_deref().unwrap_or_default(), rect, ui);
+                    }
+                    hover_structure(model, con.section_annotation.as_ref(), response);
                     let dim = (*im.size().iter().max().unwrap() as f32) / 250.0;
                     draw_hex(&mut con.hist_hex, con.transform2.scaling / dim, ui);
                 }
@@ -107,6 +297,103 @@ fn image_viewer(model: &mut Model, con: &mut RegisterController, ui: &mut egui::
             }
         });
     });
+
+    if con.live_landmarks && hexes != (con.atlas_hex, con.hist_hex) {
+        con.fit_landmarks(model, ui.ctx());
+    }
+}
+
+/// Draws the midline polyline given as fractions of `rect`.
+fn draw_midline(points: &[(f32, f32)], rect: Rect, ui: &mut Ui) {
+    let points = points
+        .iter()
+        .map(|&(u, v)| rect.lerp_inside(Vec2::new(u, v)))
+        .collect();
+    ui.painter().add(egui::Shape::line(
+        points,
+        Stroke::new(1.5, Color32::from_rgb(255, 200, 0)),
+    ));
+}
+
+/// Names the structure under the pointer when it hovers an image showing `labels`.
+fn hover_structure(model: &Model, labels: Option<&Matrix<u32>>, response: Response) {
+    let Some((labels, pos)) = labels.zip(response.hover_pos()) else {
+        return;
+    };
+    let (h, w) = labels.dim();
+    let uv = (pos - response.rect.min) / response.rect.size();
+    if !(0.0..1.0).contains(&uv.x) || !(0.0..1.0).contains(&uv.y) {
+        return;
+    }
+    let (r, c) = ((uv.y * h as f32) as usize, (uv.x * w as f32) as usize);
+    let structure = labels
+        .get((r, c))
+        .and_then(|&l| model.atlas.ontology().get(l as u64));
+    if let Some(s) = structure {
+        response.on_hover_text_at_pointer(format!("{} ({})", s.name, s.acronym));
+    }
+}
+
+fn report_summary(report: &AlignReport) -> String {
+    let levels = report
+        .levels
+        .iter()
+        .map(|l| {
+            let done = if l.converged { "" } else { "*" };
+            format!(
+                "{}x{}: {}{} it, {:.2}s",
+                l.size.0,
+                l.size.1,
+                l.iterations,
+                done,
+                l.elapsed.as_secs_f32()
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" | ");
+    let deformation = report
+        .deformation
+        .as_ref()
+        .map(|d| {
+            let done = if d.converged { "" } else { "*" };
+            format!(
+                "; B-spline {:.4} -> {:.4}, {}{} it, {:.2}s",
+                d.initial_metric,
+                d.metric,
+                d.iterations,
+                done,
+                d.elapsed.as_secs_f32()
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "{} {:.4} after {} iterations ({}){}",
+        report.options.metric.to_str(),
+        report.metric,
+        report.iterations,
+        levels,
+        deformation
+    )
+}
+
+/// DragValues for the affine part of the working transform. Returns whether it changed.
+fn transform_editor(con: &mut RegisterController, ui: &mut egui::Ui) -> bool {
+    let Some(t) = &mut con.atlas_transform else {
+        return false;
+    };
+
+    let mut changed = false;
+    egui::Grid::new("atlas_transform").show(ui, |ui| {
+        for row in 0..2 {
+            for col in 0..3 {
+                let speed = if col == 2 { 10.0 } else { 0.01 };
+                let value = egui::DragValue::new(&mut t[3 * row + col]).speed(speed);
+                changed |= ui.add(value).changed();
+            }
+            ui.end_row();
+        }
+    });
+    changed
 }
 
 fn draw_hex(pos: &mut [(f32, f32); 6], scale: f32, ui: &mut egui::Ui) {
@@ -189,7 +476,17 @@ fn table_ui(model: &mut Model, con: &mut RegisterController, ui: &mut egui::Ui)
                     ui.label(img.src_fn());
                 });
                 row.col(|ui| {
-                    ui.label(img.registration_channel.to_string());
+                    let registered = img.registration.as_ref().map(|r| match (r.pitch, r.yaw) {
+                        (0.0, 0.0) => format!("{} {}", r.orientation.to_str(), r.slice_index),
+                        (p, y) => format!(
+                            "{} {} ({:+.1}°, {:+.1}°)",
+                            r.orientation.to_str(),
+                            r.slice_index,
+                            p,
+                            y
+                        ),
+                    });
+                    ui.label(registered.unwrap_or("No".into()));
                 });
                 row.col(|ui| {
                     ui.label(img.cell_channel.to_string());
@@ -213,8 +510,51 @@ fn table_ui(model: &mut Model, con: &mut RegisterController, ui: &mut egui::Ui)
                 } else if clicked {
                     con.unselect_all();
                     con.toggle_selection(img, &row.response().ctx);
-                    con.on_image_selected(img, &row.response().ctx);
+                    con.on_image_selected(img, model, &row.response().ctx);
                 }
             });
         });
 }
+
+/// Lists the structures matching the Structure field, or those present in the
+/// current atlas slice when it is empty, and fills the field with the one picked.
+fn structure_picker(model: &Model, con: &mut RegisterController, ui: &mut Ui) {
+    const MAX_MATCHES: usize = 20;
+
+    let atlas = &model.atlas;
+    let selected = atlas
+        .ontology()
+        .by_acronym(con.structure_acronym.trim())
+        .map(|s| s.name.clone())
+        .unwrap_or("Find...".into());
+    egui::ComboBox::from_id_salt("structure_picker")
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            let found = if con.structure_acronym.trim().is_empty() {
+                atlas.structures_in_slice(
+                    con.atlas_orientation,
+                    con.slider_pos as isize,
+                    con.pitch,
+                    con.yaw,
+                )
+            } else {
+                atlas.ontology().search(&con.structure_acronym)
+            };
+            if found.is_empty() {
+                ui.label("No matching structures");
+            }
+            found.iter().take(MAX_MATCHES).for_each(|s| {
+                let depth = atlas.ontology().depth(s.id);
+                let text = format!("{}{} - {}", "  ".repeat(depth), s.acronym, s.name);
+                if ui
+                    .selectable_label(con.structure_acronym == s.acronym, text)
+                    .clicked()
+                {
+                    con.structure_acronym = s.acronym.clone();
+                }
+            });
+            if found.len() > MAX_MATCHES {
+                ui.label(format!("{} more...", found.len() - MAX_MATCHES));
+            }
+        });
+}
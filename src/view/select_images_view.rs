@@ -7,8 +7,8 @@ use eframe::egui::{self, Color32, Rect, Scene, Sense, Stroke, TextureHandle, Ui,
 use tokio::runtime::Handle;
 
 use crate::controller::SelectImagesController;
-use crate::model::Model;
-use crate::utility::io::egui_image_from_path;
+use crate::model::{ImageMetadata, Model};
+use crate::utility::{io::egui_image_from_path, tile_cache};
 
 pub fn ui_tab_select_images(
     model: &mut Model,
@@ -34,6 +34,7 @@ pub fn ui_tab_select_images(
                 *curr_count.lock().await += 1;
             });
         }
+        ui.weak(format!("Tile cache: {}", tile_cache::stats()));
     });
 
     ui.vertical(|ui| {
@@ -220,6 +221,7 @@ fn table_ui(model: &mut Model, con: &mut SelectImagesController, ui: &mut egui::
             body.rows(18.0, img_ids.len(), |mut row| {
                 let idx = row.index();
                 let img = img_ids.get_mut(idx).unwrap();
+                let hint = channel_hint(img);
 
                 row.set_selected(con.selection.contains(img.src_fn()));
                 row.set_overline(true);
@@ -228,19 +230,25 @@ fn table_ui(model: &mut Model, con: &mut SelectImagesController, ui: &mut egui::
                     ui.label(img.id());
                 });
                 row.col(|ui| {
-                    let res = ui.text_edit_singleline(&mut img.registration_buffer);
+                    let res = ui
+                        .text_edit_singleline(&mut img.registration_buffer)
+                        .on_hover_text(&hint);
                     if res.clicked_elsewhere() {
                         img.refresh_channels();
                     }
                 });
                 row.col(|ui| {
-                    let res = ui.text_edit_singleline(&mut img.cell_buffer);
+                    let res = ui
+                        .text_edit_singleline(&mut img.cell_buffer)
+                        .on_hover_text(&hint);
                     if res.clicked_elsewhere() {
                         img.refresh_channels();
                     }
                 });
                 row.col(|ui| {
-                    let res = ui.text_edit_singleline(&mut img.comarker_buffer);
+                    let res = ui
+                        .text_edit_singleline(&mut img.comarker_buffer)
+                        .on_hover_text(&hint);
                     if res.clicked_elsewhere() {
                         img.refresh_channels();
                     }
@@ -272,3 +280,23 @@ fn table_ui(model: &mut Model, con: &mut SelectImagesController, ui: &mut egui::
             });
         });
 }
+
+/// The image's named channels with their indices, for the channel fields.
+fn channel_hint(img: &ImageMetadata) -> String {
+    let channels = &img.acquisition.channels;
+    if channels.is_empty() {
+        return format!(
+            "Channel index, 0 to {}",
+            img.channel_count.saturating_sub(1)
+        );
+    }
+    channels
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match c.emission_wavelength {
+            Some(nm) => format!("{i}: {} ({nm:.0} nm)", c.name),
+            None => format!("{i}: {}", c.name),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
@@ -0,0 +1,350 @@
+use std::ops::Div;
+
+use eframe::egui::{self, Color32, Pos2, Rect, Scene, Sense, Shape, Stroke, Ui, Vec2};
+
+use crate::controller::select_regions_controller::DrawMode;
+use crate::controller::SelectRegionsController;
+use crate::model::Model;
+use crate::utility::types::Region;
+
+pub fn ui_tab_select_regions(
+    model: &mut Model,
+    con: &mut SelectRegionsController,
+    ui: &mut egui::Ui,
+) {
+    ui.horizontal(|ui| {
+        ui.radio_value(&mut con.mode, DrawMode::Polygon, "Polygon");
+        ui.radio_value(&mut con.mode, DrawMode::Freehand, "Freehand");
+
+        ui.separator();
+
+        if ui
+            .add_enabled(con.draft.len() >= 3, egui::Button::new("Finish Region"))
+            .clicked()
+        {
+            con.finish_draft();
+        }
+        if ui
+            .add_enabled(!con.draft.is_empty(), egui::Button::new("Cancel Region"))
+            .clicked()
+        {
+            con.cancel_draft();
+        }
+        if ui
+            .add_enabled(con.dirty, egui::Button::new("Save Regions"))
+            .clicked()
+        {
+            con.save(model);
+        }
+        if ui
+            .add_enabled(con.dirty, egui::Button::new("Revert"))
+            .clicked()
+        {
+            con.revert(model);
+        }
+
+        if let Some(msg) = &con.message {
+            ui.colored_label(Color32::RED, msg);
+        }
+        if let Some(err) = con.preview_error.try_lock().ok().and_then(|e| e.clone()) {
+            ui.colored_label(Color32::RED, err);
+        }
+    });
+
+    ui.vertical(|ui| {
+        table_ui(model, con, ui);
+
+        ui.separator();
+
+        image_viewer(con, ui);
+    });
+}
+
+fn black_box(ui: &mut Ui, name: &str, add_contents: impl FnOnce(&mut Ui)) {
+    egui::containers::Window::new(name.to_string())
+        .current_pos(ui.max_rect().min)
+        .max_size(ui.available_size())
+        .min_size(ui.available_size())
+        .interactable(false)
+        .title_bar(false)
+        .frame(
+            egui::Frame::new()
+                .corner_radius(0)
+                .fill(Color32::BLACK)
+                .outer_margin(0),
+        )
+        .show(ui.ctx(), add_contents);
+}
+
+/// Maps between screen positions over the preview and full-resolution `(row, col)`.
+struct ImageFrame {
+    rect: Rect,
+    size: (usize, usize),
+}
+
+impl ImageFrame {
+    fn scale(&self) -> Vec2 {
+        Vec2::new(
+            self.rect.width() / self.size.0 as f32,
+            self.rect.height() / self.size.1 as f32,
+        )
+    }
+
+    fn to_full(&self, pos: Pos2) -> (f64, f64) {
+        let s = self.scale();
+        (
+            ((pos.y - self.rect.min.y) / s.y) as f64,
+            ((pos.x - self.rect.min.x) / s.x) as f64,
+        )
+    }
+
+    fn to_screen(&self, (r, c): (f64, f64)) -> Pos2 {
+        let s = self.scale();
+        Pos2::new(
+            self.rect.min.x + c as f32 * s.x,
+            self.rect.min.y + r as f32 * s.y,
+        )
+    }
+}
+
+fn image_viewer(con: &mut SelectRegionsController, ui: &mut egui::Ui) {
+    ui.columns(2, |ui| {
+        black_box(&mut ui[0], "regions_left", |ui| {
+            let mut inner_rect = Rect::NAN;
+            let mut new_vertex = None;
+            let mut finish = false;
+            let mut moves = vec![];
+
+            let texture = con.preview_data.try_lock().ok().and_then(|t| t.clone());
+
+            let scene = Scene::new().zoom_range(0.0..=f32::INFINITY).show(
+                ui,
+                &mut con.preview_rect,
+                |ui| {
+                    if let Some(im) = &texture {
+                        let sense = match con.mode {
+                            DrawMode::Polygon => Sense::click(),
+                            DrawMode::Freehand => Sense::click_and_drag(),
+                        };
+                        let response = ui.add(egui::Image::new(im).sense(sense));
+                        let frame = ImageFrame {
+                            rect: response.rect,
+                            size: con.image_size,
+                        };
+                        let pointer = response.interact_pointer_pos();
+
+                        match con.mode {
+                            DrawMode::Polygon => {
+                                if response.double_clicked() {
+                                    finish = true;
+                                } else if response.clicked() {
+                                    new_vertex = pointer.map(|p| frame.to_full(p));
+                                }
+                            }
+                            DrawMode::Freehand => {
+                                let far_enough = |p: Pos2| {
+                                    con.draft
+                                        .last()
+                                        .is_none_or(|&v| frame.to_screen(v).distance(p) > 2.0)
+                                };
+                                if response.dragged() {
+                                    new_vertex = pointer
+                                        .filter(|&p| far_enough(p))
+                                        .map(|p| frame.to_full(p));
+                                }
+                                if response.drag_stopped() {
+                                    finish = true;
+                                }
+                            }
+                        }
+
+                        draw_regions(ui, &frame, &con.regions, con.active, &con.draft);
+
+                        if let Some(idx) = con.active {
+                            moves = vertex_handles(ui, &frame, idx, &con.regions[idx]);
+                        }
+                    }
+
+                    inner_rect = ui.min_rect();
+                },
+            );
+
+            if let Some(pt) = new_vertex {
+                con.add_vertex(pt);
+            }
+            if finish {
+                con.finish_draft();
+            }
+            moves
+                .into_iter()
+                .for_each(|(region, vertex, delta)| con.move_vertex(region, vertex, delta));
+
+            if scene.response.double_clicked() {
+                con.preview_rect = inner_rect;
+            }
+        });
+
+        black_box(&mut ui[1], "regions_right", |ui| {
+            regions_ui(con, ui);
+        });
+    });
+}
+
+fn draw_regions(
+    ui: &mut Ui,
+    frame: &ImageFrame,
+    regions: &[Region],
+    active: Option<usize>,
+    draft: &[(f64, f64)],
+) {
+    let painter = ui.painter();
+    let width = frame.rect.width() / 500.0;
+
+    regions.iter().enumerate().for_each(|(i, region)| {
+        let colour = if Some(i) == active {
+            Color32::YELLOW
+        } else {
+            Color32::LIGHT_BLUE
+        };
+        let points = region
+            .vertices
+            .iter()
+            .map(|&v| frame.to_screen(v))
+            .collect::<Vec<Pos2>>();
+
+        let n = points.len() as f32;
+        let centre = points
+            .iter()
+            .fold(Pos2::ZERO, |acc, p| acc + p.to_vec2() / n);
+
+        painter.add(Shape::closed_line(points, Stroke::new(width, colour)));
+        painter.text(
+            centre,
+            egui::Align2::CENTER_CENTER,
+            &region.name,
+            egui::FontId::proportional(width * 12.0),
+            colour,
+        );
+    });
+
+    let points = draft
+        .iter()
+        .map(|&v| frame.to_screen(v))
+        .collect::<Vec<Pos2>>();
+    painter.add(Shape::line(points, Stroke::new(width, Color32::RED)));
+}
+
+/// Draggable handles on the vertices of the active region. Returns the moves
+/// made this frame as `(region, vertex, (d_row, d_col))`.
+fn vertex_handles(
+    ui: &mut Ui,
+    frame: &ImageFrame,
+    idx: usize,
+    region: &Region,
+) -> Vec<(usize, usize, (f64, f64))> {
+    let radius = frame.rect.width() / 200.0;
+    let scale = frame.scale();
+    let id = ui.id().with("region_vertex").with(idx);
+
+    region
+        .vertices
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &v)| {
+            let centre = frame.to_screen(v);
+            let rect = Rect::from_center_size(centre, Vec2::splat(radius * 2.0));
+            let response = ui.interact(rect, id.with(i), Sense::drag());
+
+            ui.painter().circle_filled(centre, radius, Color32::YELLOW);
+
+            let d = response.drag_delta();
+            (d != Vec2::ZERO).then(|| (idx, i, ((d.y / scale.y) as f64, (d.x / scale.x) as f64)))
+        })
+        .collect()
+}
+
+fn regions_ui(con: &mut SelectRegionsController, ui: &mut Ui) {
+    let mut remove = None;
+
+    egui::Grid::new("regions_list")
+        .striped(true)
+        .show(ui, |ui| {
+            ui.strong("Name");
+            ui.strong("Vertices");
+            ui.end_row();
+
+            for (i, region) in con.regions.iter_mut().enumerate() {
+                let name = ui.text_edit_singleline(&mut region.name);
+                if name.changed() {
+                    con.dirty = true;
+                }
+                if name.gained_focus() {
+                    con.active = Some(i);
+                }
+
+                let label =
+                    ui.selectable_label(con.active == Some(i), region.vertices.len().to_string());
+                if label.clicked() {
+                    con.active = if con.active == Some(i) { None } else { Some(i) };
+                }
+
+                if ui.button("Delete").clicked() {
+                    remove = Some(i);
+                }
+                ui.end_row();
+            }
+        });
+
+    if let Some(i) = remove {
+        con.remove_region(i);
+    }
+}
+
+fn table_ui(model: &mut Model, con: &mut SelectRegionsController, ui: &mut egui::Ui) {
+    use egui_extras::{Column, TableBuilder};
+
+    let available_height = ui.available_height();
+
+    TableBuilder::new(ui)
+        .cell_layout(egui::Layout::left_to_right(egui::Align::Center))
+        .column(Column::remainder())
+        .column(Column::remainder())
+        .auto_shrink(false)
+        .min_scrolled_height(available_height.div(5.0))
+        .max_scroll_height(available_height.div(5.0))
+        .striped(true)
+        .sense(egui::Sense::click())
+        .header(20.0, |mut header| {
+            header.col(|ui| {
+                ui.strong("Image");
+            });
+            header.col(|ui| {
+                ui.strong("Regions");
+            });
+        })
+        .body(|body| {
+            let mut img_ids = model.get_all_images().unwrap_or(vec![]);
+            img_ids.sort_by(|a, b| a.id().cmp(b.id()));
+
+            body.rows(18.0, img_ids.len(), |mut row| {
+                let img = &img_ids[row.index()];
+
+                row.set_selected(con.selected_img.as_deref() == Some(img.src_fn()));
+                row.set_overline(true);
+
+                row.col(|ui| {
+                    ui.label(img.id());
+                });
+                row.col(|ui| {
+                    ui.label(match con.unsaved.get(img.src_fn()) {
+                        Some(regions) => format!("{} (unsaved)", regions.len()),
+                        None => img.regions.len().to_string(),
+                    });
+                });
+
+                if row.response().clicked() {
+                    con.on_image_selected(img, model);
+                }
+            });
+        });
+}
@@ -0,0 +1,121 @@
+//! Loading atlases from a manifest and slicing them along anatomical axes.
+
+use std::collections::HashSet;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use microcount_rs::model::atlas::Orientation;
+use microcount_rs::model::atlas_manifest::AtlasManifest;
+use microcount_rs::model::Atlas;
+use tiff::encoder::{colortype, TiffEncoder};
+
+/// Voxels along (page, row, column).
+const SIZE: (usize, usize, usize) = (5, 4, 6);
+
+/// Value of the voxel at (`p`, `r`, `c`), distinct for every voxel.
+fn voxel(p: usize, r: usize, c: usize) -> u16 {
+    (p * 100 + r * 10 + c) as u16
+}
+
+fn write_volume(path: &Path, value: impl Fn(usize, usize, usize) -> u16) {
+    let (pages, h, w) = SIZE;
+    let file = std::fs::File::create(path).unwrap();
+    let mut enc = TiffEncoder::new(BufWriter::new(file)).unwrap();
+    for p in 0..pages {
+        let data = (0..h * w)
+            .map(|i| value(p, i / w, i % w))
+            .collect::<Vec<u16>>();
+        let im = enc
+            .new_image::<colortype::Gray16>(w as u32, h as u32)
+            .unwrap();
+        im.write_data(&data).unwrap();
+    }
+}
+
+/// An atlas in a fresh folder stored with axes `axis_order`, e.g.
+/// `["LR", "DV", "AP"]`.
+fn write_atlas(name: &str, axis_order: [&str; 3]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("microcount_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    write_volume(&dir.join("reference.tiff"), voxel);
+    // B on the right, A on the left with A1 along its top row
+    write_volume(&dir.join("annotation.tiff"), |_, r, c| match (r, c) {
+        (_, 3..) => 3,
+        (0, _) => 4,
+        _ => 2,
+    });
+    std::fs::write(
+        dir.join("structures.csv"),
+        "id,acronym,name,parent_structure_id\n1,root,Root,\n2,A,Alpha,1\n3,B,Beta,1\n4,A1,Alpha one,2\n",
+    )
+    .unwrap();
+    let manifest = format!(
+        r#"{{"name": "{name}", "reference": "reference.tiff", "annotation": "annotation.tiff",
+            "structures": {{"file": "structures.csv"}}, "voxel_size": [25, 25, 25],
+            "axis_order": {axis_order:?}}}"#
+    );
+    std::fs::write(dir.join("atlas.json"), manifest).unwrap();
+    dir
+}
+
+#[test]
+fn sections_are_cut_across_their_anatomical_axis() {
+    // pages run left to right, rows dorsal to ventral, columns anterior to posterior
+    let dir = write_atlas("atlas_lr_dv_ap", ["LR", "DV", "AP"]);
+    let atlas = Atlas::from_manifest(AtlasManifest::from_dir(&dir).unwrap()).unwrap();
+    let (pages, rows, cols) = SIZE;
+
+    assert_eq!(atlas.n_slices(Orientation::Coronal), cols);
+    assert_eq!(atlas.n_slices(Orientation::Axial), rows);
+    assert_eq!(atlas.n_slices(Orientation::Sagittal), pages);
+
+    let coronal = atlas.get_reference_img(Orientation::Coronal, 2);
+    assert_eq!(coronal.dim(), (pages, rows));
+    assert!(coronal
+        .indexed_iter()
+        .all(|((p, r), &v)| v == voxel(p, r, 2)));
+
+    let axial = atlas.get_reference_img(Orientation::Axial, 1);
+    assert_eq!(axial.dim(), (pages, cols));
+    assert!(axial.indexed_iter().all(|((p, c), &v)| v == voxel(p, 1, c)));
+
+    let sagittal = atlas.get_reference_img(Orientation::Sagittal, 3);
+    assert_eq!(sagittal.dim(), (rows, cols));
+    assert!(sagittal
+        .indexed_iter()
+        .all(|((r, c), &v)| v == voxel(3, r, c)));
+
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn structures_are_looked_up_by_voxel() {
+    let dir = write_atlas("atlas_structures", ["AP", "DV", "LR"]);
+    let atlas = Atlas::from_manifest(AtlasManifest::from_dir(&dir).unwrap()).unwrap();
+
+    let a1 = atlas.structure_at((1, 0, 1)).unwrap();
+    assert_eq!(a1.acronym, "A1");
+    assert_eq!(atlas.ontology().ancestors(a1.id), [2, 1]);
+    assert_eq!(atlas.structure_at((4, 3, 0)).unwrap().acronym, "A");
+    assert_eq!(atlas.structure_at((0, 2, 5)).unwrap().acronym, "B");
+    assert!(atlas.structure_at((SIZE.0, 0, 0)).is_none());
+
+    assert_eq!(atlas.structure_ids("A"), Some(HashSet::from([2, 4])));
+    assert!(atlas.structure_ids("C").is_none());
+    let in_slice = atlas.structures_in_slice(Orientation::Coronal, 2, 0.0, 0.0);
+    let acronyms = in_slice
+        .iter()
+        .map(|s| s.acronym.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(acronyms, ["A", "B", "A1"]);
+
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn allen_coronal_sections_are_pages() {
+    let allen = AtlasManifest::allen_50um(Path::new("assets"));
+    assert_eq!(allen.slice_axis(Orientation::Coronal), 0);
+    assert_eq!(allen.slice_axis(Orientation::Axial), 1);
+    assert_eq!(allen.slice_axis(Orientation::Sagittal), 2);
+}
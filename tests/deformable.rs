@@ -0,0 +1,77 @@
+//! B-spline refinement of an affine alignment.
+
+use image::{ImageBuffer, Luma};
+use microcount_rs::algorithm::deformable::{refine, BSplineField, DeformOptions};
+use microcount_rs::algorithm::metrics::Metric;
+
+type Buffer = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+const IDENTITY: [f32; 9] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+
+/// Soft blobs of differing brightness, smooth enough to follow a warp.
+fn blobs(x: f32, y: f32) -> f32 {
+    [(30.0, 25.0, 1.0), (70.0, 30.0, 0.6), (45.0, 60.0, 0.8)]
+        .iter()
+        .map(|&(cx, cy, a)| a * (-((x - cx).powi(2) + (y - cy).powi(2)) / 120.0).exp())
+        .sum()
+}
+
+/// The blobs pushed by a bulge of up to `strength` pixels to the right.
+fn bulged(strength: f32) -> Buffer {
+    ImageBuffer::from_fn(96, 80, |x, y| {
+        let (x, y) = (x as f32, y as f32);
+        let push = strength * (-((x - 48.0).powi(2) + (y - 40.0).powi(2)) / 800.0).exp();
+        Luma([blobs(x - push, y)])
+    })
+}
+
+#[test]
+fn zero_displacement_is_the_identity() {
+    let field = BSplineField::zeros((96, 80), (20.0, 20.0));
+    for pt in [(0.0, 0.0), (13.5, 71.25), (95.0, 79.0), (-4.0, 120.0)] {
+        assert_eq!(field.displace(pt), pt);
+    }
+    assert_eq!(field.bending_energy(), 0.0);
+
+    // the cubic weights sum to one, so a uniform field is a plain shift
+    let mut shift = field.clone();
+    shift.coefficients.fill((3.0, -2.0));
+    let (x, y) = shift.displace((41.0, 17.5));
+    assert!((x - 44.0).abs() < 1e-4 && (y - 15.5).abs() < 1e-4);
+}
+
+#[test]
+fn refine_improves_a_warped_section() {
+    let (fixed, moving) = (bulged(0.0), bulged(6.0));
+    for metric in Metric::ALL {
+        let options = DeformOptions {
+            metric,
+            ..DeformOptions::default()
+        };
+        let (field, report) = refine(&moving, &fixed, IDENTITY, &options);
+        assert!(
+            report.metric > report.initial_metric,
+            "{}: {} -> {}",
+            metric.to_str(),
+            report.initial_metric,
+            report.metric
+        );
+        assert!(report.iterations > 0 && field.bending_energy() > 0.0);
+    }
+}
+
+#[test]
+fn refine_leaves_aligned_images_alone() {
+    let image = bulged(6.0);
+    let options = DeformOptions {
+        metric: Metric::MeanSquaredDifference,
+        ..DeformOptions::default()
+    };
+    let (field, report) = refine(&image, &image, IDENTITY, &options);
+    assert!(report.metric >= report.initial_metric);
+    let largest = field
+        .coefficients
+        .iter()
+        .fold(0.0f32, |m, c| m.max(c.0.abs()).max(c.1.abs()));
+    assert!(largest < 0.5, "aligned images moved by {largest}");
+}
@@ -0,0 +1,64 @@
+//! Fitting landmark pairs with a least-squares affine and a thin-plate spline.
+
+use microcount_rs::algorithm::landmarks::{fit_affine, Point, ThinPlate};
+
+/// Corners of a hexagon around (100, 80), as placed when registering by hand.
+fn hexagon() -> Vec<Point> {
+    (0..6)
+        .map(|k| {
+            let a = k as f32 * std::f32::consts::PI / 3.0;
+            (100.0 + 40.0 * a.cos(), 80.0 + 40.0 * a.sin())
+        })
+        .collect()
+}
+
+fn transform(m: &[f32; 9], (x, y): Point) -> Point {
+    (m[0] * x + m[1] * y + m[2], m[3] * x + m[4] * y + m[5])
+}
+
+fn assert_close(a: Point, b: Point, tolerance: f32) {
+    let d = (a.0 - b.0).hypot(a.1 - b.1);
+    assert!(d < tolerance, "{a:?} is {d} from {b:?}");
+}
+
+#[test]
+fn affine_is_recovered_from_six_pairs() {
+    let known = [1.2, -0.3, 15.0, 0.4, 0.9, -7.0, 0.0, 0.0, 1.0];
+    let src = hexagon();
+    let dst = src
+        .iter()
+        .map(|&p| transform(&known, p))
+        .collect::<Vec<_>>();
+
+    let fitted = fit_affine(&src, &dst).unwrap();
+    for (f, k) in fitted.iter().zip(known) {
+        assert!((f - k).abs() < 1e-3, "{fitted:?} != {known:?}");
+    }
+}
+
+#[test]
+fn affine_needs_three_points_off_a_line() {
+    let line = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0)];
+    assert!(fit_affine(&line, &line).is_none());
+    assert!(fit_affine(&line[..2], &line[..2]).is_none());
+}
+
+#[test]
+fn thin_plate_interpolates_its_control_points() {
+    let mut src = hexagon();
+    src.push((100.0, 80.0));
+    // a bend no affine can follow: the centre pushed out, the corners kept
+    let dst = src
+        .iter()
+        .enumerate()
+        .map(|(i, &(x, y))| if i == 6 { (x + 9.0, y - 6.0) } else { (x, y) })
+        .collect::<Vec<Point>>();
+
+    let tps = ThinPlate::fit(&src, &dst).unwrap();
+    for (&s, &d) in src.iter().zip(&dst) {
+        assert_close(tps.apply(s), d, 1e-2);
+    }
+    // between the control points the warp fades out towards the corners
+    let halfway = tps.apply((120.0, 80.0));
+    assert!(halfway.0 > 120.0 && halfway.0 < 129.0);
+}
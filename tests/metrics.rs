@@ -0,0 +1,75 @@
+//! Properties every similarity metric should have.
+
+use image::{ImageBuffer, Luma};
+use microcount_rs::algorithm::metrics::Metric;
+
+type Buffer = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Soft blobs quantised to tenths, offset `dx` pixels to the right.
+fn section(dx: f32) -> Buffer {
+    ImageBuffer::from_fn(80, 64, |x, y| {
+        let (x, y) = (x as f32 - dx, y as f32);
+        let v: f32 = [(25.0, 20.0, 0.9), (55.0, 28.0, 0.6), (38.0, 48.0, 0.75)]
+            .iter()
+            .map(|&(cx, cy, a)| a * (-((x - cx).powi(2) + (y - cy).powi(2)) / 90.0).exp())
+            .sum();
+        Luma([(v.min(0.9) * 10.0).round() / 10.0])
+    })
+}
+
+fn rescale(im: &Buffer, gain: f32, offset: f32) -> Buffer {
+    ImageBuffer::from_fn(im.width(), im.height(), |x, y| {
+        Luma([im.get_pixel(x, y).0[0] * gain + offset])
+    })
+}
+
+#[test]
+fn identical_images_match_best() {
+    let fixed = section(0.0);
+    for metric in Metric::ALL {
+        let same = metric.similarity(&fixed, &fixed);
+        for dx in [1.0, 3.0, 8.0] {
+            let shifted = metric.similarity(&fixed, &section(dx));
+            assert!(
+                shifted < same,
+                "{}: shifted by {dx} scores {shifted}, identical {same}",
+                metric.to_str()
+            );
+        }
+    }
+    assert!((Metric::NormalizedCrossCorrelation.similarity(&fixed, &fixed) - 1.0).abs() < 1e-5);
+    assert_eq!(
+        Metric::MeanSquaredDifference.similarity(&fixed, &fixed),
+        0.0
+    );
+}
+
+#[test]
+fn further_shifts_score_worse() {
+    let fixed = section(0.0);
+    for metric in Metric::ALL {
+        let near = metric.similarity(&fixed, &section(2.0));
+        let far = metric.similarity(&fixed, &section(10.0));
+        assert!(far < near, "{}: {far} !< {near}", metric.to_str());
+    }
+}
+
+#[test]
+fn correlation_ignores_gain_and_offset() {
+    let (fixed, moving) = (section(0.0), section(3.0));
+    let ncc = Metric::NormalizedCrossCorrelation;
+    let plain = ncc.similarity(&fixed, &moving);
+    let rescaled = ncc.similarity(&fixed, &rescale(&moving, 0.4, 0.3));
+    assert!((plain - rescaled).abs() < 1e-5, "{plain} != {rescaled}");
+}
+
+#[test]
+fn normalized_mutual_information_ignores_scaling() {
+    // halving keeps every intensity level in a bin of its own
+    let (fixed, moving) = (section(0.0), section(3.0));
+    let nmi = Metric::NormalizedMutualInformation;
+    let plain = nmi.similarity(&fixed, &moving);
+    let halved = nmi.similarity(&fixed, &rescale(&moving, 0.5, 0.0));
+    assert!((plain - halved).abs() < 1e-5, "{plain} != {halved}");
+    assert!((nmi.similarity(&fixed, &rescale(&fixed, 0.5, 0.0)) - 2.0).abs() < 1e-5);
+}
@@ -0,0 +1,86 @@
+//! Slicing a volume along a tilted plane.
+
+use imageproc::geometric_transformations::Interpolation;
+use microcount_rs::utility::imops::{get_oblique_labels, get_oblique_slice, get_slice};
+use microcount_rs::utility::types::Volume;
+
+/// Pages, rows and columns; odd so the tilt pivots on a voxel.
+const SHAPE: (usize, usize, usize) = (11, 9, 7);
+
+/// A distinct value for every voxel.
+fn numbered() -> Volume<u16> {
+    Volume::from_shape_fn(SHAPE, |(p, r, c)| (p * 100 + r * 10 + c) as u16)
+}
+
+/// Ten times the page, so a sample tells how far through the pages it fell.
+fn page_ramp() -> Volume<u16> {
+    Volume::from_shape_fn(SHAPE, |(p, _, _)| (p * 10) as u16)
+}
+
+#[test]
+fn no_tilt_is_the_plain_slice() {
+    let vol = numbered();
+    let labels = vol.mapv(|v| v as u32);
+    let anisotropic = [50.0, 25.0, 10.0];
+    for axis in 0..3 {
+        for idx in [0, 3, 6] {
+            let plain = get_slice(&vol, idx, axis);
+            for interpolation in [Interpolation::Nearest, Interpolation::Bilinear] {
+                let tilted =
+                    get_oblique_slice(&vol, idx, axis, (0.0, 0.0), anisotropic, interpolation);
+                assert_eq!(tilted, plain, "axis {axis}, slice {idx}");
+            }
+            let tilted = get_oblique_labels(&labels, idx, axis, (0.0, 0.0), anisotropic);
+            assert_eq!(tilted, get_slice(&labels, idx, axis));
+        }
+    }
+}
+
+#[test]
+fn pitch_moves_rows_through_the_volume() {
+    // sin 30° = 1/2: each row off the centre row steps half a page
+    let slice = get_oblique_slice(
+        &page_ramp(),
+        5,
+        0,
+        (30.0, 0.0),
+        [25.0; 3],
+        Interpolation::Bilinear,
+    );
+    assert_eq!(slice.dim(), (SHAPE.1, SHAPE.2));
+    for ((r, _), &v) in slice.indexed_iter() {
+        assert_eq!(v as f32, 50.0 + 5.0 * (r as f32 - 4.0), "row {r}");
+    }
+}
+
+#[test]
+fn yaw_moves_columns_through_the_volume() {
+    let slice = get_oblique_slice(
+        &page_ramp(),
+        5,
+        0,
+        (0.0, 30.0),
+        [25.0; 3],
+        Interpolation::Bilinear,
+    );
+    for ((_, c), &v) in slice.indexed_iter() {
+        assert_eq!(v as f32, 50.0 + 5.0 * (c as f32 - 3.0), "column {c}");
+    }
+}
+
+#[test]
+fn tilt_is_physical_for_anisotropic_voxels() {
+    // pages twice as thick as rows: the same pitch crosses half as many pages
+    let slice = get_oblique_slice(
+        &page_ramp(),
+        5,
+        0,
+        (30.0, 0.0),
+        [50.0, 25.0, 25.0],
+        Interpolation::Bilinear,
+    );
+    for ((r, _), &v) in slice.indexed_iter() {
+        let expected = 50.0 + 2.5 * (r as f32 - 4.0);
+        assert!((v as f32 - expected).abs() <= 0.5, "row {r}: {v}");
+    }
+}
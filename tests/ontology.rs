@@ -0,0 +1,84 @@
+//! Querying the atlas structure hierarchy.
+
+use microcount_rs::model::ontology::{Ontology, Structure};
+
+/// A slice of the Allen hierarchy:
+/// root > CTX > {MO > {MOp, MOs}, SS}, root > CB.
+fn ontology() -> Ontology {
+    let rows = [
+        (997, "root", "root", None),
+        (688, "CTX", "Cerebral cortex", Some(997)),
+        (500, "MO", "Somatomotor areas", Some(688)),
+        (985, "MOp", "Primary motor area", Some(500)),
+        (993, "MOs", "Secondary motor area", Some(500)),
+        (453, "SS", "Somatosensory areas", Some(688)),
+        (512, "CB", "Cerebellum", Some(997)),
+    ];
+    Ontology::new(
+        rows.into_iter()
+            .map(|(id, acronym, name, parent)| Structure {
+                id,
+                acronym: acronym.to_string(),
+                name: name.to_string(),
+                parent,
+                colour: None,
+            })
+            .collect(),
+    )
+}
+
+fn acronyms(found: &[&Structure]) -> Vec<String> {
+    found.iter().map(|s| s.acronym.clone()).collect()
+}
+
+#[test]
+fn structures_are_found_by_id_and_acronym() {
+    let onto = ontology();
+    assert_eq!(onto.len(), 7);
+    assert_eq!(onto.get(985).unwrap().acronym, "MOp");
+    assert!(onto.get(1).is_none());
+    assert_eq!(onto.by_acronym("MOs").unwrap().id, 993);
+    assert!(onto.by_acronym("mos").is_none());
+}
+
+#[test]
+fn search_matches_names_and_ranks_exact_acronyms_first() {
+    let onto = ontology();
+    assert_eq!(
+        acronyms(&onto.search("motor")),
+        ["MO", "MOp", "MOs"].map(String::from)
+    );
+    assert_eq!(acronyms(&onto.search(" mop ")), ["MOp"].map(String::from));
+    assert_eq!(acronyms(&onto.search("mo"))[0], "MO");
+    assert_eq!(
+        acronyms(&onto.search("CEREB")),
+        ["CB", "CTX"].map(String::from)
+    );
+    assert!(onto.search("  ").is_empty());
+}
+
+#[test]
+fn hierarchy_runs_up_and_down() {
+    let onto = ontology();
+    assert_eq!(onto.ancestors(985), [500, 688, 997]);
+    assert!(onto.ancestors(997).is_empty());
+    assert_eq!(onto.children(688), [453, 500]);
+    assert_eq!(onto.descendants(688), [453, 500, 985, 993]);
+    assert!(onto.descendants(512).is_empty());
+    assert_eq!(onto.depth(997), 0);
+    assert_eq!(onto.depth(993), 3);
+}
+
+#[test]
+fn cycles_in_a_malformed_table_end() {
+    let looped = |id, parent| Structure {
+        id,
+        acronym: id.to_string(),
+        name: String::new(),
+        parent: Some(parent),
+        colour: None,
+    };
+    let onto = Ontology::new(vec![looped(1, 2), looped(2, 3), looped(3, 1)]);
+    assert_eq!(onto.ancestors(1), [2, 3]);
+    assert_eq!(onto.descendants(1), [3, 2]);
+}
@@ -0,0 +1,542 @@
+//! Round trips of `read_tiff_region` through generated striped and tiled
+//! TIFFs, written uncompressed by hand since the `tiff` encoder cannot tile,
+//! and of the pyramid levels and channel pages `tiff_layout` finds in them.
+
+use std::path::PathBuf;
+
+use microcount_rs::utility::io::{read_tiff_region, tiff_info, tiff_layout};
+use microcount_rs::utility::types::{PixelMatrix, ROI};
+
+const WIDTH: usize = 45;
+const HEIGHT: usize = 37;
+
+/// Value of sample `s` at (`y`, `x`), distinct for every sample and pixel.
+fn px(s: usize, y: usize, x: usize) -> u16 {
+    (s * 10_000 + y * 100 + x) as u16
+}
+
+/// Set on every pixel of a reduced level so reads show which level they
+/// came from.
+const REDUCED: u16 = 0x8000;
+
+/// Value at (`y`, `x`) of a level `factor` times smaller: the full-resolution
+/// pixel it samples, marked when the level is reduced.
+fn level_px(s: usize, y: usize, x: usize, factor: usize) -> u16 {
+    let mark = if factor > 1 { REDUCED } else { 0 };
+    px(s, y * factor, x * factor) | mark
+}
+
+/// (width, height) of a level `factor` times smaller.
+fn level_dims(factor: usize) -> (usize, usize) {
+    (WIDTH.div_ceil(factor), HEIGHT.div_ceil(factor))
+}
+
+#[derive(Clone, Copy)]
+enum Chunks {
+    Strips { rows: usize },
+    Tiles { width: usize, height: usize },
+}
+
+/// How levels halving in size are stored besides the full-resolution pages.
+#[derive(Clone, Copy)]
+enum Pyramid {
+    None,
+    /// In each page's SubIFDs.
+    SubIfds {
+        levels: usize,
+    },
+    /// As top-level pages flagged reduced resolution by NewSubfileType,
+    /// followed by a transparency mask that is neither a level nor a channel.
+    Pages {
+        levels: usize,
+    },
+}
+
+struct Fixture {
+    pages: usize,
+    samples: usize,
+    planar: bool,
+    chunks: Chunks,
+    pyramid: Pyramid,
+    /// ImageDescription of the first page.
+    description: Option<String>,
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        Self {
+            pages: 1,
+            samples: 1,
+            planar: false,
+            chunks: Chunks::Strips { rows: 4 },
+            pyramid: Pyramid::None,
+            description: None,
+        }
+    }
+}
+
+impl Fixture {
+    /// Pixels (y, x) of chunk `(row, col)` of sample plane `plane` of a level
+    /// `factor` times smaller, padded to the full tile size as TIFF requires
+    /// for tiles.
+    fn chunk(&self, page: usize, plane: usize, row: usize, col: usize, factor: usize) -> Vec<u16> {
+        let (width, height) = level_dims(factor);
+        let (cw, ch) = self.chunk_dims(factor);
+        let rows = match self.chunks {
+            Chunks::Strips { .. } => ch.min(height - row * ch),
+            Chunks::Tiles { .. } => ch,
+        };
+        let samples = if self.planar {
+            vec![plane]
+        } else {
+            (0..self.samples).collect()
+        };
+        let mut data = vec![];
+        for y in row * ch..row * ch + rows {
+            for x in col * cw..col * cw + cw {
+                for &s in &samples {
+                    let channel = page * self.samples + s;
+                    let inside = y < height && x < width;
+                    data.push(if inside {
+                        level_px(channel, y, x, factor)
+                    } else {
+                        0
+                    });
+                }
+            }
+        }
+        data
+    }
+
+    fn chunk_dims(&self, factor: usize) -> (usize, usize) {
+        match self.chunks {
+            Chunks::Strips { rows } => (level_dims(factor).0, rows),
+            Chunks::Tiles { width, height } => (width, height),
+        }
+    }
+
+    /// A little-endian classic TIFF of 16-bit samples.
+    fn write(&self, name: &str) -> PathBuf {
+        let mut buf = b"II\x2a\x00\0\0\0\0".to_vec();
+        let mut next_ifd = 4;
+        let mut link = |buf: &mut Vec<u8>, (ifd, next): (u32, usize)| {
+            buf[next_ifd..next_ifd + 4].copy_from_slice(&ifd.to_le_bytes());
+            next_ifd = next;
+        };
+
+        for page in 0..self.pages {
+            let sub_ifds = match self.pyramid {
+                Pyramid::SubIfds { levels } => (1..=levels)
+                    .map(|k| self.write_ifd(&mut buf, page, 1 << k, 1, &[], None).0)
+                    .collect(),
+                _ => vec![],
+            };
+            let description = self.description.as_deref().filter(|_| page == 0);
+            let ifd = self.write_ifd(&mut buf, page, 1, 0, &sub_ifds, description);
+            link(&mut buf, ifd);
+        }
+        if let Pyramid::Pages { levels } = self.pyramid {
+            for k in 1..=levels {
+                for page in 0..self.pages {
+                    let ifd = self.write_ifd(&mut buf, page, 1 << k, 1, &[], None);
+                    link(&mut buf, ifd);
+                }
+            }
+            let mask = self.write_ifd(&mut buf, 0, 2, 4, &[], None);
+            link(&mut buf, mask);
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("microcount_{}_{}.tiff", name, std::process::id()));
+        std::fs::write(&path, buf).expect("write fixture");
+        path
+    }
+
+    /// Appends the chunks and IFD of `page` at a level `factor` times smaller,
+    /// returning the IFD's offset and where its next-IFD offset goes.
+    fn write_ifd(
+        &self,
+        buf: &mut Vec<u8>,
+        page: usize,
+        factor: usize,
+        subfile_type: u32,
+        sub_ifds: &[u32],
+        description: Option<&str>,
+    ) -> (u32, usize) {
+        let (width, height) = level_dims(factor);
+        let (cw, ch) = self.chunk_dims(factor);
+        let (across, down) = (width.div_ceil(cw), height.div_ceil(ch));
+        let planes = if self.planar { self.samples } else { 1 };
+
+        let (mut offsets, mut counts) = (vec![], vec![]);
+        for plane in 0..planes {
+            for row in 0..down {
+                for col in 0..across {
+                    let data = self.chunk(page, plane, row, col, factor);
+                    offsets.push(buf.len() as u32);
+                    counts.push(data.len() as u32 * 2);
+                    data.iter().for_each(|v| buf.extend(v.to_le_bytes()));
+                }
+            }
+        }
+        let photometric = if self.samples == 3 { 2 } else { 1 };
+        let mut tags = vec![
+            (256, LONG, 1, width as u32),
+            (257, LONG, 1, height as u32),
+            shorts(buf, 258, &vec![16; self.samples]),
+            (259, SHORT, 1, 1),
+            (262, SHORT, 1, photometric),
+            (277, SHORT, 1, self.samples as u32),
+            (284, SHORT, 1, if self.planar { 2 } else { 1 }),
+        ];
+        match self.chunks {
+            Chunks::Strips { rows } => tags.extend([
+                longs(buf, 273, &offsets),
+                (278, LONG, 1, rows as u32),
+                longs(buf, 279, &counts),
+            ]),
+            Chunks::Tiles { width, height } => tags.extend([
+                (322, LONG, 1, width as u32),
+                (323, LONG, 1, height as u32),
+                longs(buf, 324, &offsets),
+                longs(buf, 325, &counts),
+            ]),
+        }
+        if subfile_type != 0 {
+            tags.push((254, LONG, 1, subfile_type));
+        }
+        if !sub_ifds.is_empty() {
+            tags.push(longs(buf, 330, sub_ifds));
+        }
+        if let Some(text) = description {
+            tags.push(ascii(buf, 270, text));
+        }
+        tags.sort_by_key(|t| t.0);
+
+        if buf.len() % 2 == 1 {
+            buf.push(0);
+        }
+        let ifd = buf.len() as u32;
+        buf.extend((tags.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in tags {
+            buf.extend(tag.to_le_bytes());
+            buf.extend(kind.to_le_bytes());
+            buf.extend(count.to_le_bytes());
+            match (kind, count) {
+                (SHORT, 1) => buf.extend([(value as u16).to_le_bytes(), [0, 0]].concat()),
+                _ => buf.extend(value.to_le_bytes()),
+            }
+        }
+        let next = buf.len();
+        buf.extend([0; 4]);
+        (ifd, next)
+    }
+
+    fn channels(&self) -> usize {
+        self.pages * self.samples
+    }
+}
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+/// An IFD entry (tag, type, count, value or offset) for `values`, appending
+/// them to `buf` unless a single one fits in the entry.
+fn longs(buf: &mut Vec<u8>, tag: u16, values: &[u32]) -> (u16, u16, u32, u32) {
+    if let [value] = values {
+        return (tag, LONG, 1, *value);
+    }
+    let at = buf.len() as u32;
+    values.iter().for_each(|v| buf.extend(v.to_le_bytes()));
+    (tag, LONG, values.len() as u32, at)
+}
+
+fn shorts(buf: &mut Vec<u8>, tag: u16, values: &[u16]) -> (u16, u16, u32, u32) {
+    if let [value] = values {
+        return (tag, SHORT, 1, *value as u32);
+    }
+    let at = buf.len() as u32;
+    values.iter().for_each(|v| buf.extend(v.to_le_bytes()));
+    (tag, SHORT, values.len() as u32, at)
+}
+
+/// An IFD entry for `text`, NUL-terminated.
+fn ascii(buf: &mut Vec<u8>, tag: u16, text: &str) -> (u16, u16, u32, u32) {
+    let mut bytes = text.as_bytes().to_vec();
+    bytes.push(0);
+    let count = bytes.len() as u32;
+    if bytes.len() <= 4 {
+        bytes.resize(4, 0);
+        return (
+            tag,
+            ASCII,
+            count,
+            u32::from_le_bytes(bytes.try_into().unwrap()),
+        );
+    }
+    let at = buf.len() as u32;
+    buf.extend(bytes);
+    (tag, ASCII, count, at)
+}
+
+const ROIS: [ROI; 5] = [
+    (0, 0, HEIGHT, WIDTH),
+    (5, 7, 20, 30),
+    (15, 17, 3, 2),
+    (30, 40, 7, 5),
+    (0, 33, 37, 12),
+];
+
+/// Reads every ROI at several downsamples and compares each pixel with the
+/// value written.
+fn check(fixture: Fixture, name: &str) {
+    let path = fixture.write(name);
+    let file = path.to_str().unwrap();
+
+    let info = tiff_info(file).unwrap();
+    assert_eq!(info.dimensions, (WIDTH, HEIGHT));
+    assert_eq!(info.n_channels, fixture.channels());
+
+    for roi @ (r, c, h, w) in ROIS {
+        for df in [1, 2, 3, 5] {
+            let channels = read_tiff_region(file, roi, df).unwrap();
+            assert_eq!(channels.len(), fixture.channels(), "{name} {roi:?} df {df}");
+            for (s, channel) in channels.iter().enumerate() {
+                let PixelMatrix::U16(m) = channel else {
+                    panic!("{name}: expected u16 samples");
+                };
+                assert_eq!(m.dim(), (h.div_ceil(df), w.div_ceil(df)));
+                for ((i, j), &v) in m.indexed_iter() {
+                    let expected = px(s, r + i * df, c + j * df);
+                    assert_eq!(v, expected, "{name} {roi:?} df {df} sample {s} at {i},{j}");
+                }
+            }
+        }
+    }
+    std::fs::remove_file(path).ok();
+}
+
+#[test]
+fn striped_pages() {
+    let chunks = Chunks::Strips { rows: 4 };
+    check(
+        Fixture {
+            pages: 3,
+            samples: 1,
+            planar: false,
+            chunks,
+            ..Default::default()
+        },
+        "striped_pages",
+    );
+}
+
+#[test]
+fn striped_chunky_rgb() {
+    let chunks = Chunks::Strips { rows: 5 };
+    check(
+        Fixture {
+            pages: 1,
+            samples: 3,
+            planar: false,
+            chunks,
+            ..Default::default()
+        },
+        "striped_chunky",
+    );
+}
+
+#[test]
+fn striped_planar_rgb() {
+    let chunks = Chunks::Strips { rows: 6 };
+    check(
+        Fixture {
+            pages: 1,
+            samples: 3,
+            planar: true,
+            chunks,
+            ..Default::default()
+        },
+        "striped_planar",
+    );
+}
+
+#[test]
+fn tiled_pages() {
+    let chunks = Chunks::Tiles {
+        width: 16,
+        height: 16,
+    };
+    check(
+        Fixture {
+            pages: 2,
+            samples: 1,
+            planar: false,
+            chunks,
+            ..Default::default()
+        },
+        "tiled_pages",
+    );
+}
+
+#[test]
+fn tiled_chunky_rgb() {
+    let chunks = Chunks::Tiles {
+        width: 16,
+        height: 32,
+    };
+    check(
+        Fixture {
+            pages: 1,
+            samples: 3,
+            planar: false,
+            chunks,
+            ..Default::default()
+        },
+        "tiled_chunky",
+    );
+}
+
+#[test]
+fn tiled_planar_rgb() {
+    let chunks = Chunks::Tiles {
+        width: 32,
+        height: 16,
+    };
+    check(
+        Fixture {
+            pages: 1,
+            samples: 3,
+            planar: true,
+            chunks,
+            ..Default::default()
+        },
+        "tiled_planar",
+    );
+}
+
+#[test]
+fn region_is_clipped_to_the_image() {
+    let chunks = Chunks::Tiles {
+        width: 16,
+        height: 16,
+    };
+    let fixture = Fixture {
+        pages: 1,
+        samples: 1,
+        planar: false,
+        chunks,
+        ..Default::default()
+    };
+    let path = fixture.write("clipped");
+    let file = path.to_str().unwrap();
+    for (df, dims) in [(1, (7, 5)), (2, (4, 3)), (3, (3, 2))] {
+        let channels = read_tiff_region(file, (30, 40, 10, 10), df).unwrap();
+        assert_eq!(channels[0].dim(), dims, "df {df}");
+        assert_eq!(channels[0].to_u16()[(0, 0)], px(0, 30, 40));
+    }
+    std::fs::remove_file(path).ok();
+}
+
+/// Checks the levels `tiff_layout` finds, `levels` halvings below full
+/// resolution, and reads at downsamples served by each. `channel_pages` are
+/// the pages holding each channel at full resolution.
+fn check_levels(fixture: Fixture, levels: usize, channel_pages: &[usize], name: &str) {
+    let path = fixture.write(name);
+    let file = path.to_str().unwrap();
+    let n_channels = channel_pages.len() * fixture.samples;
+    assert_eq!(tiff_info(file).unwrap().n_channels, n_channels, "{name}");
+
+    let layout = tiff_layout(file).unwrap();
+    let dims = layout
+        .levels
+        .iter()
+        .map(|l| l.dimensions)
+        .collect::<Vec<_>>();
+    let expected = (0..=levels).map(|k| level_dims(1 << k)).collect::<Vec<_>>();
+    assert_eq!(dims, expected, "{name}");
+    for level in &layout.levels {
+        assert_eq!(level.pages.len(), channel_pages.len(), "{name}");
+    }
+
+    // ROIs aligned to every level, so reads land on level pixels
+    for roi @ (r, c, h, w) in [(0, 0, HEIGHT, WIDTH), (4, 8, 20, 16)] {
+        for df in [1, 2, 4] {
+            let factor = df.min(1 << levels);
+            let channels = read_tiff_region(file, roi, df).unwrap();
+            assert_eq!(channels.len(), n_channels, "{name} {roi:?} df {df}");
+            for (s, channel) in channels.iter().enumerate() {
+                let PixelMatrix::U16(m) = channel else {
+                    panic!("{name}: expected u16 samples");
+                };
+                let page = channel_pages[s / fixture.samples];
+                let stored = page * fixture.samples + s % fixture.samples;
+                assert_eq!(m.dim(), (h.div_ceil(df), w.div_ceil(df)));
+                for ((i, j), &v) in m.indexed_iter() {
+                    let (y, x) = ((r + i * df) / factor, (c + j * df) / factor);
+                    let expected = level_px(stored, y, x, factor);
+                    assert_eq!(v, expected, "{name} {roi:?} df {df} sample {s} at {i},{j}");
+                }
+            }
+        }
+    }
+    std::fs::remove_file(path).ok();
+}
+
+#[test]
+fn sub_ifd_pyramid() {
+    let chunks = Chunks::Tiles {
+        width: 16,
+        height: 16,
+    };
+    let fixture = Fixture {
+        pages: 2,
+        chunks,
+        pyramid: Pyramid::SubIfds { levels: 2 },
+        ..Default::default()
+    };
+    check_levels(fixture, 2, &[0, 1], "sub_ifd_pyramid");
+}
+
+#[test]
+fn reduced_resolution_pages() {
+    let fixture = Fixture {
+        pages: 2,
+        pyramid: Pyramid::Pages { levels: 2 },
+        ..Default::default()
+    };
+    check_levels(fixture, 2, &[0, 1], "reduced_pages");
+}
+
+/// OME-XML for one image of `size_c` channels and `size_z` planes each.
+fn ome(dimension_order: &str, size_c: usize, size_z: usize) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OME xmlns="http://www.openmicroscopy.org/Schemas/OME/2016-06">
+  <Image ID="Image:0">
+    <Pixels ID="Pixels:0" DimensionOrder="{dimension_order}" Type="uint16"
+        SizeX="{WIDTH}" SizeY="{HEIGHT}" SizeC="{size_c}" SizeZ="{size_z}" SizeT="1">
+      <TiffData/>
+    </Pixels>
+  </Image>
+</OME>"#
+    )
+}
+
+#[test]
+fn ome_channels_are_not_z_planes() {
+    // XYZCT stores both planes of channel 0 before those of channel 1
+    let chunks = Chunks::Tiles {
+        width: 16,
+        height: 16,
+    };
+    let fixture = Fixture {
+        pages: 4,
+        chunks,
+        pyramid: Pyramid::SubIfds { levels: 1 },
+        description: Some(ome("XYZCT", 2, 2)),
+        ..Default::default()
+    };
+    check_levels(fixture, 1, &[0, 2], "ome_xyzct");
+}
@@ -0,0 +1,142 @@
+//! Tiled analysis against a single pass over the same generated slide.
+
+use std::path::{Path, PathBuf};
+
+use microcount_rs::algorithm::microcount::{from_fn, ArtifactDirs};
+use microcount_rs::utility::io::read_mask;
+use microcount_rs::utility::types::{CellRecord, CellTable, Intermediate, Settings};
+use tiff::encoder::{colortype, TiffEncoder};
+
+const WIDTH: u32 = 224;
+const HEIGHT: u32 = 160;
+/// Soma centres (x, y), two of them straddling tile borders; every other
+/// cell carries the co-marker.
+const CELLS: [(f64, f64); 4] = [(50.0, 45.0), (98.0, 94.0), (170.0, 50.0), (185.0, 125.0)];
+
+/// Three 16-bit pages: a registration stain, the co-marker and the cell
+/// marker, with cross-shaped cells on a dark background whose branches
+/// reach 18 pixels out.
+fn write_slide(path: &Path) {
+    let mut enc = TiffEncoder::new(std::fs::File::create(path).unwrap()).unwrap();
+    for ch in 0..3 {
+        let mut buf = vec![0u16; (WIDTH * HEIGHT) as usize];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let mut v = match ch {
+                    2 => 0.0,
+                    _ => 50.0 + ((x * 7 + y * 13) % 17) as f64,
+                };
+                for (i, &(cx, cy)) in CELLS.iter().enumerate() {
+                    let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+                    let r = dx.hypot(dy);
+                    match ch {
+                        0 => v += 200.0 * (-r / 80.0).exp(),
+                        1 if i % 2 == 0 && r < 4.0 => v += 3000.0,
+                        2 if r < 8.0 => v += 4000.0,
+                        2 if r < 18.0 && (dx.abs() < 1.5 || dy.abs() < 1.5) => v += 1500.0,
+                        _ => {}
+                    }
+                }
+                buf[(y * WIDTH + x) as usize] = v as u16;
+            }
+        }
+        let mut im = enc.new_image::<colortype::Gray16>(WIDTH, HEIGHT).unwrap();
+        im.rows_per_strip(16).unwrap();
+        im.write_data(&buf).unwrap();
+    }
+}
+
+fn run(slide: &Path, settings: &Settings, out: &Path) -> CellTable {
+    let out = ArtifactDirs {
+        proc_dir: out.to_owned(),
+        mask_dir: out.to_owned(),
+    };
+    let roi = (0, 0, HEIGHT as usize, WIDTH as usize);
+    from_fn(
+        slide.to_str().unwrap(),
+        roi,
+        2,
+        1,
+        settings,
+        &out,
+        None,
+        &|_, _| true,
+    )
+    .unwrap()
+}
+
+/// Cells in centroid order with their ROI-specific label left out.
+fn measurements(table: &CellTable) -> Vec<String> {
+    let mut cells = table
+        .cells
+        .iter()
+        .map(|c| CellRecord {
+            label_id: 0,
+            ..c.clone()
+        })
+        .map(|c| format!("{:?}", c))
+        .collect::<Vec<String>>();
+    cells.sort();
+    cells
+}
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("microcount_tiled_{}_{}", name, std::process::id()))
+}
+
+#[test]
+fn tiles_match_a_single_pass() {
+    let slide = temp("slide.tiff");
+    write_slide(&slide);
+
+    let intermediates = vec![
+        Intermediate::CoMarkerMask,
+        Intermediate::Skeleton,
+        Intermediate::Segmented,
+    ];
+    let single = Settings {
+        intermediates,
+        ..Settings::default()
+    };
+    let tiled = Settings {
+        tile_size: Some(96),
+        tile_overlap: 64,
+        ..single.clone()
+    };
+    tiled.validate().unwrap();
+
+    let (single_dir, tiled_dir) = (temp("single"), temp("tiled"));
+    let a = run(&slide, &single, &single_dir);
+    let b = run(&slide, &tiled, &tiled_dir);
+
+    assert_eq!(a.cells.len(), CELLS.len());
+    assert_eq!(b.cells.len(), a.cells.len());
+    assert_eq!(measurements(&b), measurements(&a));
+    assert_eq!(b.percentage_cd68_area, a.percentage_cd68_area);
+
+    // masks are stitched into one image of the whole ROI
+    for mask in [Intermediate::CoMarkerMask, Intermediate::Skeleton] {
+        let read = |dir: &Path| std::fs::read(dir.join(mask.file_name())).unwrap();
+        assert!(read(&tiled_dir) == read(&single_dir), "{:?}", mask);
+    }
+    let segmented = tiled_dir.join(Intermediate::Segmented.file_name());
+    let segmented = read_mask(segmented.to_str().unwrap()).unwrap();
+    assert_eq!(segmented.dim(), (HEIGHT as usize, WIDTH as usize));
+
+    std::fs::remove_file(slide).ok();
+    std::fs::remove_dir_all(single_dir).ok();
+    std::fs::remove_dir_all(tiled_dir).ok();
+}
+
+#[test]
+fn overlap_must_cover_a_cell_and_fit_in_a_tile() {
+    let settings = |tile_size, tile_overlap| Settings {
+        tile_size,
+        tile_overlap,
+        ..Settings::default()
+    };
+    assert!(settings(Some(96), 64).validate().is_ok());
+    assert!(settings(Some(128), 10).validate().is_err());
+    assert!(settings(Some(128), 128).validate().is_err());
+    assert!(settings(None, 10).validate().is_err());
+}
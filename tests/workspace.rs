@@ -0,0 +1,68 @@
+//! Saving a workspace and loading it back from its directory.
+
+use std::path::Path;
+
+use microcount_rs::model::{ImageMetadata, Workspace};
+use microcount_rs::utility::types::{CellTable, Region, RegionArea, Settings};
+
+#[test]
+fn zero_cell_results_reload() {
+    let dir = std::env::temp_dir().join(format!("microcount_ws_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir_name = dir.to_str().unwrap().to_owned();
+
+    let table = CellTable {
+        cells: vec![],
+        percentage_cd68_area: 0.0,
+        regions: vec![RegionArea {
+            name: "empty".into(),
+            area: 100,
+            percentage_cd68_area: 0.0,
+        }],
+    };
+    let settings = Settings::default();
+    let mut img = ImageMetadata::new("slide.tiff", &dir_name);
+    img.results = Some(table.results(&settings));
+    img.region_results = table.region_results(&settings);
+
+    let mut ws = Workspace::new(dir_name);
+    ws.images.insert(img.id().to_owned(), img);
+    ws.save().unwrap();
+
+    let loaded = Workspace::from_dir(Path::new(&ws.dir_name)).unwrap();
+    let img = &loaded.images["slide"];
+    let results = img.results.as_ref().unwrap();
+    assert_eq!(results.cell_count, 0);
+    assert_eq!(results.average_rotundity, None);
+    assert_eq!(results.average_branch_length, None);
+    assert_eq!(results.average_scholl, None);
+    assert_eq!(results.percentage_cd68_num, None);
+    assert_eq!(img.region_results["empty"].percentage_cd68_num, None);
+
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn regions_must_cover_the_image_and_have_their_own_folder() {
+    let mut img = ImageMetadata::new("slide.tiff", "ws");
+    img.size = (100, 50);
+    let square = |name: &str, r: f64, c: f64| Region {
+        name: name.into(),
+        vertices: vec![(r, c), (r, c + 10.0), (r + 10.0, c + 10.0), (r + 10.0, c)],
+    };
+
+    assert!(img.set_regions(vec![square("a b", 0.0, 0.0)]).is_ok());
+    assert!(img.set_regions(vec![square("off", 60.0, 0.0)]).is_err());
+    assert!(img.set_regions(vec![square("off", 0.0, 120.0)]).is_err());
+    let sliver = Region {
+        name: "sliver".into(),
+        vertices: vec![(10.0, 10.0), (10.2, 40.0), (10.4, 10.0)],
+    };
+    assert!(img.set_regions(vec![sliver]).is_err());
+
+    let shared = vec![square("a b", 0.0, 0.0), square("a_b", 20.0, 20.0)];
+    assert!(img.set_regions(shared).is_err());
+    let cased = vec![square("Cortex", 0.0, 0.0), square("cortex", 20.0, 20.0)];
+    assert!(img.set_regions(cased).is_err());
+    assert_eq!(img.regions.len(), 1);
+}